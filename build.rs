@@ -0,0 +1,32 @@
+use std::process::Command;
+
+fn main() {
+    // Docker builds don't have the .git directory, so allow the commit to be passed in
+    let commit = std::env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    });
+
+    println!(
+        "cargo:rustc-env=GIT_COMMIT={}",
+        commit.unwrap_or("unknown".to_owned())
+    );
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // HEAD only changes on checkout; a commit moves the branch it points to,
+    // which lives either in its own file or in packed-refs
+    if let Some(branch) = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_owned()))
+    {
+        for path in [format!(".git/{}", branch), ".git/packed-refs".to_owned()] {
+            if std::path::Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::exposition::{self, Collector};
+
+const HOURS_PER_WEEK: usize = 7 * 24;
+
+/// Weeks of history an hour needs before it's scored at all
+const MIN_SAMPLES: u32 = 2;
+
+/// Once an hour has this many weeks of history, older weeks fade out
+/// exponentially rather than counting equally
+const MAX_AVERAGED_SAMPLES: u32 = 4;
+
+/// Arrivals in one hour of the week, over the weeks seen so far
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HourBaseline {
+    pub mean: f64,
+    pub variance: f64,
+    pub samples: u32,
+}
+
+impl HourBaseline {
+    /// How many standard deviations `count` is from the mean. The deviation
+    /// is at least that of a Poisson process with the same mean, so an hour
+    /// that has always seen exactly 3 messages doesn't score 50 for a 4th.
+    fn score(&self, count: u64) -> f64 {
+        if self.samples < MIN_SAMPLES {
+            return 0.0;
+        }
+        let deviation = self.variance.max(self.mean).max(1.0).sqrt();
+        (count as f64 - self.mean) / deviation
+    }
+
+    fn fold(&mut self, count: u64) {
+        let alpha = 1.0 / f64::from((self.samples + 1).min(MAX_AVERAGED_SAMPLES));
+        let difference = count as f64 - self.mean;
+        self.mean += alpha * difference;
+        self.variance = (1.0 - alpha) * (self.variance + alpha * difference * difference);
+        self.samples += 1;
+    }
+}
+
+struct Progress {
+    baselines: Vec<HourBaseline>,
+    /// Start of the hour being counted
+    hour_start: DateTime<Utc>,
+    count: u64,
+    /// The exporter started partway through the hour being counted, so it
+    /// isn't folded into the baseline
+    partial: bool,
+    score: f64,
+}
+
+/// Learns how much mail usually arrives in each hour of the week and scores
+/// each finished hour against it, as `gmail_arrival_anomaly_score`.
+pub struct ArrivalBaseline {
+    timezone: Tz,
+    base_labels: Vec<(String, String)>,
+    progress: Mutex<Progress>,
+}
+
+impl ArrivalBaseline {
+    pub fn new(timezone: Tz, base_labels: Vec<(String, String)>) -> Self {
+        Self {
+            timezone,
+            base_labels,
+            progress: Mutex::new(Progress {
+                baselines: vec![HourBaseline::default(); HOURS_PER_WEEK],
+                hour_start: start_of_hour(Utc::now()),
+                count: 0,
+                partial: true,
+                score: 0.0,
+            }),
+        }
+    }
+
+    /// Picks up the history saved by `baselines`; ignored if it's the wrong shape
+    pub fn restore(&self, baselines: Vec<HourBaseline>) {
+        if baselines.len() == HOURS_PER_WEEK {
+            self.progress.lock().unwrap().baselines = baselines;
+        }
+    }
+
+    pub fn baselines(&self) -> Vec<HourBaseline> {
+        self.progress.lock().unwrap().baselines.clone()
+    }
+
+    pub fn observe(&self) {
+        let mut progress = self.progress.lock().unwrap();
+        self.roll(&mut progress, Utc::now());
+        progress.count += 1;
+    }
+
+    pub fn score(&self) -> f64 {
+        let mut progress = self.progress.lock().unwrap();
+        self.roll(&mut progress, Utc::now());
+        progress.score
+    }
+
+    /// Scores and folds in every hour that has finished by `now`, including
+    /// ones without any mail
+    fn roll(&self, progress: &mut Progress, now: DateTime<Utc>) {
+        while now >= progress.hour_start + TimeDelta::hours(1) {
+            if !progress.partial {
+                let baseline = &mut progress.baselines[self.hour_of_week(progress.hour_start)];
+                progress.score = baseline.score(progress.count);
+                baseline.fold(progress.count);
+            }
+            progress.partial = false;
+            progress.count = 0;
+            progress.hour_start += TimeDelta::hours(1);
+        }
+    }
+
+    fn hour_of_week(&self, time: DateTime<Utc>) -> usize {
+        let local = time.with_timezone(&self.timezone);
+        local.weekday().num_days_from_monday() as usize * 24 + local.hour() as usize
+    }
+}
+
+fn start_of_hour(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::hours(1)).unwrap_or(time)
+}
+
+impl Collector for ArrivalBaseline {
+    fn header(&self) -> String {
+        "# HELP gmail_arrival_anomaly_score Standard deviations between the mail that arrived in the last full hour and what usually arrives in that hour of the week; 0 until there are two weeks of history.\n# TYPE gmail_arrival_anomaly_score gauge\n".to_owned()
+    }
+
+    fn render_series(&self) -> String {
+        format!(
+            "{} {}\n",
+            exposition::format_series("gmail_arrival_anomaly_score", &self.base_labels),
+            self.score()
+        )
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{
+    events::{EventSink, MessageEvent},
+    store::MessageStore,
+    top_senders::TopSenders,
+};
+
+/// Messages returned when the request doesn't ask for a limit
+const DEFAULT_LIMIT: usize = 100;
+
+/// Most messages one request may ask for
+const MAX_LIMIT: usize = 1000;
+
+/// Events a slow /api/v1/stream client may fall behind by before it misses some
+const STREAM_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Args)]
+pub struct ApiOptions {
+    /// Serve /api/v1/messages, listing recently observed messages with their
+    /// senders and subjects, behind the same basic auth as /metrics
+    #[arg(long, env = "MESSAGES_API")]
+    pub messages_api: bool,
+
+    /// How far back (in seconds) /api/v1/messages can look. With --sqlite-path
+    /// it's read from the database; otherwise messages are kept in memory
+    #[arg(long, env = "MESSAGES_API_WINDOW", default_value_t = 24 * 60 * 60)]
+    pub messages_api_window: u64,
+
+    /// Most messages kept in memory for /api/v1/messages without --sqlite-path
+    #[arg(long, env = "MESSAGES_API_MAX_MESSAGES", default_value_t = 10_000)]
+    pub messages_api_max_messages: usize,
+
+    /// Serve /api/v1/stream, pushing every newly observed message as a
+    /// server-sent event, behind the same basic auth as /metrics
+    #[arg(long, env = "EVENT_STREAM")]
+    pub event_stream: bool,
+}
+
+/// Keeps the messages observed within the window for the API when there's no
+/// SQLite store to read them from
+pub struct RecentMessages {
+    window: chrono::Duration,
+    max_messages: usize,
+    messages: Mutex<VecDeque<MessageEvent>>,
+}
+
+impl RecentMessages {
+    pub fn new(options: &ApiOptions) -> Self {
+        Self {
+            window: window(options),
+            max_messages: options.messages_api_max_messages,
+            messages: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn observed_since(
+        &self,
+        since: DateTime<Utc>,
+        account: Option<&str>,
+        limit: usize,
+    ) -> Vec<MessageEvent> {
+        let mut messages = self.messages.lock().unwrap();
+        prune(&mut messages, Utc::now() - self.window);
+
+        messages
+            .iter()
+            .filter(|message| message.observed_at > since)
+            .filter(|message| account.is_none() || message.account.as_deref() == account)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+impl EventSink for RecentMessages {
+    fn publish(&self, event: &MessageEvent) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push_back(event.clone());
+        prune(&mut messages, event.observed_at - self.window);
+        while messages.len() > self.max_messages {
+            messages.pop_front();
+        }
+    }
+}
+
+/// Drops messages observed before `oldest`; they're kept in the order observed
+fn prune(messages: &mut VecDeque<MessageEvent>, oldest: DateTime<Utc>) {
+    while messages
+        .front()
+        .is_some_and(|message| message.observed_at <= oldest)
+    {
+        messages.pop_front();
+    }
+}
+
+fn window(options: &ApiOptions) -> chrono::Duration {
+    chrono::Duration::seconds(options.messages_api_window as i64)
+}
+
+/// Hands each observed message to the /api/v1/stream clients connected at
+/// the time
+pub struct EventStream {
+    sender: broadcast::Sender<MessageEvent>,
+}
+
+impl EventStream {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(STREAM_CAPACITY).0,
+        }
+    }
+}
+
+impl Default for EventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventSink for EventStream {
+    fn publish(&self, event: &MessageEvent) {
+        // Fails only when nobody is listening
+        let _ = self.sender.send(event.clone());
+    }
+}
+
+/// Where the API reads observed messages from
+#[derive(Clone)]
+pub enum MessageSource {
+    Sqlite(Arc<MessageStore>),
+    Memory(Arc<RecentMessages>),
+}
+
+#[derive(Clone)]
+struct MessagesState {
+    source: MessageSource,
+    window: chrono::Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    /// RFC 3339; defaults to the start of the window
+    since: Option<DateTime<Utc>>,
+    account: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    account: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewslettersQuery {
+    account: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct NewsletterSender {
+    account: Option<String>,
+    from: String,
+    /// Approximate, over the rolling window
+    messages: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct NewslettersResponse {
+    senders: Vec<NewsletterSender>,
+}
+
+/// Each account's newsletter sender inventory
+pub type NewsletterInventories = Arc<Vec<(Option<String>, Arc<TopSenders>)>>;
+
+#[derive(Debug, Serialize)]
+struct MessagesResponse {
+    messages: Vec<MessageEvent>,
+    /// More messages were observed after the last one returned; ask again
+    /// with `since` set to its `observed_at`
+    truncated: bool,
+}
+
+/// Routes for whichever of the API's endpoints are enabled, mounted behind
+/// the metrics basic auth
+pub fn router(
+    options: &ApiOptions,
+    source: Option<MessageSource>,
+    stream: Option<Arc<EventStream>>,
+) -> Router {
+    let mut router = Router::new();
+    if let Some(source) = source {
+        router = router.merge(
+            Router::new()
+                .route("/api/v1/messages", get(list_messages))
+                .with_state(MessagesState {
+                    source,
+                    window: window(options),
+                }),
+        );
+    }
+    if let Some(stream) = stream {
+        router = router.merge(
+            Router::new()
+                .route("/api/v1/stream", get(stream_messages))
+                .with_state(stream),
+        );
+    }
+    router
+}
+
+/// /api/v1/newsletters, the heaviest senders of mail with a `List-Unsubscribe`
+/// header, mounted behind the metrics basic auth
+pub fn newsletters_router(inventories: NewsletterInventories) -> Router {
+    Router::new()
+        .route("/api/v1/newsletters", get(list_newsletters))
+        .with_state(inventories)
+}
+
+async fn list_newsletters(
+    State(inventories): State<NewsletterInventories>,
+    Query(query): Query<NewslettersQuery>,
+) -> Json<NewslettersResponse> {
+    let mut senders = inventories
+        .iter()
+        .filter(|(account, _)| query.account.is_none() || *account == query.account)
+        .flat_map(|(account, inventory)| {
+            inventory
+                .top()
+                .into_iter()
+                .map(|(from, messages)| NewsletterSender {
+                    account: account.clone(),
+                    from,
+                    messages,
+                })
+        })
+        .collect::<Vec<_>>();
+    senders.sort_by_key(|sender| std::cmp::Reverse(sender.messages));
+
+    Json(NewslettersResponse { senders })
+}
+
+async fn list_messages(
+    State(state): State<MessagesState>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<MessagesResponse>, StatusCode> {
+    let oldest = Utc::now() - state.window;
+    let since = query.since.map_or(oldest, |since| since.max(oldest));
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let account = query.account.as_deref();
+
+    // One extra tells us whether there are more
+    let mut messages = match &state.source {
+        MessageSource::Sqlite(store) => {
+            let store = store.clone();
+            let account = account.map(str::to_owned);
+            tokio::task::spawn_blocking(move || {
+                store.observed_since(since, account.as_deref(), limit + 1)
+            })
+            .await
+            .expect("Message query panicked")
+            .map_err(|err| {
+                warn!("Failed to query observed messages: {}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+        }
+        MessageSource::Memory(recent) => recent.observed_since(since, account, limit + 1),
+    };
+
+    let truncated = messages.len() > limit;
+    messages.truncate(limit);
+
+    Ok(Json(MessagesResponse {
+        messages,
+        truncated,
+    }))
+}
+
+async fn stream_messages(
+    State(stream): State<Arc<EventStream>>,
+    Query(query): Query<StreamQuery>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    debug!("Event stream client connected");
+    let events = stream::unfold(
+        (stream.sender.subscribe(), query.account),
+        |(mut receiver, account)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(message) if account.is_some() && message.account != account => continue,
+                    Ok(message) => Event::default().event("message").json_data(&message),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        Ok(Event::default().comment(format!("skipped {} messages", skipped)))
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                };
+                return Some((event, (receiver, account)));
+            }
+        },
+    );
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
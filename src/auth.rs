@@ -1,61 +1,146 @@
 use std::collections::HashMap;
 
 use serde_json::Value;
+use tracing::{debug, info, instrument, warn};
 use url::{self, Url};
 
-use crate::mail;
+use crate::{
+    config::AccountConfig,
+    debug_status,
+    error::{ApiErrorReason, Error, Result},
+    http_trace, mail, state,
+};
 
+pub const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+pub const GOOGLE_TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// OAuth credentials for one Gmail mailbox. The access token is refreshed
+/// in place as it expires.
 #[derive(Debug, Clone)]
 pub struct GoogleAuth {
     client_id: String,
     client_secret: String,
     pub access_token: Option<String>,
     refresh_token: Option<String>,
+    /// Set for accounts from the config file, to label what they report
+    pub account: Option<String>,
+    token_url: String,
+    tokeninfo_url: String,
 }
 
 impl GoogleAuth {
-    pub fn new_from_env() -> Self {
+    /// Credentials that are already known; nothing is checked until they're used
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        refresh_token: Option<String>,
+        access_token: Option<String>,
+    ) -> Self {
         Self {
-            client_id: std::env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID must be set"),
-            client_secret: std::env::var("GOOGLE_CLIENT_SECRET")
-                .expect("GOOGLE_CLIENT_SECRET must be set"),
-            access_token: std::env::var_os("GOOGLE_ACCESS_TOKEN")
-                .map(|s| s.to_string_lossy().to_string()),
-            refresh_token: std::env::var_os("GOOGLE_REFRESH_TOKEN")
-                .map(|s| s.to_string_lossy().to_string()),
+            client_id,
+            client_secret,
+            access_token,
+            refresh_token,
+            account: None,
+            token_url: GOOGLE_TOKEN_URL.to_owned(),
+            tokeninfo_url: GOOGLE_TOKENINFO_URL.to_owned(),
         }
     }
 
-    pub async fn load_from_env() -> Self {
-        let mut google_auth = Self::new_from_env();
+    /// Exchange tokens somewhere other than `GOOGLE_TOKEN_URL`, e.g. a mock server
+    pub fn with_token_url(mut self, token_url: impl Into<String>) -> Self {
+        self.token_url = token_url.into();
+        self
+    }
 
-        if let Some(callback_code) = std::env::var_os("GOOGLE_CALLBACK") {
-            println!("Handling callback url...");
-            let callback_code = callback_code.to_string_lossy().to_string();
-            google_auth.handle_callback_url(callback_code).await;
-            println!();
-            println!("Auth updated based on callback url, please update env vars:");
-            google_auth.print_env_vars();
-        }
-        let mut mail = mail::MailClient {
-            google_client: google_auth.clone(),
+    /// Look up scopes somewhere other than `GOOGLE_TOKENINFO_URL`, e.g. a mock server
+    pub fn with_tokeninfo_url(mut self, tokeninfo_url: impl Into<String>) -> Self {
+        self.tokeninfo_url = tokeninfo_url.into();
+        self
+    }
+
+    /// Credentials from the GOOGLE_* environment variables, without checking them
+    pub fn new_from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| Error::Auth(format!("{} must be set", name)))
         };
 
-        if google_auth.is_authenticated() && mail.test_auth().await {
-            println!("Authenticated!");
-        } else {
-            println!("Not authenticated!");
+        Ok(Self::new(
+            var("GOOGLE_CLIENT_ID")?,
+            var("GOOGLE_CLIENT_SECRET")?,
+            std::env::var_os("GOOGLE_REFRESH_TOKEN").map(|s| s.to_string_lossy().to_string()),
+            std::env::var_os("GOOGLE_ACCESS_TOKEN").map(|s| s.to_string_lossy().to_string()),
+        ))
+    }
+
+    /// Credentials for an account from the config file. These never go
+    /// through the interactive flow, so a refresh token is required.
+    pub async fn for_account(account: &AccountConfig) -> Result<Self> {
+        let setting = |value: &Option<String>, key: &str, var: &str| {
+            value
+                .clone()
+                .or_else(|| std::env::var(var).ok())
+                .ok_or_else(|| {
+                    Error::Auth(format!(
+                        "Account {} needs a {}, or {} must be set",
+                        account.name, key, var
+                    ))
+                })
+        };
+
+        let mut google_auth = Self {
+            client_id: setting(&account.client_id, "client_id", "GOOGLE_CLIENT_ID")?,
+            client_secret: setting(
+                &account.client_secret,
+                "client_secret",
+                "GOOGLE_CLIENT_SECRET",
+            )?,
+            access_token: account.access_token.clone(),
+            refresh_token: Some(account.refresh_token.clone().ok_or_else(|| {
+                Error::Auth(format!("Account {} needs a refresh_token", account.name))
+            })?),
+            account: Some(account.name.clone()),
+            token_url: GOOGLE_TOKEN_URL.to_owned(),
+            tokeninfo_url: GOOGLE_TOKENINFO_URL.to_owned(),
+        };
 
-            let auth_url = google_auth.get_auth_url();
-            println!("Auth URL: {}", auth_url);
+        if google_auth.access_token.is_none() {
+            google_auth.do_refresh().await?;
+        }
 
-            println!("Please visit the URL above to authenticate.");
-            println!("Set the GOOGLE_CALLBACK environment variable to the code you receive.");
+        Ok(google_auth)
+    }
 
-            std::process::exit(1);
+    fn account_labels(&self) -> Vec<(String, String)> {
+        match &self.account {
+            Some(account) => vec![("account".to_owned(), account.clone())],
+            None => vec![],
         }
+    }
 
-        google_auth
+    /// Credentials from the GOOGLE_* environment variables, exchanging
+    /// GOOGLE_CALLBACK for tokens if it's set. Fails with
+    /// `Error::NotAuthenticated` if they don't work.
+    pub async fn load_from_env() -> Result<Self> {
+        let mut google_auth = Self::new_from_env()?;
+
+        if let Some(callback_code) = std::env::var_os("GOOGLE_CALLBACK") {
+            info!("Handling callback url...");
+            let callback_code = callback_code.to_string_lossy().to_string();
+            google_auth.handle_callback_url(callback_code).await?;
+            warn!("Auth updated based on callback url, please update env vars:");
+            google_auth.print_env_vars();
+        }
+        let mut mail = mail::MailClient::new(google_auth.clone());
+
+        if google_auth.is_authenticated() && mail.test_auth().await? {
+            info!("Authenticated!");
+            Ok(google_auth)
+        } else {
+            Err(Error::NotAuthenticated {
+                auth_url: google_auth.get_auth_url(),
+            })
+        }
     }
 
     pub fn print_env_vars(&self) {
@@ -91,97 +176,149 @@ impl GoogleAuth {
             .to_string()
     }
 
-    pub async fn handle_callback_url(&mut self, callback_url: String) {
-        let url = Url::parse(&callback_url).unwrap();
+    /// Exchange the URL Google redirected to after consent for tokens
+    #[instrument(skip_all)]
+    pub async fn handle_callback_url(&mut self, callback_url: String) -> Result<()> {
+        let url = Url::parse(&callback_url)
+            .map_err(|err| Error::Auth(format!("Invalid callback url: {}", err)))?;
         let code = url
             .query_pairs()
             .find(|(key, _)| key == "code")
-            .expect("expected callback url to have 'code' query param")
+            .ok_or_else(|| {
+                Error::Auth("Expected callback url to have a 'code' query param".to_owned())
+            })?
             .1;
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post("https://oauth2.googleapis.com/token")
-            .form(&[
-                ("code", code.as_ref()),
-                ("client_id", self.client_id.as_ref()),
-                ("client_secret", self.client_secret.as_ref()),
-                ("redirect_uri", "http://127.0.0.1:8080"),
-                ("grant_type", "authorization_code"),
-            ])
-            .send()
-            .await
-            .unwrap();
-
-        let response_json: serde_json::Value = response
-            .json()
-            .await
-            .expect("expected token exchange to return json");
-
-        println!("response_json: {:?}", response_json);
-
-        self.access_token = Some(
-            response_json["access_token"]
-                .as_str()
-                .expect("expected token exchange response to include an access_token. Have you already used this callback url?")
-                .to_owned(),
-        );
-        self.refresh_token = Some(
-            response_json["refresh_token"]
-                .as_str()
-                .expect("expected token exchange response to include a refresh_token")
-                .to_owned(),
-        );
+        let client = http_trace::client();
+        let response_json = http_trace::try_send_json(client.post(&self.token_url).form(&[
+            ("code", code.as_ref()),
+            ("client_id", self.client_id.as_ref()),
+            ("client_secret", self.client_secret.as_ref()),
+            ("redirect_uri", "http://127.0.0.1:8080"),
+            ("grant_type", "authorization_code"),
+        ]))
+        .await?;
+        ApiErrorReason::count(&response_json, self.account.as_deref());
+
+        debug!("response_json: {:?}", response_json);
+
+        self.access_token = Some(token_field(
+            &response_json,
+            "access_token",
+            "Have you already used this callback url?",
+        )?);
+        self.refresh_token = Some(token_field(&response_json, "refresh_token", "")?);
+
+        if let Some(expires_in) = response_json["expires_in"].as_i64() {
+            debug_status::record_token_expiry(
+                self.account.as_deref(),
+                chrono::Utc::now() + chrono::Duration::seconds(expires_in),
+            );
+        }
+
+        Ok(())
     }
 
-    pub async fn do_refresh(&mut self) {
-        let client = reqwest::Client::new();
-
-        println!("Refresh required, refreshing...");
-
-        let response = client
-            .post("https://oauth2.googleapis.com/token")
-            .form(&[
-                ("client_id", &self.client_id),
-                ("client_secret", &self.client_secret),
-                (
-                    "refresh_token",
-                    &self
-                        .refresh_token
-                        .clone()
-                        .expect("refresh token required during potential_refresh"),
-                ),
-                ("grant_type", &"refresh_token".to_string()),
-            ])
-            .send()
-            .await
-            .unwrap();
-
-        let response_json: serde_json::Value = response
-            .json()
-            .await
-            .expect("expected token exchange to return json");
-
-        println!("refresh response_json: {:?}", response_json);
-
-        self.access_token = Some(
-            response_json["access_token"]
-                .as_str()
-                .expect("expected token exchange response to include an access_token")
-                .to_owned(),
-        );
+    /// Exchange the refresh token for a new access token
+    #[instrument(skip_all)]
+    pub async fn do_refresh(&mut self) -> Result<()> {
+        let client = http_trace::client();
+        let refresh_token = self
+            .refresh_token
+            .clone()
+            .ok_or_else(|| Error::Auth("A refresh token is required to refresh".to_owned()))?;
 
-        println!(
-            "!IMPORTANT! Access token refreshed, update env vars: {}",
-            self.access_token.as_ref().unwrap()
-        );
+        info!("Refresh required, refreshing...");
+        state::increment_counter("gmail_auth_refreshes_total", &self.account_labels(), 1);
+
+        let response_json = http_trace::try_send_json(client.post(&self.token_url).form(&[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("refresh_token", &refresh_token),
+            ("grant_type", &"refresh_token".to_string()),
+        ]))
+        .await?;
+        ApiErrorReason::count(&response_json, self.account.as_deref());
+
+        debug!("refresh response_json: {:?}", response_json);
+
+        self.access_token = Some(token_field(&response_json, "access_token", "")?);
+
+        if let Some(expires_in) = response_json["expires_in"].as_i64() {
+            debug_status::record_token_expiry(
+                self.account.as_deref(),
+                chrono::Utc::now() + chrono::Duration::seconds(expires_in),
+            );
+        }
+
+        match &self.account {
+            Some(account) => info!("Access token for account {} refreshed", account),
+            None => warn!(
+                "!IMPORTANT! Access token refreshed, update env vars: {}",
+                self.access_token.as_ref().unwrap()
+            ),
+        }
+
+        Ok(())
     }
 
-    pub async fn needs_refresh(json: &Value) -> bool {
-        if json["error"]["code"] == 401 {
-            true
-        } else {
-            false
+    /// The scopes the access token was granted, refreshing it first if it
+    /// has expired
+    #[instrument(skip_all)]
+    pub async fn granted_scopes(&mut self) -> Result<Vec<String>> {
+        let mut refreshed = false;
+        loop {
+            let client = http_trace::client();
+            let response_json =
+                // In the body rather than the query, to keep it out of request logs
+                http_trace::try_send_json(client.post(&self.tokeninfo_url).form(&[(
+                    "access_token",
+                    self.access_token.as_deref().unwrap_or_default(),
+                )]))
+                .await?;
+
+            match (
+                response_json["scope"].as_str(),
+                Error::from_api(&response_json),
+            ) {
+                (Some(scope), _) => break Ok(scope.split(' ').map(str::to_owned).collect()),
+                // An expired token is answered with invalid_token rather than a 401
+                (None, Some(_)) if !refreshed => {
+                    self.do_refresh().await?;
+                    refreshed = true;
+                }
+                (None, Some(err)) => break Err(err),
+                (None, None) => {
+                    break Err(Error::UnexpectedResponse {
+                        what: "tokeninfo to return the token's scopes",
+                        body: response_json,
+                    })
+                }
+            }
         }
     }
+
+    pub async fn needs_refresh(json: &Value) -> bool {
+        json["error"]["code"] == 401
+    }
+}
+
+/// A token from a token exchange response, or why there isn't one
+fn token_field(response: &Value, key: &str, hint: &str) -> Result<String> {
+    match response[key].as_str() {
+        Some(token) => Ok(token.to_owned()),
+        None => Err(Error::Auth(
+            format!(
+                "Expected the token exchange response to include {}: {}. {}",
+                key,
+                Error::from_api(response)
+                    .map(|err| err.to_string())
+                    // Not the response itself, which can hold the other tokens
+                    .unwrap_or_else(|| "no error given".to_owned()),
+                hint
+            )
+            .trim_end()
+            .to_owned(),
+        )),
+    }
 }
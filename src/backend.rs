@@ -0,0 +1,445 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{
+    error::{Error, Result},
+    hashing::short_hash,
+    mail::{
+        Attachment, MailClient, MinimalMessage, UsableMessageDetails, WatchResponse,
+        COUNTED_ON_ARRIVAL,
+    },
+    mailbox_settings::{FilterFingerprint, MailboxSettings},
+};
+
+/// Where the watcher gets mail from. Progress is tracked with an opaque
+/// cursor string (a history ID for Gmail) that the watcher saves and hands
+/// back; each backend decides what goes in it. An error fails the poll it
+/// happened in, which the watcher's failure policy then decides about.
+#[async_trait]
+pub trait MailBackend: Send {
+    /// A cursor for right now, to start watching from
+    async fn current_cursor(&mut self) -> Result<String>;
+
+    /// Messages added after `cursor`, oldest first, or None if the cursor is
+    /// too old for the backend to list changes from
+    async fn changes_since(&mut self, cursor: &str) -> Result<Option<Vec<MinimalMessage>>>;
+
+    /// Skips messages that no longer exist. Each message's `history_id` is
+    /// the cursor to resume from once it has been processed.
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>>;
+
+    /// Unread messages in the inbox, if the backend can tell
+    async fn inbox_unread(&mut self) -> Result<Option<u64>>;
+
+    /// Names of the labels the mailbox keeps itself, like Gmail's `type:
+    /// system` labels, as opposed to ones the user made
+    fn system_labels(&self) -> HashSet<String> {
+        HashSet::new()
+    }
+
+    /// Whether the last `changes_since` stopped at a page boundary with more
+    /// to list, so its messages should be processed and the rest listed
+    /// from the cursor it reached in the same poll
+    fn more_changes(&self) -> bool {
+        false
+    }
+
+    /// Labels added to existing messages by the last `changes_since`, one
+    /// label ID per message it was added to
+    fn take_labels_added(&mut self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Messages deleted for good in the last `changes_since`
+    fn take_messages_deleted(&mut self) -> u64 {
+        0
+    }
+
+    /// How far `cursor` is behind the mailbox's latest change, if cursors can
+    /// be compared
+    async fn cursor_lag(&mut self, _cursor: &str) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// The cursor the last `changes_since` reached, to resume from once all
+    /// of its messages have been processed. Without one the watcher resumes
+    /// from the last message, and sees any later changes again.
+    fn take_latest_cursor(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Messages currently starred, if the backend has stars
+    async fn starred_total(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn drafts_total(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Messages in the spam folder, if the backend has one
+    async fn spam_total(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Messages in the trash, if the backend has one
+    async fn trash_total(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Drafts last saved before `before`
+    async fn drafts_before(
+        &mut self,
+        _before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Messages in the inbox received before each of `cutoffs`, all counted
+    /// from the same listing; None if they can't be counted this time
+    async fn inbox_older_than(
+        &mut self,
+        _cutoffs: &[chrono::DateTime<chrono::Utc>],
+    ) -> Result<Option<Vec<u64>>> {
+        Ok(None)
+    }
+
+    async fn mailbox_settings(&mut self) -> Result<Option<MailboxSettings>> {
+        Ok(None)
+    }
+
+    /// IDs of messages received since `since` that match a stream query,
+    /// written in the backend's own search syntax
+    async fn search_since(
+        &mut self,
+        query: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>>;
+
+    /// Ask Gmail to push change notifications to a Pub/Sub topic
+    async fn watch_pubsub(&mut self, _topic: &str) -> Result<WatchResponse> {
+        Err(Error::Unsupported(
+            "--pubsub-topic is only supported for Gmail",
+        ))
+    }
+
+    /// The contents of one of a message's `attachments`
+    async fn fetch_attachment(
+        &mut self,
+        _message_id: &str,
+        _attachment: &Attachment,
+    ) -> Result<Vec<u8>> {
+        Err(Error::Unsupported(
+            "Downloading attachments is only supported for Gmail",
+        ))
+    }
+
+    /// Notified when the server reports a change, so the watcher can poll
+    /// without waiting out its interval
+    fn wake(&self) -> Option<Arc<Notify>> {
+        None
+    }
+}
+
+pub struct GmailBackend {
+    pub mail: MailClient,
+    /// Label ID to name
+    labels: HashMap<String, String>,
+    /// Names of the labels the labels API reports as `type: system`
+    system_labels: HashSet<String>,
+    /// From the last history fetch, waiting for `take_labels_added`
+    labels_added: Vec<String>,
+    /// From the last history fetch, waiting for `take_messages_deleted`
+    messages_deleted: u64,
+    /// From the last history fetch, waiting for `take_latest_cursor`
+    latest_history_id: Option<String>,
+    /// From the last history fetch: the history record each message was
+    /// added in, to resume from once it has been processed
+    added_in: HashMap<String, String>,
+    /// Messages added on earlier pages of a listing that isn't finished yet,
+    /// whose later labels were already counted on arrival
+    added_earlier: HashSet<String>,
+    /// Whether the last history page had more after it
+    more: bool,
+    /// Pages listed this poll, for --max-pages
+    pages: u32,
+}
+
+impl GmailBackend {
+    /// Loads the mailbox's labels, to name them in metrics
+    pub async fn new(mut mail: MailClient) -> Result<Self> {
+        let listed = mail.list_labels().await?;
+        let system_labels = listed
+            .iter()
+            .filter(|label| label.label_type.as_deref() == Some("system"))
+            .map(|label| label.name.clone())
+            .collect();
+        let labels = listed
+            .into_iter()
+            .map(|label| (label.id, label.name))
+            .collect();
+        Ok(Self {
+            mail,
+            labels,
+            system_labels,
+            labels_added: vec![],
+            messages_deleted: 0,
+            latest_history_id: None,
+            added_in: HashMap::new(),
+            added_earlier: HashSet::new(),
+            more: false,
+            pages: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl MailBackend for GmailBackend {
+    async fn current_cursor(&mut self) -> Result<String> {
+        Ok(self.mail.fetch_profile().await?.history_id)
+    }
+
+    /// Lists a page of history at a time, so a long catch-up is recorded
+    /// and checkpointed as it goes rather than held in memory
+    async fn changes_since(&mut self, cursor: &str) -> Result<Option<Vec<MinimalMessage>>> {
+        let Some(changes) = self.mail.fetch_history_pages(cursor, Some(1)).await? else {
+            self.added_earlier.clear();
+            return Ok(None);
+        };
+        self.pages += 1;
+
+        self.added_earlier.extend(changes.added_in.keys().cloned());
+        self.labels_added = changes
+            .labels_added
+            .into_iter()
+            .flat_map(
+                |labels_added| match self.added_earlier.contains(&labels_added.message.id) {
+                    true => labels_added
+                        .label_ids
+                        .into_iter()
+                        .filter(|label| !COUNTED_ON_ARRIVAL.contains(&label.as_str()))
+                        .collect(),
+                    false => labels_added.label_ids,
+                },
+            )
+            .collect();
+        if !changes.more {
+            self.added_earlier.clear();
+        }
+        self.more = changes.more && !self.mail.last_page(self.pages);
+        if changes.more && !self.more {
+            warn!(
+                "History listing stopped after {} pages (--max-pages), continuing next poll",
+                self.pages
+            );
+        }
+        if !self.more {
+            self.pages = 0;
+        }
+
+        self.messages_deleted = changes.messages_deleted;
+        self.latest_history_id = changes.history_id;
+        self.added_in = changes.added_in;
+        Ok(Some(changes.messages_added))
+    }
+
+    fn more_changes(&self) -> bool {
+        self.more
+    }
+
+    fn system_labels(&self) -> HashSet<String> {
+        self.system_labels.clone()
+    }
+
+    fn take_labels_added(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.labels_added)
+    }
+
+    fn take_messages_deleted(&mut self) -> u64 {
+        std::mem::take(&mut self.messages_deleted)
+    }
+
+    fn take_latest_cursor(&mut self) -> Option<String> {
+        self.latest_history_id.take()
+    }
+
+    async fn cursor_lag(&mut self, cursor: &str) -> Result<Option<u64>> {
+        let latest = self.current_cursor().await?;
+        Ok(lag_between(&latest, cursor))
+    }
+
+    async fn starred_total(&mut self) -> Result<Option<u64>> {
+        Ok(self.mail.get_label("STARRED").await?.messages_total)
+    }
+
+    async fn drafts_total(&mut self) -> Result<Option<u64>> {
+        Ok(self.mail.get_label("DRAFT").await?.messages_total)
+    }
+
+    async fn spam_total(&mut self) -> Result<Option<u64>> {
+        Ok(self.mail.get_label("SPAM").await?.messages_total)
+    }
+
+    async fn trash_total(&mut self) -> Result<Option<u64>> {
+        Ok(self.mail.get_label("TRASH").await?.messages_total)
+    }
+
+    async fn drafts_before(
+        &mut self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<u64>> {
+        let drafts = self
+            .mail
+            .search_message_ids(&format!("in:drafts before:{}", before.timestamp()))
+            .await?;
+        Ok(Some(drafts.len() as u64))
+    }
+
+    /// Lists the inbox once, up to the newest cutoff. messages.list has no
+    /// dates, but lists newest first, so the messages older than each other
+    /// cutoff are a tail of it, found by looking up a few of their dates.
+    async fn inbox_older_than(
+        &mut self,
+        cutoffs: &[chrono::DateTime<chrono::Utc>],
+    ) -> Result<Option<Vec<u64>>> {
+        let Some(newest) = cutoffs.iter().max() else {
+            return Ok(Some(vec![]));
+        };
+        let query = format!("in:inbox before:{}", newest.timestamp());
+        let max_pages = self.mail.max_pages();
+        let (listing, complete) = self.mail.search_message_pages(&query, max_pages).await?;
+        if !complete {
+            warn!(
+                "Not counting inbox messages by age: the search for {:?} stopped after {} pages (--max-pages)",
+                query,
+                max_pages.unwrap_or_default()
+            );
+            return Ok(None);
+        }
+
+        let mut dates = HashMap::new();
+        let mut counts = vec![];
+        for cutoff in cutoffs {
+            if cutoff == newest {
+                counts.push(listing.len() as u64);
+                continue;
+            }
+            // The first message older than the cutoff
+            let (mut start, mut end) = (0, listing.len());
+            while start < end {
+                let middle = (start + end) / 2;
+                let date = match dates.get(&middle) {
+                    Some(date) => *date,
+                    None => {
+                        let Some(date) = self.mail.fetch_internal_date(&listing[middle].id).await?
+                        else {
+                            warn!("Not counting inbox messages by age: a message was deleted while counting");
+                            return Ok(None);
+                        };
+                        dates.insert(middle, date);
+                        date
+                    }
+                };
+                match date < *cutoff {
+                    true => end = middle,
+                    false => start = middle + 1,
+                }
+            }
+            counts.push((listing.len() - start) as u64);
+        }
+        Ok(Some(counts))
+    }
+
+    async fn mailbox_settings(&mut self) -> Result<Option<MailboxSettings>> {
+        let vacation = self.mail.get_vacation().await?;
+        let send_as = self.mail.list_send_as().await?;
+        let forwarding_addresses = self.mail.list_forwarding_addresses().await?;
+        let filters = self.mail.list_filters().await?;
+
+        Ok(Some(MailboxSettings {
+            vacation_responder: vacation.enable_auto_reply,
+            send_as_aliases: send_as.iter().filter(|send_as| !send_as.is_primary).count() as u64,
+            forwarding_addresses: forwarding_addresses
+                .into_iter()
+                .map(|address| address.forwarding_email)
+                .collect(),
+            filters: filters
+                .into_iter()
+                .map(|filter| FilterFingerprint {
+                    id: filter.id,
+                    criteria_hash: short_hash(&filter.criteria.to_string()),
+                    action_hash: short_hash(&filter.action.to_string()),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>> {
+        let mut details = self.mail.fetch_mail_details(messages, &self.labels).await?;
+        for message in &mut details {
+            if let Some(added_in) = self.added_in.remove(&message.id) {
+                message.history_id = added_in;
+            }
+        }
+        Ok(details)
+    }
+
+    async fn inbox_unread(&mut self) -> Result<Option<u64>> {
+        Ok(self.mail.get_label("INBOX").await?.messages_unread)
+    }
+
+    async fn search_since(
+        &mut self,
+        query: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>> {
+        self.mail
+            .search_message_ids(&format!("({}) after:{}", query, since.timestamp() - 1))
+            .await
+    }
+
+    async fn watch_pubsub(&mut self, topic: &str) -> Result<WatchResponse> {
+        self.mail.watch(topic).await
+    }
+
+    async fn fetch_attachment(
+        &mut self,
+        message_id: &str,
+        attachment: &Attachment,
+    ) -> Result<Vec<u8>> {
+        self.mail
+            .fetch_attachment(message_id, &attachment.attachment_id)
+            .await
+    }
+}
+
+/// History IDs only ever grow, so their difference counts the changes in between
+fn lag_between(latest: &str, cursor: &str) -> Option<u64> {
+    let latest = latest.parse::<u64>().ok()?;
+    Some(latest.saturating_sub(cursor.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lag_between_counts_up_to_the_latest_history_id() {
+        assert_eq!(lag_between("1500", "1200"), Some(300));
+        // A cursor from ahead of the profile, e.g. just after a poll, isn't negative lag
+        assert_eq!(lag_between("1200", "1500"), Some(0));
+        assert_eq!(lag_between("1500", "not-a-history-id"), None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mail::{MinimalMessage, UsableMessageDetails};
+
+/// How far an `export` or `sync` got, saved in the state file after every
+/// batch so an interrupted one carries on from there instead of starting
+/// over
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillProgress {
+    /// Which backfill this is, e.g. the command and its output; a different
+    /// one starts over
+    pub name: String,
+    /// The search the backfill lists, kept so a resumed sync covers the same
+    /// window as the run it resumes
+    pub query: String,
+    /// History ID from the start of a sync, saved for the watcher once the
+    /// sync is done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_id: Option<String>,
+    /// Messages written so far
+    pub written: usize,
+    /// The newest message written so far, in Unix milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_internal_date: Option<i64>,
+    /// Messages already written from the second of `last_internal_date`,
+    /// which the resumed listing includes again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub written_in_last_second: Vec<String>,
+}
+
+impl BackfillProgress {
+    pub fn new(name: String, query: String) -> Self {
+        Self {
+            name,
+            query,
+            history_id: None,
+            written: 0,
+            last_internal_date: None,
+            written_in_last_second: vec![],
+        }
+    }
+
+    /// The search that lists what's left: everything from the second of the
+    /// newest message written on. Gmail's `after:` is exclusive and in whole
+    /// seconds.
+    pub fn remaining_query(&self) -> String {
+        match self.last_internal_date {
+            Some(millis) => format!("{} after:{}", self.query, millis.div_euclid(1000) - 1),
+            None => self.query.clone(),
+        }
+    }
+
+    /// Leaves out messages from the boundary second that were written before
+    pub fn skip_written(&self, listing: &mut Vec<MinimalMessage>) {
+        let written = self.written_in_last_second.iter().collect::<HashSet<_>>();
+        listing.retain(|message| !written.contains(&message.id));
+    }
+
+    /// Notes a batch as written, once it's safely in the output
+    pub fn advance(&mut self, batch: &[UsableMessageDetails]) {
+        self.written += batch.len();
+        for message in batch {
+            let millis = message.internal_date.timestamp_millis();
+            let second = millis.div_euclid(1000);
+            match self.last_internal_date.map(|last| last.div_euclid(1000)) {
+                Some(last_second) if second < last_second => continue,
+                Some(last_second) if second == last_second => {}
+                _ => self.written_in_last_second.clear(),
+            }
+            self.last_internal_date = Some(self.last_internal_date.unwrap_or(millis).max(millis));
+            self.written_in_last_second.push(message.id.clone());
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use crate::{
+    loops::{LoopDetectionConfig, LoopDetector},
+    mail_rules::{self, RuleConfig},
+    pipeline::{MetricsPipeline, PipelineSettings},
+    risky_attachments::{RiskyAttachments, RiskyAttachmentsConfig},
+    snippets::{Snippets, SnippetsConfig},
+};
+
+/// Settings read from `--config`, which can be reloaded with SIGHUP.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub labels: LabelFilters,
+    pub cardinality: CardinalityConfig,
+    pub streams: Vec<StreamConfig>,
+    /// Subject regexes counted in `email_subject_match_total`
+    pub subjects: Vec<TextMatcherConfig>,
+    /// Body regexes counted in `email_body_match_total`; needs --scan-bodies
+    pub bodies: Vec<TextMatcherConfig>,
+    /// Custom counters for messages matching a rule
+    pub rules: Vec<RuleConfig>,
+    /// Attachment types counted in `email_risky_attachment_total`
+    pub risky_attachments: Option<RiskyAttachmentsConfig>,
+    /// Threads and repeated subjects counted in `email_possible_loop_total`
+    pub loop_detection: Option<LoopDetectionConfig>,
+    /// When to poll less often
+    pub quiet_hours: Option<QuietHoursConfig>,
+    /// Message previews for event sinks and webhook notifications
+    pub snippets: Option<SnippetsConfig>,
+    /// IANA timezone for the `hour` and `weekday` labels, quiet hours and the
+    /// anomaly score, e.g. `Europe/Berlin`; overrides --timezone
+    pub timezone: Option<Tz>,
+    /// Mailboxes to watch instead of the one given through the environment,
+    /// each labelled with its `name` as `account`
+    pub accounts: Vec<AccountConfig>,
+    /// Labels put on every metric next to `instance_id`, e.g. `env = "home"`;
+    /// only read at startup
+    pub global_labels: BTreeMap<String, String>,
+}
+
+/// Only the filters and cardinality settings of an account are reloaded on
+/// SIGHUP; adding, removing or re-authenticating accounts needs a restart.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    pub name: String,
+    pub provider: MailProvider,
+    /// Defaults to GOOGLE_CLIENT_ID, or GRAPH_CLIENT_ID for Microsoft Graph
+    pub client_id: Option<String>,
+    /// Defaults to GOOGLE_CLIENT_SECRET, or GRAPH_CLIENT_SECRET for Microsoft Graph
+    #[serde(skip_serializing)]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing)]
+    pub refresh_token: Option<String>,
+    #[serde(skip_serializing)]
+    pub access_token: Option<String>,
+    /// Azure AD tenant of a Microsoft Graph account; defaults to GRAPH_TENANT, then `common`
+    pub tenant: Option<String>,
+    /// Mail folder of a Microsoft Graph account to watch; defaults to `inbox`
+    pub folder: Option<String>,
+    pub state_file: Option<PathBuf>,
+    /// Replaces the top-level label filters for this account
+    pub labels: Option<LabelFilters>,
+    /// Overrides the top-level cardinality settings for this account
+    pub cardinality: CardinalityConfig,
+    /// Replaces the top-level streams for this account
+    pub streams: Option<Vec<StreamConfig>>,
+    /// Replaces the top-level subject matchers for this account
+    pub subjects: Option<Vec<TextMatcherConfig>>,
+    /// Replaces the top-level body matchers for this account
+    pub bodies: Option<Vec<TextMatcherConfig>>,
+    /// Replaces the top-level rules for this account
+    pub rules: Option<Vec<RuleConfig>>,
+    /// Overrides the top-level timezone for this account
+    pub timezone: Option<Tz>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailProvider {
+    #[default]
+    Gmail,
+    /// Microsoft 365 / Outlook.com through Microsoft Graph
+    Graph,
+}
+
+/// A named search; new mail matching it is also counted in
+/// `email_received_by_stream_total` with a `stream` label
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    pub name: String,
+    /// Gmail search syntax, e.g. `label:PagerDuty` or `from:billing@example.com`;
+    /// an OData `$filter` for Microsoft Graph accounts, and IMAP SEARCH keys
+    /// with --imap-url
+    pub query: String,
+}
+
+/// A `[[subjects]]` or `[[bodies]]` entry: mail whose subject or body
+/// matches `pattern` is counted in `email_subject_match_total` or
+/// `email_body_match_total` with `pattern_name="<name>"`, without the text
+/// itself ending up in a label
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextMatcherConfig {
+    pub name: String,
+    /// Regex, matched anywhere in the subject
+    pub pattern: String,
+    #[serde(default)]
+    pub case_sensitive: bool,
+}
+
+/// A `[[subjects]]` or `[[bodies]]` entry, ready to match
+#[derive(Debug, Clone)]
+pub struct TextMatcher {
+    pub name: String,
+    regex: Regex,
+}
+
+impl TextMatcher {
+    fn compile(matcher: &TextMatcherConfig) -> Result<Self, String> {
+        let regex = RegexBuilder::new(&matcher.pattern)
+            .case_insensitive(!matcher.case_sensitive)
+            .build()
+            .map_err(|err| format!("Invalid pattern for matcher {}: {}", matcher.name, err))?;
+
+        Ok(Self {
+            name: matcher.name.clone(),
+            regex,
+        })
+    }
+
+    pub fn compile_all(matchers: &[TextMatcherConfig]) -> Result<Vec<Self>, String> {
+        matchers.iter().map(Self::compile).collect()
+    }
+
+    pub fn matches(&self, subject: &str) -> bool {
+        self.regex.is_match(subject)
+    }
+}
+
+/// Hours (in the configured timezone) to poll less often, e.g. overnight, to save Gmail
+/// quota. `start` and `end` are `HH:MM`; a range past midnight wraps around.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    pub start: String,
+    pub end: String,
+    /// Seconds to wait between polls during quiet hours, at least
+    pub interval: u64,
+}
+
+/// `[quiet_hours]`, with the times parsed
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    pub interval: Duration,
+}
+
+impl QuietHours {
+    pub fn compile(config: &QuietHoursConfig) -> Result<Self, String> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|err| format!("Invalid quiet hours time {:?}: {}", time, err))
+        };
+
+        Ok(Self {
+            start: parse(&config.start)?,
+            end: parse(&config.end)?,
+            interval: Duration::from_secs(config.interval),
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Which Gmail labels become `label_*` metric labels, by label name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelFilters {
+    /// If non-empty, only these labels are kept
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Applied before `include` and `exclude`, which see the new names; the
+    /// first matching entry wins
+    pub rename: Vec<LabelRenameConfig>,
+    /// How nested labels like `Finance/Bills/Utilities` are counted
+    pub nested: NestedLabels,
+    /// Cut nested labels down to this many levels, e.g. 2 counts
+    /// `Finance/Bills/Utilities` as `Finance/Bills`
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NestedLabels {
+    /// Only under the label itself
+    #[default]
+    Leaf,
+    /// Under the label and each of its parents, so `Finance/Bills` is also
+    /// counted as `Finance`
+    Explode,
+}
+
+impl LabelFilters {
+    pub fn allows(&self, label: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|included| included == label))
+            && !self.exclude.iter().any(|excluded| excluded == label)
+    }
+}
+
+/// A `[[labels.rename]]` entry, mapping either the label called `name` or
+/// every label `pattern` matches in full to `to`. Labels renamed to the same
+/// value are merged, e.g. `pattern = "Work/Projects/.*"` and `to = "work"`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelRenameConfig {
+    pub name: Option<String>,
+    /// Regex; `$1` or `${group}` in `to` is replaced with what it captured
+    pub pattern: Option<String>,
+    pub to: String,
+}
+
+/// A `[[labels.rename]]` entry, ready to apply
+#[derive(Debug, Clone)]
+pub struct LabelRename {
+    matcher: Regex,
+    to: String,
+}
+
+impl LabelRename {
+    fn compile(rename: &LabelRenameConfig) -> Result<Self, String> {
+        let pattern = match (&rename.name, &rename.pattern) {
+            (Some(name), None) => regex::escape(name),
+            (None, Some(pattern)) => pattern.clone(),
+            _ => {
+                return Err(format!(
+                    "Label rename to {:?} needs exactly one of name or pattern",
+                    rename.to
+                ))
+            }
+        };
+        let matcher = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|err| format!("Invalid label rename pattern {:?}: {}", pattern, err))?;
+
+        Ok(Self {
+            matcher,
+            to: rename.to.clone(),
+        })
+    }
+
+    pub fn compile_all(renames: &[LabelRenameConfig]) -> Result<Vec<Self>, String> {
+        renames.iter().map(Self::compile).collect()
+    }
+
+    /// The new name of `label`, or None if this entry doesn't match it
+    pub fn apply(&self, label: &str) -> Option<String> {
+        self.matcher
+            .is_match(label)
+            .then(|| self.matcher.replace(label, &self.to).into_owned())
+    }
+}
+
+/// Overrides for the matching command line flags
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CardinalityConfig {
+    pub max_received_series: Option<usize>,
+    pub time_labels: Option<bool>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read config {}: {}", path.display(), err))?;
+
+        let config: Self = toml::from_str(&contents)
+            .map_err(|err| format!("Failed to parse config {}: {}", path.display(), err))?;
+
+        // Catch bad regexes and names here, so a bad reload is rejected as a whole
+        for rules in std::iter::once(&config.rules).chain(
+            config
+                .accounts
+                .iter()
+                .filter_map(|account| account.rules.as_ref()),
+        ) {
+            mail_rules::compile_all(rules)
+                .map_err(|err| format!("{} in config {}", err, path.display()))?;
+        }
+        for labels in std::iter::once(&config.labels).chain(
+            config
+                .accounts
+                .iter()
+                .filter_map(|account| account.labels.as_ref()),
+        ) {
+            LabelRename::compile_all(&labels.rename)
+                .map_err(|err| format!("{} in config {}", err, path.display()))?;
+        }
+        for matchers in
+            [&config.subjects, &config.bodies]
+                .into_iter()
+                .chain(config.accounts.iter().flat_map(|account| {
+                    [account.subjects.as_ref(), account.bodies.as_ref()]
+                        .into_iter()
+                        .flatten()
+                }))
+        {
+            TextMatcher::compile_all(matchers)
+                .map_err(|err| format!("{} in config {}", err, path.display()))?;
+        }
+
+        if let Some(quiet_hours) = &config.quiet_hours {
+            QuietHours::compile(quiet_hours)
+                .map_err(|err| format!("{} in config {}", err, path.display()))?;
+        }
+        if let Some(snippets) = &config.snippets {
+            Snippets::compile(snippets)
+                .map_err(|err| format!("{} in config {}", err, path.display()))?;
+        }
+        if let Some(label) = config
+            .global_labels
+            .keys()
+            .find(|label| !mail_rules::is_valid_name(label) || *label == "instance_id")
+        {
+            return Err(format!(
+                "Invalid global label name {:?} in config {}",
+                label,
+                path.display()
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Human readable list of settings that differ between two configs
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        let mut changes = vec![];
+        diff_values(
+            "",
+            &serde_json::to_value(self).unwrap(),
+            &serde_json::to_value(other).unwrap(),
+            &mut changes,
+        );
+        changes
+    }
+
+    /// Layers this config, and then the matching account's section of it, over
+    /// the settings given on the command line.
+    pub fn apply(&self, base: &PipelineSettings, account: Option<&str>) -> PipelineSettings {
+        let mut settings = base.clone();
+        settings.label_filters = self.labels.clone();
+        settings.streams = self.streams.clone();
+        let mut subjects = &self.subjects;
+        let mut bodies = &self.bodies;
+        let mut rules = &self.rules;
+        self.cardinality.apply(&mut settings);
+        if let Some(timezone) = self.timezone {
+            settings.timezone = timezone;
+        }
+
+        if let Some(account) =
+            account.and_then(|name| self.accounts.iter().find(|account| account.name == name))
+        {
+            if let Some(labels) = &account.labels {
+                settings.label_filters = labels.clone();
+            }
+            if let Some(streams) = &account.streams {
+                settings.streams = streams.clone();
+            }
+            if let Some(account_subjects) = &account.subjects {
+                subjects = account_subjects;
+            }
+            if let Some(account_bodies) = &account.bodies {
+                bodies = account_bodies;
+            }
+            if let Some(account_rules) = &account.rules {
+                rules = account_rules;
+            }
+            account.cardinality.apply(&mut settings);
+            if let Some(timezone) = account.timezone {
+                settings.timezone = timezone;
+            }
+        }
+
+        settings.rules = mail_rules::compile_all(rules).expect("Rules are validated when loading");
+        settings.subject_matchers = TextMatcher::compile_all(subjects)
+            .expect("Subject matchers are validated when loading");
+        settings.body_matchers =
+            TextMatcher::compile_all(bodies).expect("Body matchers are validated when loading");
+        settings.risky_attachments = self.risky_attachments.as_ref().map(RiskyAttachments::new);
+        settings.loop_detection = self
+            .loop_detection
+            .as_ref()
+            .map(|config| Arc::new(LoopDetector::new(config)));
+        settings.quiet_hours = self.quiet_hours.as_ref().map(|quiet_hours| {
+            QuietHours::compile(quiet_hours).expect("Quiet hours are validated when loading")
+        });
+        settings.snippets = self.snippets.as_ref().map(|snippets| {
+            Snippets::compile(snippets).expect("Snippet redactions are validated when loading")
+        });
+        settings.label_renames = LabelRename::compile_all(&settings.label_filters.rename)
+            .expect("Label renames are validated when loading");
+        settings
+    }
+
+    fn account_names(&self) -> Vec<&str> {
+        self.accounts
+            .iter()
+            .map(|account| account.name.as_str())
+            .collect()
+    }
+}
+
+impl CardinalityConfig {
+    fn apply(&self, settings: &mut PipelineSettings) {
+        if let Some(max_received_series) = self.max_received_series {
+            settings.max_received_series = Some(max_received_series);
+        }
+        if let Some(time_labels) = self.time_labels {
+            settings.time_labels = time_labels;
+        }
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut keys = old_fields
+                .keys()
+                .chain(new_fields.keys())
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let child_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(
+                    &child_path,
+                    old_fields.get(key).unwrap_or(&Value::Null),
+                    new_fields.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items))
+            if old_items.len() == new_items.len() =>
+        {
+            for (index, (old_item, new_item)) in old_items.iter().zip(new_items).enumerate() {
+                diff_values(&format!("{}[{}]", path, index), old_item, new_item, changes);
+            }
+        }
+        _ if old != new => changes.push(format!("{}: {} -> {}", path, old, new)),
+        _ => {}
+    }
+}
+
+/// Re-reads the config on SIGHUP and swaps the new settings into each
+/// account's pipeline. A config that fails to load is logged and ignored,
+/// keeping the old one.
+pub fn spawn_reload_on_sighup(
+    path: PathBuf,
+    initial: Config,
+    base: PipelineSettings,
+    pipelines: Vec<Arc<MetricsPipeline>>,
+) {
+    tokio::spawn(async move {
+        let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to install SIGHUP handler");
+        let mut current = initial;
+
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading {}", path.display());
+
+            let new = match Config::load(&path) {
+                Ok(new) => new,
+                Err(err) => {
+                    error!("{}; keeping the current config", err);
+                    continue;
+                }
+            };
+
+            let changes = current.diff(&new);
+            if changes.is_empty() {
+                info!("Config unchanged");
+                continue;
+            }
+            for change in &changes {
+                info!("Config changed: {}", change);
+            }
+
+            if current.account_names() != new.account_names() {
+                warn!("Accounts were added or removed; that only takes effect on restart");
+            }
+            if current.global_labels != new.global_labels {
+                warn!("Global labels changed; that only takes effect on restart");
+            }
+
+            for pipeline in &pipelines {
+                pipeline.set_settings(new.apply(&base, pipeline.account.as_deref()));
+            }
+            current = new;
+        }
+    });
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::{LabelFilters, StreamConfig},
+    http_trace,
+    pipeline::PipelineSettings,
+    top_senders::TopSenders,
+};
+
+/// What `/debug/status` reports, kept up to date by the watcher, pipeline and
+/// auth as they go.
+static STATUS: OnceLock<Mutex<DebugStatus>> = OnceLock::new();
+
+/// Each account's top senders, read when a snapshot is taken
+static TOP_SENDERS: Mutex<BTreeMap<String, Arc<TopSenders>>> = Mutex::new(BTreeMap::new());
+
+/// How far back `messages_last_24h` looks, in hourly buckets
+const RECENT_HOURS: i64 = 24;
+
+/// Key for the mailbox watched without an `account` label
+const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DebugStatus {
+    /// Sent with every Gmail API and auth request
+    pub user_agent: String,
+    pub accounts: BTreeMap<String, AccountStatus>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AccountStatus {
+    pub history_id: Option<String>,
+    pub last_poll: Option<LastPoll>,
+    /// Unknown until the token has been refreshed at least once
+    pub access_token_expires_at: Option<DateTime<Utc>>,
+    /// Messages found but not yet processed (see --max-messages-per-poll)
+    pub backlog_messages: usize,
+    /// Messages counted since this time yesterday, to the hour
+    pub messages_last_24h: usize,
+    /// Only with --top-senders
+    pub top_senders: Vec<TopSender>,
+    pub settings: Option<SettingsStatus>,
+    /// Messages counted per hour (as hours since the epoch), oldest first
+    #[serde(skip)]
+    hourly_messages: VecDeque<(i64, usize)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopSender {
+    pub sender: String,
+    pub messages: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastPoll {
+    pub at: DateTime<Utc>,
+    pub outcome: PollOutcome,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case", tag = "result")]
+pub enum PollOutcome {
+    Ok {
+        messages: usize,
+    },
+    HistoryReset,
+    /// The poll panicked, e.g. on an API error
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingsStatus {
+    pub label_filters: LabelFilters,
+    pub max_received_series: Option<usize>,
+    pub time_labels: bool,
+    pub timezone: String,
+    pub streams: Vec<StreamConfig>,
+    pub rules: Vec<String>,
+}
+
+fn update(account: Option<&str>, f: impl FnOnce(&mut AccountStatus)) {
+    let mut status = STATUS
+        .get_or_init(|| Mutex::new(DebugStatus::default()))
+        .lock()
+        .unwrap();
+    f(status
+        .accounts
+        .entry(account.unwrap_or(DEFAULT_ACCOUNT).to_owned())
+        .or_default());
+}
+
+fn current_hour() -> i64 {
+    Utc::now().timestamp() / 3600
+}
+
+pub fn snapshot() -> DebugStatus {
+    let mut snapshot = STATUS
+        .get()
+        .map(|status| status.lock().unwrap().clone())
+        .unwrap_or_default();
+    snapshot.user_agent = http_trace::user_agent().to_owned();
+
+    let oldest_hour = current_hour() - RECENT_HOURS;
+    let top_senders = TOP_SENDERS.lock().unwrap();
+    for (account, status) in snapshot.accounts.iter_mut() {
+        status.messages_last_24h = status
+            .hourly_messages
+            .iter()
+            .filter(|(hour, _)| *hour > oldest_hour)
+            .map(|(_, messages)| messages)
+            .sum();
+        if let Some(top) = top_senders.get(account) {
+            status.top_senders = top
+                .top()
+                .into_iter()
+                .map(|(sender, messages)| TopSender { sender, messages })
+                .collect();
+        }
+    }
+
+    snapshot
+}
+
+pub fn register_top_senders(account: Option<&str>, top_senders: Arc<TopSenders>) {
+    TOP_SENDERS
+        .lock()
+        .unwrap()
+        .insert(account.unwrap_or(DEFAULT_ACCOUNT).to_owned(), top_senders);
+}
+
+pub fn record_poll(
+    account: Option<&str>,
+    history_id: &str,
+    outcome: PollOutcome,
+    backlog_messages: usize,
+) {
+    update(account, |status| {
+        status.history_id = Some(history_id.to_owned());
+        status.last_poll = Some(LastPoll {
+            at: Utc::now(),
+            outcome,
+        });
+        status.backlog_messages = backlog_messages;
+
+        if let PollOutcome::Ok { messages } = outcome {
+            let hour = current_hour();
+            match status.hourly_messages.back_mut() {
+                Some((last, count)) if *last == hour => *count += messages,
+                _ => status.hourly_messages.push_back((hour, messages)),
+            }
+            while status
+                .hourly_messages
+                .front()
+                .is_some_and(|(oldest, _)| *oldest <= hour - RECENT_HOURS)
+            {
+                status.hourly_messages.pop_front();
+            }
+        }
+    });
+}
+
+pub fn record_token_expiry(account: Option<&str>, expires_at: DateTime<Utc>) {
+    update(account, |status| {
+        status.access_token_expires_at = Some(expires_at)
+    });
+}
+
+pub fn record_settings(account: Option<&str>, settings: &PipelineSettings) {
+    update(account, |status| {
+        status.settings = Some(SettingsStatus {
+            label_filters: settings.label_filters.clone(),
+            max_received_series: settings.max_received_series,
+            time_labels: settings.time_labels,
+            timezone: settings.timezone.name().to_owned(),
+            streams: settings.streams.clone(),
+            rules: settings
+                .rules
+                .iter()
+                .map(|rule| rule.name.clone())
+                .collect(),
+        })
+    });
+}
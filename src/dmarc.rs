@@ -0,0 +1,106 @@
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use crate::mail::{Attachment, UsableMessageDetails};
+
+/// An aggregate (RUA) report, as described in RFC 7489 appendix C. Only the
+/// parts the metrics are built from are kept.
+#[derive(Debug, Deserialize)]
+pub struct Feedback {
+    #[serde(default)]
+    pub record: Vec<Record>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    pub row: Row,
+}
+
+/// Messages from one source IP that were all treated the same way
+#[derive(Debug, Deserialize)]
+pub struct Row {
+    pub source_ip: String,
+    pub count: u64,
+    pub policy_evaluated: PolicyEvaluated,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyEvaluated {
+    /// `none`, `quarantine` or `reject`
+    pub disposition: String,
+    /// `pass` or `fail`, after alignment
+    pub dkim: String,
+    pub spf: String,
+}
+
+/// Reporters send the XML as is, gzipped or zipped
+const REPORT_EXTENSIONS: [&str; 4] = [".xml", ".xml.gz", ".gz", ".zip"];
+
+/// Attachments of `message` that look like aggregate reports. Reporters are
+/// asked to use a `Report Domain: ...` subject (RFC 7489 section 7.2.1.1),
+/// which every major one does.
+pub fn report_attachments(message: &UsableMessageDetails) -> Vec<&Attachment> {
+    if !message
+        .subject
+        .trim_start()
+        .to_lowercase()
+        .starts_with("report domain:")
+    {
+        return vec![];
+    }
+
+    message
+        .attachments
+        .iter()
+        .filter(|attachment| {
+            let filename = attachment.filename.to_lowercase();
+            REPORT_EXTENSIONS
+                .iter()
+                .any(|extension| filename.ends_with(extension))
+        })
+        .collect()
+}
+
+/// Parses a report attachment, unpacking it first if it's gzipped or zipped
+pub fn parse_report(data: &[u8]) -> Result<Feedback, String> {
+    let xml = if data.starts_with(&[0x1f, 0x8b]) {
+        let mut xml = String::new();
+        GzDecoder::new(data)
+            .read_to_string(&mut xml)
+            .map_err(|err| format!("Failed to gunzip report: {}", err))?;
+        xml
+    } else if data.starts_with(b"PK\x03\x04") {
+        unzip_report(data)?
+    } else {
+        String::from_utf8(data.to_vec()).map_err(|err| format!("Report isn't UTF-8: {}", err))?
+    };
+
+    quick_xml::de::from_str(&xml).map_err(|err| format!("Failed to parse report XML: {}", err))
+}
+
+/// The first XML file in a zip archive
+fn unzip_report(data: &[u8]) -> Result<String, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))
+        .map_err(|err| format!("Failed to unzip report: {}", err))?;
+
+    for index in 0..archive.len() {
+        let mut file = archive
+            .by_index(index)
+            .map_err(|err| format!("Failed to unzip report: {}", err))?;
+        if !file
+            .name()
+            .is_ok_and(|name| name.to_lowercase().ends_with(".xml"))
+        {
+            continue;
+        }
+
+        let mut xml = String::new();
+        file.read_to_string(&mut xml)
+            .map_err(|err| format!("Failed to unzip report: {}", err))?;
+        return Ok(xml);
+    }
+
+    Err("Zipped report has no XML file".to_owned())
+}
@@ -0,0 +1,59 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::mail::{ParseForMetrics, UsableMessageDetails};
+
+/// Remembers the messages seen within a window, by a hash of their
+/// `Message-ID`, to spot the same message arriving more than once.
+pub struct DuplicateDetector {
+    window: Duration,
+    /// Fingerprint to the ID of the first message with it, and when it arrived
+    seen: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+impl DuplicateDetector {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Messages without a `Message-ID` are told apart by sender, subject and
+    /// date instead
+    fn fingerprint(message: &UsableMessageDetails) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match &message.message_id {
+            Some(message_id) => message_id.trim().hash(&mut hasher),
+            None => (
+                message.from.first_address(),
+                &message.subject,
+                message.internal_date,
+            )
+                .hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    /// Whether another copy of the message was already seen within the
+    /// window. Seeing the very same message again (e.g. after a history
+    /// reset) doesn't count.
+    pub fn is_duplicate(&self, message: &UsableMessageDetails) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, (_, first_seen)| now.duration_since(*first_seen) < self.window);
+
+        let fingerprint = Self::fingerprint(message);
+        match seen.get(&fingerprint) {
+            Some((first_id, _)) => *first_id != message.id,
+            None => {
+                seen.insert(fingerprint, (message.id.clone(), now));
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::state;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors from authenticating with Google or calling the Gmail API, and from
+/// the other mail backends
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent or its response couldn't be read
+    Http(reqwest::Error),
+    /// A response body wasn't JSON
+    InvalidJson {
+        url: String,
+        source: serde_json::Error,
+    },
+    /// The API answered with an error object
+    Api {
+        code: Option<i64>,
+        reason: ApiErrorReason,
+        message: String,
+    },
+    /// A response didn't have the shape we expected
+    UnexpectedResponse { what: &'static str, body: Value },
+    /// Credentials are missing or were rejected while exchanging them for a token
+    Auth(String),
+    /// No working access token; visit `auth_url` and pass the code it
+    /// redirects with to `GoogleAuth::handle_callback_url`
+    NotAuthenticated { auth_url: String },
+    /// Replaying recorded responses, and this request was never recorded
+    NotRecorded { request: String },
+    /// An IMAP command failed, even after reconnecting
+    Imap(String),
+    /// The backend can't do what was asked of it
+    Unsupported(&'static str),
+}
+
+/// What kind of error the API answered with, as the `reason` label of
+/// `gmail_api_errors_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorReason {
+    /// Over the project's quota, or sending too fast
+    RateLimitExceeded,
+    /// Over the older per-user quota
+    UserRateLimitExceeded,
+    /// A 5xx on Google's side, usually gone on retry
+    BackendError,
+    /// The refresh token has expired or was revoked
+    InvalidGrant,
+    /// The token wasn't granted a scope the request needs
+    InsufficientScope,
+    /// The access token has expired
+    Unauthenticated,
+    NotFound,
+    Other,
+}
+
+impl ApiErrorReason {
+    /// The reason for the API error in `json`, if it is one
+    pub fn from_api(json: &Value) -> Option<Self> {
+        let error = json.get("error")?;
+        let Value::Object(_) = error else {
+            // OAuth token endpoints use a string code
+            return Some(match error.as_str() {
+                Some("invalid_grant") => ApiErrorReason::InvalidGrant,
+                _ => ApiErrorReason::Other,
+            });
+        };
+
+        let reasons = error["errors"]
+            .as_array()
+            .into_iter()
+            .chain(error["details"].as_array())
+            .flatten()
+            .filter_map(|err| err["reason"].as_str())
+            .collect::<Vec<_>>();
+        let code = error["code"].as_i64().unwrap_or_default();
+        Some(if reasons.contains(&"userRateLimitExceeded") {
+            ApiErrorReason::UserRateLimitExceeded
+        } else if reasons.contains(&"rateLimitExceeded") || code == 429 {
+            ApiErrorReason::RateLimitExceeded
+        } else if reasons.contains(&"backendError") || code >= 500 {
+            ApiErrorReason::BackendError
+        } else if reasons.contains(&"insufficientPermissions")
+            || reasons.contains(&"ACCESS_TOKEN_SCOPE_INSUFFICIENT")
+        {
+            ApiErrorReason::InsufficientScope
+        } else if code == 401 {
+            ApiErrorReason::Unauthenticated
+        } else if code == 404 {
+            ApiErrorReason::NotFound
+        } else {
+            ApiErrorReason::Other
+        })
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ApiErrorReason::RateLimitExceeded => "rate_limit_exceeded",
+            ApiErrorReason::UserRateLimitExceeded => "user_rate_limit_exceeded",
+            ApiErrorReason::BackendError => "backend_error",
+            ApiErrorReason::InvalidGrant => "invalid_grant",
+            ApiErrorReason::InsufficientScope => "insufficient_scope",
+            ApiErrorReason::Unauthenticated => "unauthenticated",
+            ApiErrorReason::NotFound => "not_found",
+            ApiErrorReason::Other => "other",
+        }
+    }
+
+    /// Whether the same request is likely to work after backing off
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            ApiErrorReason::RateLimitExceeded
+                | ApiErrorReason::UserRateLimitExceeded
+                | ApiErrorReason::BackendError
+        )
+    }
+
+    /// Counts the API error in `json`, if it is one, in `gmail_api_errors_total`
+    pub(crate) fn count(json: &Value, account: Option<&str>) -> Option<Self> {
+        let reason = Self::from_api(json)?;
+        let mut labels = vec![("reason".to_owned(), reason.label().to_owned())];
+        if let Some(account) = account {
+            labels.push(("account".to_owned(), account.to_owned()));
+        }
+        state::increment_counter("gmail_api_errors_total", &labels, 1);
+        Some(reason)
+    }
+}
+
+impl Error {
+    /// The API error in `json`, if it is one
+    pub(crate) fn from_api(json: &Value) -> Option<Self> {
+        let reason = ApiErrorReason::from_api(json)?;
+        let error = &json["error"];
+        Some(match error {
+            Value::Object(_) => Error::Api {
+                code: error["code"].as_i64(),
+                reason,
+                message: error["message"].as_str().unwrap_or_default().to_owned(),
+            },
+            // OAuth token endpoints use a string code with a separate description
+            _ => Error::Api {
+                code: None,
+                reason,
+                message: format!(
+                    "{}: {}",
+                    error.as_str().unwrap_or_default(),
+                    json["error_description"].as_str().unwrap_or_default()
+                ),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "HTTP request failed: {}", err),
+            Error::InvalidJson { url, source } => {
+                write!(f, "Expected {} to return JSON: {}", url, source)
+            }
+            Error::Api {
+                code: Some(code),
+                message,
+                ..
+            } => write!(f, "API error {}: {}", code, message),
+            Error::Api {
+                code: None,
+                message,
+                ..
+            } => write!(f, "API error: {}", message),
+            Error::UnexpectedResponse { what, body } => {
+                write!(f, "Expected {}, got {}", what, body)
+            }
+            Error::Auth(message) => write!(f, "{}", message),
+            Error::NotAuthenticated { auth_url } => {
+                write!(f, "Not authenticated; visit {} to authenticate", auth_url)
+            }
+            Error::NotRecorded { request } => {
+                write!(f, "No recorded response to replay for {}", request)
+            }
+            Error::Imap(message) => write!(f, "IMAP request failed: {}", message),
+            Error::Unsupported(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(err) => Some(err),
+            Error::InvalidJson { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
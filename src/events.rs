@@ -0,0 +1,159 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::{
+    mail::{ParseForMetrics, UsableMessageDetails},
+    mail_rules::is_valid_name,
+};
+
+/// Events waiting for a slow or unreachable sink beyond this are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// One record per observed message, handed to every configured sink
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MessageEvent {
+    /// Always `message`; Home Assistant's MQTT `event` entities require it
+    pub event_type: &'static str,
+    pub account: Option<String>,
+    pub id: String,
+    pub thread_id: String,
+    pub from: String,
+    pub from_domain: String,
+    pub to: String,
+    pub to_domain: String,
+    pub subject: String,
+    pub labels: Vec<String>,
+    pub category: Option<&'static str>,
+    /// A preview of the text, if `[snippets]` is in the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// When Gmail received the message
+    pub date: chrono::DateTime<chrono::Utc>,
+    /// When the exporter saw it
+    pub observed_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl MessageEvent {
+    pub fn new(
+        account: Option<&str>,
+        message: &UsableMessageDetails,
+        snippet: Option<String>,
+    ) -> Self {
+        Self {
+            event_type: "message",
+            account: account.map(str::to_owned),
+            id: message.id.clone(),
+            thread_id: message.thread_id.clone(),
+            from: message.from.first_address().unwrap_or_default(),
+            from_domain: message.from.first_domain().unwrap_or_default(),
+            to: message.to.first_address().unwrap_or_default(),
+            to_domain: message.to.first_domain().unwrap_or_default(),
+            subject: message.subject.clone(),
+            labels: message.labels.clone(),
+            category: message.category(),
+            snippet,
+            date: message.internal_date,
+            observed_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Somewhere message events are streamed to
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    /// Must not block polling; sinks queue the event and deliver it in the background
+    fn publish(&self, event: &MessageEvent);
+
+    /// Like `publish`, but waits for room in the queue instead of dropping
+    /// the event, for backfills that can afford to
+    async fn publish_waiting(&self, event: &MessageEvent) {
+        self.publish(event);
+    }
+
+    /// Stops taking events and waits for the queued ones to be delivered,
+    /// before a command that publishes and exits does
+    async fn close(&self) {}
+}
+
+/// Stands in for a real sink in a dry run, logging instead of connecting
+pub struct DryRunSink(pub &'static str);
+
+impl EventSink for DryRunSink {
+    fn publish(&self, event: &MessageEvent) {
+        info!(sink = self.0, ?event, "dry run: would publish event");
+    }
+}
+
+/// The polling side of a sink whose delivery loop runs in its own task,
+/// reading from the receiver `new` hands back.
+pub struct QueuedSink {
+    name: &'static str,
+    /// Taken on close, which ends the delivery loop once it has drained
+    queue: Mutex<Option<mpsc::Sender<MessageEvent>>>,
+    delivery: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl QueuedSink {
+    pub fn new(name: &'static str) -> (Self, mpsc::Receiver<MessageEvent>) {
+        let (queue, events) = mpsc::channel(QUEUE_CAPACITY);
+        let sink = Self {
+            name,
+            queue: Mutex::new(Some(queue)),
+            delivery: Mutex::new(None),
+        };
+        (sink, events)
+    }
+
+    /// The task reading from the receiver, for `close` to wait on
+    pub fn delivered_by(self, delivery: JoinHandle<()>) -> Self {
+        *self.delivery.lock().unwrap() = Some(delivery);
+        self
+    }
+
+    fn queue(&self) -> Option<mpsc::Sender<MessageEvent>> {
+        self.queue.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EventSink for QueuedSink {
+    fn publish(&self, event: &MessageEvent) {
+        let Some(queue) = self.queue() else {
+            return;
+        };
+        if queue.try_send(event.clone()).is_err() {
+            warn!(
+                sink = self.name,
+                message_id = event.id,
+                "Event queue is full, dropping event"
+            );
+        }
+    }
+
+    async fn publish_waiting(&self, event: &MessageEvent) {
+        if let Some(queue) = self.queue() {
+            let _ = queue.send(event.clone()).await;
+        }
+    }
+
+    async fn close(&self) {
+        self.queue.lock().unwrap().take();
+        let delivery = self.delivery.lock().unwrap().take();
+        if let Some(delivery) = delivery {
+            let _ = delivery.await;
+        }
+    }
+}
+
+/// Clap parser for the table a database sink writes to. The name is spliced
+/// into SQL, so only `table` or `schema.table` made of plain identifiers is allowed.
+pub fn parse_table_name(table: &str) -> Result<String, String> {
+    if table.split('.').count() <= 2 && table.split('.').all(is_valid_name) {
+        Ok(table.to_owned())
+    } else {
+        Err(format!("{:?} isn't a plain table name", table))
+    }
+}
@@ -0,0 +1,468 @@
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+use std::{
+    fs::{File, OpenOptions},
+    path::PathBuf,
+};
+
+use chrono::NaiveDate;
+use clap::{Args, ValueEnum};
+#[cfg(feature = "parquet")]
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use tracing::info;
+
+use crate::{
+    backfill::BackfillProgress,
+    mail::{MailClient, ParseForMetrics, UsableMessageDetails},
+    state::StateFile,
+};
+
+/// Messages fetched and written per batch; each batch is one Parquet row group
+const BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportOptions {
+    #[arg(long, env = "EXPORT_FORMAT", value_enum, default_value_t = ExportFormat::Csv)]
+    pub format: ExportFormat,
+
+    /// Export mail received on or after this date (UTC), e.g. `2023-01-01`
+    #[arg(long, env = "EXPORT_SINCE")]
+    pub since: NaiveDate,
+
+    /// Export mail received before this date (UTC); defaults to now
+    #[arg(long, env = "EXPORT_UNTIL")]
+    pub until: Option<NaiveDate>,
+
+    /// Additional Gmail search query to narrow the export, e.g. `in:inbox`
+    #[arg(long, env = "EXPORT_QUERY")]
+    pub query: Option<String>,
+
+    #[arg(long, env = "EXPORT_OUT")]
+    pub out: PathBuf,
+
+    /// Save progress here after every batch, so an interrupted export run
+    /// again with the same options appends the rest to --out instead of
+    /// starting over. CSV only, since a Parquet file can't be appended to
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+}
+
+const COLUMNS: [&str; 12] = [
+    "id",
+    "thread_id",
+    "internal_date",
+    "from_address",
+    "from_name",
+    "from_domain",
+    "to_address",
+    "to_domain",
+    "subject",
+    "labels",
+    "category",
+    "size_estimate",
+];
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str = "
+message messages {
+    REQUIRED BYTE_ARRAY id (UTF8);
+    REQUIRED BYTE_ARRAY thread_id (UTF8);
+    REQUIRED INT64 internal_date (TIMESTAMP(MILLIS,true));
+    OPTIONAL BYTE_ARRAY from_address (UTF8);
+    OPTIONAL BYTE_ARRAY from_name (UTF8);
+    OPTIONAL BYTE_ARRAY from_domain (UTF8);
+    OPTIONAL BYTE_ARRAY to_address (UTF8);
+    OPTIONAL BYTE_ARRAY to_domain (UTF8);
+    REQUIRED BYTE_ARRAY subject (UTF8);
+    REQUIRED group labels (LIST) {
+        REPEATED group list {
+            REQUIRED BYTE_ARRAY element (UTF8);
+        }
+    }
+    OPTIONAL BYTE_ARRAY category (UTF8);
+    REQUIRED INT64 size_estimate;
+}
+";
+
+struct ExportRow {
+    id: String,
+    thread_id: String,
+    internal_date: chrono::DateTime<chrono::Utc>,
+    from_address: Option<String>,
+    from_name: Option<String>,
+    from_domain: Option<String>,
+    to_address: Option<String>,
+    to_domain: Option<String>,
+    subject: String,
+    labels: Vec<String>,
+    category: Option<&'static str>,
+    size_estimate: u64,
+}
+
+impl From<UsableMessageDetails> for ExportRow {
+    fn from(message: UsableMessageDetails) -> Self {
+        Self {
+            from_address: message.from.first_address(),
+            from_name: message.from.first_display_name(),
+            from_domain: message.from.first_domain(),
+            to_address: message.to.first_address(),
+            to_domain: message.to.first_domain(),
+            category: message.category(),
+            id: message.id,
+            thread_id: message.thread_id,
+            internal_date: message.internal_date,
+            subject: message.subject,
+            labels: message.labels,
+            size_estimate: message.size_estimate,
+        }
+    }
+}
+
+enum ExportWriter {
+    Csv(csv::Writer<File>),
+    #[cfg(feature = "parquet")]
+    Parquet(SerializedFileWriter<File>),
+}
+
+impl ExportWriter {
+    fn create(format: ExportFormat, file: File) -> Result<Self, String> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(COLUMNS).unwrap();
+                Self::Csv(writer)
+            }
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let schema = parse_message_type(PARQUET_SCHEMA).map_err(|err| err.to_string())?;
+                Self::Parquet(
+                    SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))
+                        .map_err(|err| err.to_string())?,
+                )
+            }
+        })
+    }
+
+    /// Carries on writing rows after those already in a CSV file
+    fn append(format: ExportFormat, file: File) -> Result<Self, String> {
+        match format {
+            ExportFormat::Csv => Ok(Self::Csv(csv::Writer::from_writer(file))),
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => Err("Parquet exports can't be appended to".to_owned()),
+        }
+    }
+
+    fn write(&mut self, rows: &[ExportRow]) -> Result<(), String> {
+        match self {
+            Self::Csv(writer) => {
+                for row in rows {
+                    writer
+                        .write_record([
+                            row.id.as_str(),
+                            &row.thread_id,
+                            &row.internal_date.to_rfc3339(),
+                            row.from_address.as_deref().unwrap_or_default(),
+                            row.from_name.as_deref().unwrap_or_default(),
+                            row.from_domain.as_deref().unwrap_or_default(),
+                            row.to_address.as_deref().unwrap_or_default(),
+                            row.to_domain.as_deref().unwrap_or_default(),
+                            &row.subject,
+                            &row.labels.join(";"),
+                            row.category.unwrap_or_default(),
+                            &row.size_estimate.to_string(),
+                        ])
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => write_row_group(writer, rows).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Makes sure everything written so far is in the file, before the
+    /// progress past it is saved
+    fn flush(&mut self) -> Result<(), String> {
+        match self {
+            Self::Csv(writer) => writer.flush().map_err(|err| err.to_string()),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(_) => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            Self::Csv(mut writer) => writer.flush().map_err(|err| err.to_string()),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.close().map(|_| ()).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// Values and definition levels for an optional column
+#[cfg(feature = "parquet")]
+fn optional_column<'a>(
+    values: impl Iterator<Item = Option<&'a str>>,
+) -> (Vec<ByteArray>, Vec<i16>) {
+    let mut present = vec![];
+    let mut def_levels = vec![];
+    for value in values {
+        def_levels.push(value.is_some() as i16);
+        present.extend(value.map(ByteArray::from));
+    }
+    (present, def_levels)
+}
+
+/// Values, definition levels and repetition levels for a list column. An
+/// empty list is a single level-0 entry; each item after the first in a row
+/// repeats at level 1.
+#[cfg(feature = "parquet")]
+fn list_column<'a>(
+    lists: impl Iterator<Item = &'a [String]>,
+) -> (Vec<ByteArray>, Vec<i16>, Vec<i16>) {
+    let mut values = vec![];
+    let mut def_levels = vec![];
+    let mut rep_levels = vec![];
+    for list in lists {
+        if list.is_empty() {
+            def_levels.push(0);
+            rep_levels.push(0);
+        }
+        for (index, item) in list.iter().enumerate() {
+            values.push(ByteArray::from(item.as_str()));
+            def_levels.push(1);
+            rep_levels.push((index > 0) as i16);
+        }
+    }
+    (values, def_levels, rep_levels)
+}
+
+#[cfg(feature = "parquet")]
+fn write_row_group(
+    writer: &mut SerializedFileWriter<File>,
+    rows: &[ExportRow],
+) -> parquet::errors::Result<()> {
+    let mut row_group = writer.next_row_group()?;
+
+    // Columns have to be written in schema order
+    for column in COLUMNS {
+        let mut column_writer = row_group
+            .next_column()?
+            .expect("Expected a writer for every column in the schema");
+
+        match column {
+            "internal_date" | "size_estimate" => {
+                let values = rows
+                    .iter()
+                    .map(|row| match column {
+                        "internal_date" => row.internal_date.timestamp_millis(),
+                        _ => row.size_estimate as i64,
+                    })
+                    .collect::<Vec<_>>();
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            "id" | "thread_id" | "subject" => {
+                let values = rows
+                    .iter()
+                    .map(|row| match column {
+                        "id" => ByteArray::from(row.id.as_str()),
+                        "thread_id" => ByteArray::from(row.thread_id.as_str()),
+                        _ => ByteArray::from(row.subject.as_str()),
+                    })
+                    .collect::<Vec<_>>();
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            "labels" => {
+                let (values, def_levels, rep_levels) =
+                    list_column(rows.iter().map(|row| row.labels.as_slice()));
+                column_writer.typed::<ByteArrayType>().write_batch(
+                    &values,
+                    Some(&def_levels),
+                    Some(&rep_levels),
+                )?;
+            }
+            _ => {
+                let (values, def_levels) = optional_column(rows.iter().map(|row| match column {
+                    "from_address" => row.from_address.as_deref(),
+                    "from_name" => row.from_name.as_deref(),
+                    "from_domain" => row.from_domain.as_deref(),
+                    "to_address" => row.to_address.as_deref(),
+                    "to_domain" => row.to_domain.as_deref(),
+                    "category" => row.category,
+                    _ => unreachable!("column {} is missing from write_row_group", column),
+                }));
+                column_writer.typed::<ByteArrayType>().write_batch(
+                    &values,
+                    Some(&def_levels),
+                    None,
+                )?;
+            }
+        }
+
+        column_writer.close()?;
+    }
+
+    row_group.close()?;
+    Ok(())
+}
+
+fn search_query(options: &ExportOptions) -> String {
+    let timestamp = |date: NaiveDate| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+
+    // Epoch seconds rather than dates, which Gmail would read in the
+    // account's own timezone
+    let mut query = format!("after:{}", timestamp(options.since) - 1);
+    if let Some(until) = options.until {
+        query.push_str(&format!(" before:{}", timestamp(until)));
+    }
+    if let Some(extra) = &options.query {
+        query.push_str(&format!(" ({})", extra));
+    }
+    query
+}
+
+/// Write every message in the range to `options.out`, oldest first. With a
+/// state file, carries on from an interrupted export of the same messages
+/// to the same file. Returns how many messages the file holds.
+pub async fn run(options: &ExportOptions, mail: &mut MailClient) -> Result<usize, String> {
+    #[cfg(feature = "parquet")]
+    if options.state_file.is_some() && matches!(options.format, ExportFormat::Parquet) {
+        return Err("--state-file only works with --format csv".to_owned());
+    }
+
+    let labels = mail.load_labels().await.map_err(|err| err.to_string())?;
+
+    let query = search_query(options);
+    let name = format!("export {}", options.out.display());
+    let state_file = options
+        .state_file
+        .as_ref()
+        .map(|path| StateFile::new(path.clone(), None));
+    let resumed = state_file
+        .as_ref()
+        .and_then(|state_file| state_file.load().backfill)
+        .filter(|progress| progress.name == name && progress.query == query);
+    let resuming = resumed.is_some();
+    let mut progress = resumed.unwrap_or_else(|| BackfillProgress::new(name, query));
+    if resuming {
+        info!(
+            "Resuming the export after the {} messages already in {}",
+            progress.written,
+            options.out.display()
+        );
+    }
+
+    let query = progress.remaining_query();
+    info!("Listing messages matching {}", query);
+    // A backfill lists everything, whatever --max-pages says
+    let (mut listing, _) = mail
+        .search_message_pages(&query, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    listing.reverse();
+    progress.skip_written(&mut listing);
+    info!("Exporting {} messages", listing.len());
+
+    let mut writer = if resuming {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&options.out)
+            .map_err(|err| format!("Failed to open {}: {}", options.out.display(), err))?;
+        ExportWriter::append(options.format, file)?
+    } else {
+        let file = File::create(&options.out)
+            .map_err(|err| format!("Failed to create {}: {}", options.out.display(), err))?;
+        ExportWriter::create(options.format, file)?
+    };
+
+    let mut exported = 0;
+    for batch in listing.chunks(BATCH_SIZE) {
+        let messages = mail
+            .fetch_mail_details(batch.to_vec(), &labels)
+            .await
+            .map_err(|err| err.to_string())?;
+        progress.advance(&messages);
+        let rows = messages
+            .into_iter()
+            .map(ExportRow::from)
+            .collect::<Vec<_>>();
+        writer.write(&rows)?;
+
+        exported += rows.len();
+        info!("Exported {}/{} messages", exported, listing.len());
+        if let Some(state_file) = &state_file {
+            writer.flush()?;
+            state_file.save_backfill(Some(&progress));
+        }
+    }
+
+    writer.finish()?;
+    if let Some(state_file) = &state_file {
+        state_file.save_backfill(None);
+    }
+    Ok(progress.written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(until: Option<NaiveDate>, query: Option<&str>) -> ExportOptions {
+        ExportOptions {
+            format: ExportFormat::Csv,
+            since: NaiveDate::from_ymd_opt(2023, 12, 1).unwrap(),
+            until,
+            query: query.map(str::to_owned),
+            out: PathBuf::from("messages.csv"),
+            state_file: None,
+        }
+    }
+
+    #[test]
+    fn search_query_uses_utc_epoch_seconds_including_the_first_day() {
+        // 2023-12-01T00:00:00Z is 1701388800; after: is exclusive
+        assert_eq!(search_query(&options(None, None)), "after:1701388799");
+        assert_eq!(
+            search_query(&options(
+                NaiveDate::from_ymd_opt(2024, 1, 1),
+                Some("label:Receipts")
+            )),
+            "after:1701388799 before:1704067200 (label:Receipts)"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn list_column_levels_mark_empty_lists_and_repeats() {
+        let labels = [
+            vec!["INBOX".to_owned(), "UNREAD".to_owned()],
+            vec![],
+            vec!["Receipts".to_owned()],
+        ];
+
+        let (values, def_levels, rep_levels) = list_column(labels.iter().map(Vec::as_slice));
+
+        assert_eq!(values, ["INBOX", "UNREAD", "Receipts"].map(ByteArray::from));
+        assert_eq!(def_levels, [1, 1, 0, 1]);
+        assert_eq!(rep_levels, [0, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use metrics_exporter_prometheus::formatting::{sanitize_label_key, sanitize_label_value};
+
+static GLOBAL_LABELS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+static COLLECTORS: Mutex<Vec<Arc<dyn Collector>>> = Mutex::new(vec![]);
+
+/// Something that renders its own series at scrape time, for metrics whose
+/// series need to disappear again (which the recorder can't do).
+pub trait Collector: Send + Sync {
+    /// `# HELP` and `# TYPE` lines. Collectors with the same header (e.g. one
+    /// per account) are rendered together as a single family.
+    fn header(&self) -> String;
+    /// One line per series
+    fn render_series(&self) -> String;
+}
+
+/// Must be given the same global labels as the PrometheusBuilder, so that
+/// series we render ourselves line up with what the recorder renders.
+pub fn install(global_labels: Vec<(String, String)>) {
+    GLOBAL_LABELS
+        .set(global_labels)
+        .expect("exposition::install called twice");
+}
+
+pub fn register_collector(collector: Arc<dyn Collector>) {
+    COLLECTORS.lock().unwrap().push(collector);
+}
+
+pub fn render_collectors() -> String {
+    let mut families: Vec<(String, String)> = vec![];
+
+    for collector in COLLECTORS.lock().unwrap().iter() {
+        let header = collector.header();
+        let series = collector.render_series();
+        match families
+            .iter_mut()
+            .find(|(existing, _)| *existing == header)
+        {
+            Some((_, family)) => family.push_str(&series),
+            None => families.push((header, series)),
+        }
+    }
+
+    families
+        .into_iter()
+        .map(|(header, series)| format!("{}{}\n", header, series))
+        .collect()
+}
+
+/// Renders a series the same way metrics-exporter-prometheus does: global
+/// labels first, overridden in place by any metric label of the same name.
+pub fn format_series(name: &str, labels: &[(String, String)]) -> String {
+    let mut merged = GLOBAL_LABELS.get().cloned().unwrap_or_default();
+    for (key, value) in labels {
+        match merged.iter_mut().find(|(existing, _)| existing == key) {
+            Some(existing) => existing.1 = value.clone(),
+            None => merged.push((key.clone(), value.clone())),
+        }
+    }
+
+    let rendered = merged
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", sanitize_label_key(k), sanitize_label_value(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("{}{{{}}}", name, rendered)
+}
+
+/// Drops per-sender series (those with a `from` label), smallest value first,
+/// until `rendered` fits in `max_bytes`. Returns the trimmed text and how
+/// many series were dropped.
+pub fn trim_sender_series(rendered: &str, max_bytes: usize) -> (String, usize) {
+    if rendered.len() <= max_bytes {
+        return (rendered.to_owned(), 0);
+    }
+
+    let lines = rendered.lines().collect::<Vec<_>>();
+    let mut candidates = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| {
+            !line.starts_with('#') && (line.contains("{from=\"") || line.contains(",from=\""))
+        })
+        .map(|(index, line)| {
+            let value = line
+                .rsplit(' ')
+                .next()
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap_or(0.0);
+            (index, value)
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let mut size = rendered.len();
+    let mut dropped = vec![false; lines.len()];
+    let mut dropped_count = 0;
+    for (index, _) in candidates {
+        if size <= max_bytes {
+            break;
+        }
+        size -= lines[index].len() + 1;
+        dropped[index] = true;
+        dropped_count += 1;
+    }
+
+    let trimmed = lines
+        .iter()
+        .zip(dropped)
+        .filter(|(_, dropped)| !dropped)
+        .map(|(line, _)| format!("{}\n", line))
+        .collect();
+    (trimmed, dropped_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENDERED: &str = "\
+# TYPE email_received counter
+email_received{from=\"big@example.com\"} 40
+email_received{account=\"me\",from=\"small@example.com\"} 1
+gmail_inbox_unread 7
+";
+
+    #[test]
+    fn trim_sender_series_drops_the_smallest_senders_first() {
+        let small = "email_received{account=\"me\",from=\"small@example.com\"} 1\n";
+
+        let (trimmed, dropped) = trim_sender_series(RENDERED, RENDERED.len() - small.len());
+
+        assert_eq!(dropped, 1);
+        assert_eq!(trimmed, RENDERED.replace(small, ""));
+    }
+
+    #[test]
+    fn trim_sender_series_keeps_everything_else() {
+        let (trimmed, dropped) = trim_sender_series(RENDERED, 0);
+
+        assert_eq!(dropped, 2);
+        assert_eq!(
+            trimmed,
+            "# TYPE email_received counter\ngmail_inbox_unread 7\n"
+        );
+        assert_eq!(
+            trim_sender_series(RENDERED, RENDERED.len()),
+            (RENDERED.to_owned(), 0)
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use clap::Args;
+use mailparse::{MailAddr, MailAddrList, SingleInfo};
+use serde_json::Value;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    backend::MailBackend,
+    config::AccountConfig,
+    debug_status,
+    error::{ApiErrorReason, Error, Result},
+    http_trace,
+    mail::{MinimalMessage, UsableMessageDetails},
+    state,
+};
+
+const GRAPH_API: &str = "https://graph.microsoft.com/v1.0/me";
+
+const SCOPES: &str = "offline_access https://graph.microsoft.com/Mail.Read";
+
+/// Message properties requested from delta queries
+const SELECT: &str =
+    "id,conversationId,internetMessageId,from,toRecipients,subject,receivedDateTime,isRead,flag,\
+     importance,categories,bodyPreview";
+
+#[derive(Debug, Clone, Args)]
+pub struct GraphAuthOptions {
+    /// Azure AD application (client) ID; the app needs the delegated Mail.Read permission
+    #[arg(long, env = "GRAPH_CLIENT_ID")]
+    pub graph_client_id: Option<String>,
+
+    /// Only needed for confidential (web) app registrations
+    #[arg(long, env = "GRAPH_CLIENT_SECRET", hide_env_values = true)]
+    pub graph_client_secret: Option<String>,
+
+    /// Directory (tenant) ID, or `common` / `consumers` for personal accounts
+    #[arg(long, env = "GRAPH_TENANT", default_value = "common")]
+    pub graph_tenant: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GraphOptions {
+    #[command(flatten)]
+    pub auth: GraphAuthOptions,
+
+    /// Watch a Microsoft 365 / Outlook.com mailbox through Microsoft Graph
+    /// instead of Gmail; get one with the graph-login command. Azure AD
+    /// rotates refresh tokens as they're used, but the one given here expires
+    /// 90 days after it was issued, so restarts after that need a new one.
+    #[arg(
+        long,
+        env = "GRAPH_REFRESH_TOKEN",
+        hide_env_values = true,
+        requires = "graph_client_id",
+        conflicts_with = "pubsub_topic"
+    )]
+    #[cfg_attr(feature = "imap", arg(conflicts_with = "imap_url"))]
+    pub graph_refresh_token: Option<String>,
+
+    /// Mail folder to watch, by well-known name (`inbox`) or ID
+    #[arg(long, env = "GRAPH_MAIL_FOLDER", default_value = "inbox")]
+    pub graph_mail_folder: String,
+}
+
+pub struct GraphAuth {
+    client_id: String,
+    client_secret: Option<String>,
+    tenant: String,
+    refresh_token: String,
+    access_token: Option<String>,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    account: Option<String>,
+}
+
+fn token_url(tenant: &str) -> String {
+    format!(
+        "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+        tenant
+    )
+}
+
+impl GraphAuth {
+    pub fn from_options(options: &GraphOptions, account: Option<String>) -> Result<Self> {
+        Ok(Self {
+            client_id: options.auth.graph_client_id.clone().ok_or_else(|| {
+                Error::Auth("--graph-refresh-token requires --graph-client-id".to_owned())
+            })?,
+            client_secret: options.auth.graph_client_secret.clone(),
+            tenant: options.auth.graph_tenant.clone(),
+            refresh_token: options
+                .graph_refresh_token
+                .clone()
+                .ok_or_else(|| Error::Auth("--graph-refresh-token must be set".to_owned()))?,
+            access_token: None,
+            expires_at: chrono::Utc::now(),
+            account,
+        })
+    }
+
+    pub fn for_account(account: &AccountConfig) -> Result<Self> {
+        Ok(Self {
+            client_id: account
+                .client_id
+                .clone()
+                .or_else(|| std::env::var("GRAPH_CLIENT_ID").ok())
+                .ok_or_else(|| {
+                    Error::Auth(format!(
+                        "Account {} needs a client_id, or GRAPH_CLIENT_ID must be set",
+                        account.name
+                    ))
+                })?,
+            client_secret: account
+                .client_secret
+                .clone()
+                .or_else(|| std::env::var("GRAPH_CLIENT_SECRET").ok()),
+            tenant: account
+                .tenant
+                .clone()
+                .or_else(|| std::env::var("GRAPH_TENANT").ok())
+                .unwrap_or_else(|| "common".to_owned()),
+            refresh_token: account.refresh_token.clone().ok_or_else(|| {
+                Error::Auth(format!("Account {} needs a refresh_token", account.name))
+            })?,
+            access_token: account.access_token.clone(),
+            expires_at: chrono::Utc::now(),
+            account: Some(account.name.clone()),
+        })
+    }
+
+    async fn access_token(&mut self) -> Result<String> {
+        let expiring = self.expires_at - chrono::Duration::seconds(60) < chrono::Utc::now();
+        if self.access_token.is_none() || expiring {
+            self.refresh().await?;
+        }
+        Ok(self.access_token.clone().unwrap())
+    }
+
+    #[instrument(skip_all)]
+    async fn refresh(&mut self) -> Result<()> {
+        info!("Refreshing Microsoft Graph access token");
+        let labels = match &self.account {
+            Some(account) => vec![("account".to_owned(), account.clone())],
+            None => vec![],
+        };
+        state::increment_counter("gmail_auth_refreshes_total", &labels, 1);
+
+        let mut form = vec![
+            ("client_id", self.client_id.as_str()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", self.refresh_token.as_str()),
+            ("scope", SCOPES),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let json = http_trace::try_send_json(
+            reqwest::Client::new()
+                .post(token_url(&self.tenant))
+                .form(&form),
+        )
+        .await?;
+
+        let Some(access_token) = json["access_token"].as_str() else {
+            return Err(Error::Auth(format!(
+                "Failed to refresh the Microsoft Graph access token: {}",
+                json["error_description"]
+                    .as_str()
+                    .unwrap_or("no access_token returned")
+            )));
+        };
+        self.access_token = Some(access_token.to_owned());
+        if let Some(refresh_token) = json["refresh_token"].as_str() {
+            self.refresh_token = refresh_token.to_owned();
+        }
+
+        let expires_in = json["expires_in"].as_i64().unwrap_or(3600);
+        self.expires_at = chrono::Utc::now() + chrono::Duration::seconds(expires_in);
+        debug_status::record_token_expiry(self.account.as_deref(), self.expires_at);
+        Ok(())
+    }
+}
+
+/// Sign in with the device code flow and print the refresh token to use
+pub async fn login(options: &GraphAuthOptions) -> Result<()> {
+    let client_id = options
+        .graph_client_id
+        .as_deref()
+        .ok_or_else(|| Error::Auth("graph-login needs --graph-client-id".to_owned()))?;
+    let client = reqwest::Client::new();
+
+    let device_code = http_trace::try_send_json(
+        client
+            .post(format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/devicecode",
+                options.graph_tenant
+            ))
+            .form(&[("client_id", client_id), ("scope", SCOPES)]),
+    )
+    .await?;
+    let code = device_code["device_code"].as_str().ok_or_else(|| {
+        Error::Auth(format!(
+            "Failed to start sign-in: {}",
+            device_code["error_description"]
+                .as_str()
+                .unwrap_or_default()
+        ))
+    })?;
+    println!("{}", device_code["message"].as_str().unwrap_or_default());
+
+    let mut interval = device_code["interval"].as_u64().unwrap_or(5);
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+
+        let mut form = vec![
+            ("client_id", client_id),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", code),
+        ];
+        if let Some(client_secret) = &options.graph_client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let json =
+            http_trace::try_send_json(client.post(token_url(&options.graph_tenant)).form(&form))
+                .await?;
+
+        match json["error"].as_str() {
+            None => {
+                let refresh_token = json["refresh_token"].as_str().ok_or_else(|| {
+                    Error::Auth("The token response didn't include a refresh_token".to_owned())
+                })?;
+                println!();
+                println!("export GRAPH_REFRESH_TOKEN={}", refresh_token);
+                return Ok(());
+            }
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += 5,
+            Some(_) => {
+                return Err(Error::Auth(format!(
+                    "Sign-in failed: {}",
+                    json["error_description"].as_str().unwrap_or_default()
+                )))
+            }
+        }
+    }
+}
+
+/// Watches one mail folder with delta queries. The cursor is the newest
+/// `receivedDateTime` seen (in Unix milliseconds) and the delta link,
+/// separated by a space: delta queries also return messages that were only
+/// updated, e.g. marked read, and only ones received after the mark are new.
+pub struct GraphBackend {
+    auth: GraphAuth,
+    folder: String,
+    client: reqwest::Client,
+    /// Messages returned by the last delta query, waiting for fetch_details,
+    /// with the cursor to resume from after each
+    pending: HashMap<String, (Value, String)>,
+}
+
+impl GraphBackend {
+    /// Fetches an access token, to fail at startup rather than at the first
+    /// poll if the refresh token is bad
+    pub async fn new(mut auth: GraphAuth, folder: String) -> Result<Self> {
+        auth.access_token().await?;
+
+        Ok(Self {
+            auth,
+            folder,
+            client: reqwest::Client::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    fn folder_url(&self) -> String {
+        format!("{}/mailFolders/{}", GRAPH_API, self.folder)
+    }
+
+    /// GET an absolute Graph URL, refreshing the access token once if it's rejected
+    async fn get(&mut self, url: &str) -> Result<Value> {
+        let mut refreshed = false;
+        loop {
+            let access_token = self.auth.access_token().await?;
+            let json = http_trace::try_send_json(
+                self.client
+                    .get(url)
+                    .bearer_auth(access_token)
+                    .header("Prefer", "odata.maxpagesize=100"),
+            )
+            .await?;
+
+            if json["error"]["code"] == "InvalidAuthenticationToken" && !refreshed {
+                self.auth.access_token = None;
+                refreshed = true;
+                continue;
+            }
+            break Ok(json);
+        }
+    }
+
+    /// Follows `@odata.nextLink` from `url`, returning every item and the
+    /// final `@odata.deltaLink` if there is one. None if the delta link has expired.
+    async fn get_all(&mut self, url: String) -> Result<Option<(Vec<Value>, Option<String>)>> {
+        let mut items = vec![];
+        let mut url = url;
+
+        loop {
+            let json = self.get(&url).await?;
+            if let Some(code) = json["error"]["code"].as_str() {
+                if code.eq_ignore_ascii_case("SyncStateNotFound")
+                    || code.eq_ignore_ascii_case("SyncStateInvalid")
+                {
+                    return Ok(None);
+                }
+                return Err(graph_error(&json));
+            }
+
+            items.extend(json["value"].as_array().cloned().unwrap_or_default());
+
+            match json["@odata.nextLink"].as_str() {
+                Some(next_link) => url = next_link.to_owned(),
+                None => {
+                    return Ok(Some((
+                        items,
+                        json["@odata.deltaLink"].as_str().map(str::to_owned),
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Graph error codes are strings like `ErrorItemNotFound`, so they go in the message
+fn graph_error(json: &Value) -> Error {
+    Error::Api {
+        code: None,
+        reason: ApiErrorReason::Other,
+        message: format!(
+            "{}: {}",
+            json["error"]["code"].as_str().unwrap_or_default(),
+            json["error"]["message"].as_str().unwrap_or_default()
+        ),
+    }
+}
+
+fn received_millis(message: &Value) -> i64 {
+    message["receivedDateTime"]
+        .as_str()
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| date.timestamp_millis())
+        .unwrap_or_default()
+}
+
+fn address(recipient: &Value) -> Option<MailAddr> {
+    Some(MailAddr::Single(SingleInfo {
+        display_name: recipient["emailAddress"]["name"]
+            .as_str()
+            .map(str::to_owned),
+        addr: recipient["emailAddress"]["address"].as_str()?.to_owned(),
+    }))
+}
+
+fn message_details(message: &Value, folder: &str, history_id: String) -> UsableMessageDetails {
+    let mut labels = vec![folder.to_uppercase()];
+    if message["isRead"] == false {
+        labels.push("UNREAD".to_owned());
+    }
+    if message["flag"]["flagStatus"] == "flagged" {
+        labels.push("STARRED".to_owned());
+    }
+    if message["importance"] == "high" {
+        labels.push("IMPORTANT".to_owned());
+    }
+    labels.extend(
+        message["categories"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|category| category.as_str().map(str::to_owned)),
+    );
+
+    UsableMessageDetails {
+        id: message["id"].as_str().unwrap_or_default().to_owned(),
+        thread_id: message["conversationId"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        history_id,
+        labels,
+        internal_date: chrono::DateTime::from_timestamp_millis(received_millis(message))
+            .unwrap_or_else(chrono::Utc::now),
+        from: MailAddrList::from(address(&message["from"]).into_iter().collect::<Vec<_>>()),
+        to: MailAddrList::from(
+            message["toRecipients"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(address)
+                .collect::<Vec<_>>(),
+        ),
+        subject: message["subject"].as_str().unwrap_or_default().to_owned(),
+        snippet: message["bodyPreview"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        // Graph doesn't expose message sizes in its standard properties
+        size_estimate: 0,
+        attachments: vec![],
+        list_unsubscribe: false,
+        body: None,
+        message_id: message["internetMessageId"].as_str().map(str::to_owned),
+    }
+}
+
+#[async_trait]
+impl MailBackend for GraphBackend {
+    /// The folder and flags each message is labelled with; categories are
+    /// the user's own
+    fn system_labels(&self) -> HashSet<String> {
+        HashSet::from([
+            self.folder.to_uppercase(),
+            "UNREAD".to_owned(),
+            "STARRED".to_owned(),
+            "IMPORTANT".to_owned(),
+        ])
+    }
+
+    async fn current_cursor(&mut self) -> Result<String> {
+        let now = chrono::Utc::now();
+        let url = format!(
+            "{}/messages/delta?$select={}&$filter=receivedDateTime+ge+{}",
+            self.folder_url(),
+            SELECT,
+            now.format("%Y-%m-%dT%H:%M:%SZ")
+        );
+        let Some((_, Some(delta_link))) = self.get_all(url).await? else {
+            return Err(Error::UnexpectedResponse {
+                what: "a fresh delta query to end with a delta link",
+                body: Value::Null,
+            });
+        };
+
+        Ok(format!("{} {}", now.timestamp_millis(), delta_link))
+    }
+
+    async fn changes_since(&mut self, cursor: &str) -> Result<Option<Vec<MinimalMessage>>> {
+        let Some((mark, delta_link)) = cursor
+            .split_once(' ')
+            .and_then(|(mark, delta_link)| Some((mark.parse::<i64>().ok()?, delta_link)))
+        else {
+            warn!("{:?} isn't a Microsoft Graph cursor", cursor);
+            return Ok(None);
+        };
+
+        let Some((items, next_delta_link)) = self.get_all(delta_link.to_owned()).await? else {
+            return Ok(None);
+        };
+        let mut added = items
+            .into_iter()
+            .filter(|item| item.get("@removed").is_none() && received_millis(item) > mark)
+            .collect::<Vec<_>>();
+        added.sort_by_key(received_millis);
+
+        // Until the last message is processed, resuming has to replay this
+        // whole delta round
+        let next_cursor = format!(
+            "{} {}",
+            added.last().map(received_millis).unwrap_or(mark),
+            next_delta_link.as_deref().unwrap_or(delta_link)
+        );
+        let last = added.len().saturating_sub(1);
+
+        self.pending.clear();
+        Ok(Some(
+            added
+                .into_iter()
+                .enumerate()
+                .map(|(index, message)| {
+                    let minimal = MinimalMessage {
+                        id: message["id"].as_str().unwrap_or_default().to_owned(),
+                        thread_id: message["conversationId"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned(),
+                    };
+                    let resume_from = match index == last {
+                        true => next_cursor.clone(),
+                        false => cursor.to_owned(),
+                    };
+                    self.pending
+                        .insert(minimal.id.clone(), (message, resume_from));
+                    minimal
+                })
+                .collect(),
+        ))
+    }
+
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>> {
+        Ok(messages
+            .into_iter()
+            .filter_map(|message| {
+                let (message, resume_from) = self.pending.remove(&message.id)?;
+                Some(message_details(&message, &self.folder, resume_from))
+            })
+            .collect())
+    }
+
+    async fn inbox_unread(&mut self) -> Result<Option<u64>> {
+        let url = format!("{}?$select=unreadItemCount", self.folder_url());
+        Ok(self.get(&url).await?["unreadItemCount"].as_u64())
+    }
+
+    async fn search_since(
+        &mut self,
+        query: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>> {
+        let filter = format!(
+            "receivedDateTime ge {} and ({})",
+            since.format("%Y-%m-%dT%H:%M:%SZ"),
+            query
+        );
+        let url = format!(
+            "{}/messages?$select=id&$filter={}",
+            self.folder_url(),
+            url::form_urlencoded::byte_serialize(filter.as_bytes()).collect::<String>()
+        );
+
+        Ok(self
+            .get_all(url)
+            .await?
+            .map(|(items, _)| items)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|item| item["id"].as_str().map(str::to_owned))
+            .collect())
+    }
+}
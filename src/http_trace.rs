@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use mailparse::{addrparse, MailAddr};
+use serde_json::{json, Value};
+use tracing::{info, instrument, trace, warn, Span};
+
+use crate::{error::Error, hashing::short_hash};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Response bodies longer than this are cut off in trace output
+const MAX_BODY_CHARS: usize = 2048;
+
+/// Keys whose values never make it into the logs, wherever they appear in a body
+const REDACTED_KEYS: [&str; 4] = ["access_token", "refresh_token", "id_token", "client_secret"];
+
+/// Headers whose addresses are swapped for pseudonyms in recorded fixtures
+const ADDRESS_HEADERS: [&str; 8] = [
+    "From",
+    "To",
+    "Cc",
+    "Bcc",
+    "Reply-To",
+    "Sender",
+    "Delivered-To",
+    "Return-Path",
+];
+
+/// Keys holding the account's own addresses, e.g. in the profile and
+/// send-as settings
+const ADDRESS_KEYS: [&str; 4] = [
+    "emailAddress",
+    "sendAsEmail",
+    "forwardingEmail",
+    "replyToAddress",
+];
+
+/// Headers recorded as they are, since they don't identify anyone
+const PLAIN_HEADERS: [&str; 4] = [
+    "Date",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "MIME-Version",
+];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("gmail-prom-exporter/", env!("CARGO_PKG_VERSION"));
+
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+/// Send `user_agent` instead of `DEFAULT_USER_AGENT`, e.g. for an egress
+/// proxy that allows clients by name
+pub fn set_user_agent(user_agent: String) {
+    assert!(
+        USER_AGENT.set(user_agent).is_ok(),
+        "http_trace::set_user_agent can only be called once"
+    );
+}
+
+pub fn user_agent() -> &'static str {
+    USER_AGENT.get().map_or(DEFAULT_USER_AGENT, String::as_str)
+}
+
+/// An HTTP client that identifies itself with `user_agent()`
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(user_agent())
+        .build()
+        .unwrap_or_else(|err| panic!("Failed to build HTTP client: {}", err))
+}
+
+/// Where JSON responses are saved to or served from instead of the network
+enum Fixtures {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+static FIXTURES: OnceLock<Fixtures> = OnceLock::new();
+
+/// How many times each request has been recorded or replayed so far
+static FIXTURE_COUNTS: Mutex<Option<HashMap<String, usize>>> = Mutex::new(None);
+
+/// Save every JSON response to `dir`, with tokens redacted, so it can be
+/// replayed later. Addresses, subjects and message IDs are replaced with
+/// pseudonyms that are stable within the recording, while snippets, bodies,
+/// other header values and search queries are left out.
+pub fn record_to(dir: PathBuf) {
+    std::fs::create_dir_all(&dir)
+        .unwrap_or_else(|err| panic!("Failed to create {}: {}", dir.display(), err));
+    assert!(
+        FIXTURES.set(Fixtures::Record(dir)).is_ok(),
+        "http_trace::record_to and replay_from can only be called once"
+    );
+}
+
+/// Serve JSON requests from responses saved by `record_to` instead of the
+/// network. The nth identical request gets the nth recorded response, and the
+/// last one once they run out.
+pub fn replay_from(dir: PathBuf) {
+    assert!(
+        dir.is_dir(),
+        "{} isn't a directory of recorded responses",
+        dir.display()
+    );
+    assert!(
+        FIXTURES.set(Fixtures::Replay(dir)).is_ok(),
+        "http_trace::record_to and replay_from can only be called once"
+    );
+}
+
+pub fn replaying() -> bool {
+    matches!(FIXTURES.get(), Some(Fixtures::Replay(_)))
+}
+
+/// Identifies a request across runs: its method, host and path, plus a hash
+/// of the query so search requests with different queries are kept apart
+fn fixture_key(method: &reqwest::Method, url: &reqwest::Url) -> String {
+    // FNV-1a, which unlike std's hasher is stable across Rust versions
+    let query_hash = url
+        .query()
+        .unwrap_or_default()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    let name = format!(
+        "{}_{}{}_{:016x}",
+        method,
+        url.host_str().unwrap_or_default(),
+        url.path(),
+        query_hash
+    );
+
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// This request's position among identical ones
+fn next_fixture_index(key: &str) -> usize {
+    let mut counts = FIXTURE_COUNTS.lock().unwrap();
+    let count = counts
+        .get_or_insert_with(HashMap::new)
+        .entry(key.to_owned())
+        .or_default();
+    *count += 1;
+    *count - 1
+}
+
+fn fixture_path(dir: &Path, key: &str, index: usize) -> PathBuf {
+    dir.join(format!("{}.{}.json", key, index))
+}
+
+fn record(
+    dir: &Path,
+    method: &reqwest::Method,
+    url: &reqwest::Url,
+    status: reqwest::StatusCode,
+    json: &Value,
+) {
+    let key = fixture_key(method, url);
+    let path = fixture_path(dir, &key, next_fixture_index(&key));
+    let url = redact_query(url);
+    let fixture = json!({
+        "method": method.as_str(),
+        "url": url.as_str(),
+        "status": status.as_u16(),
+        "body": pseudonymize(redact(json.clone())),
+    });
+
+    if let Err(err) = std::fs::write(&path, serde_json::to_string_pretty(&fixture).unwrap()) {
+        warn!(
+            "Failed to record {} {} to {}: {}",
+            method,
+            url,
+            path.display(),
+            err
+        );
+    }
+}
+
+/// `url` with the search query, which can carry addresses, redacted
+fn redact_query(url: &reqwest::Url) -> reqwest::Url {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(key, value)| match key.as_ref() {
+            "q" => (key.into_owned(), "[REDACTED]".to_owned()),
+            _ => (key.into_owned(), value.into_owned()),
+        })
+        .collect();
+
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+/// Salts the pseudonyms of one recording, so they can't be reversed by
+/// hashing a guessed address
+static PSEUDONYM_SALT: OnceLock<String> = OnceLock::new();
+
+fn pseudonym(value: &str) -> String {
+    let salt = PSEUDONYM_SALT.get_or_init(|| format!("{:032x}", rand::random::<u128>()));
+    short_hash(&format!("{}{}", salt, value.to_lowercase()))
+}
+
+/// `user@example.com` becomes `<hash>@<hash>.example`, so mail from one
+/// sender or domain still lines up across the recording
+fn pseudonymize_address(address: &str) -> String {
+    let domain = address.rsplit_once('@').map_or("", |(_, domain)| domain);
+    format!("{}@{}.example", pseudonym(address), pseudonym(domain))
+}
+
+/// The addresses in an address header's value, pseudonymized and without
+/// display names
+fn pseudonymize_addresses(value: &str) -> String {
+    let Ok(addresses) = addrparse(value) else {
+        return "[REDACTED]".to_owned();
+    };
+
+    addresses
+        .iter()
+        .flat_map(|address| match address {
+            MailAddr::Single(single) => vec![single.addr.as_str()],
+            MailAddr::Group(group) => group
+                .addrs
+                .iter()
+                .map(|single| single.addr.as_str())
+                .collect(),
+        })
+        .map(pseudonymize_address)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn pseudonymize_header(name: &str, value: &str) -> String {
+    let is = |header: &str| name.eq_ignore_ascii_case(header);
+
+    if ADDRESS_HEADERS.iter().copied().any(is) {
+        pseudonymize_addresses(value)
+    } else if is("Subject") {
+        format!("subject-{}", pseudonym(value))
+    } else if is("Message-ID") {
+        format!("<{}@pseudonym.example>", pseudonym(value))
+    } else if PLAIN_HEADERS.iter().copied().any(is) {
+        value.to_owned()
+    } else {
+        "[REDACTED]".to_owned()
+    }
+}
+
+/// Strips what identifies correspondents or reveals mail contents from a
+/// recorded response: addresses, header values, snippets and body data
+fn pseudonymize(value: Value) -> Value {
+    match value {
+        Value::Object(mut map) => {
+            if let (Some(Value::String(name)), Some(Value::String(value))) =
+                (map.get("name"), map.get("value"))
+            {
+                let value = pseudonymize_header(name, value);
+                map.insert("value".to_owned(), Value::String(value));
+                return Value::Object(map);
+            }
+
+            Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| match key.as_str() {
+                        "snippet" => (key, Value::String("[REDACTED]".to_owned())),
+                        "data" | "raw" => (key, Value::String(String::new())),
+                        key if ADDRESS_KEYS.contains(&key) => match value {
+                            Value::String(address) => {
+                                let address = pseudonymize_address(&address);
+                                (key.to_owned(), Value::String(address))
+                            }
+                            value => (key.to_owned(), pseudonymize(value)),
+                        },
+                        _ => (key, pseudonymize(value)),
+                    })
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(pseudonymize).collect()),
+        value => value,
+    }
+}
+
+fn replay(dir: &Path, method: &reqwest::Method, url: &reqwest::Url) -> Result<Value, Error> {
+    let key = fixture_key(method, url);
+    let index = next_fixture_index(&key);
+    let path = (0..=index)
+        .rev()
+        .map(|index| fixture_path(dir, &key, index))
+        .find(|path| path.exists())
+        .ok_or_else(|| Error::NotRecorded {
+            request: format!("{} {}", method, url),
+        })?;
+
+    let fixture = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .ok_or_else(|| Error::NotRecorded {
+            request: format!("{} {} ({} is unreadable)", method, url, path.display()),
+        })?;
+
+    if ENABLED.load(Ordering::Relaxed) {
+        info!(%method, %url, fixture = %path.display(), "HTTP request replayed");
+    }
+
+    Ok(fixture["body"].clone())
+}
+
+/// Send a request and parse its JSON response. With --trace-http, logs the
+/// method, URL, status and latency, plus the (redacted, truncated) body at
+/// trace level. Error responses are returned like any other JSON body.
+#[instrument(skip_all, fields(method, url, status))]
+pub async fn try_send_json(request: reqwest::RequestBuilder) -> Result<Value, Error> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let started = Instant::now();
+    record_span(&method, &url);
+
+    if let Some(Fixtures::Replay(dir)) = FIXTURES.get() {
+        return replay(dir, &method, &url);
+    }
+
+    let response = client.execute(request).await?;
+    let status = response.status();
+    Span::current().record("status", status.as_u16());
+    let body = response.text().await?;
+    let json: Result<Value, _> = serde_json::from_str(&body);
+
+    if ENABLED.load(Ordering::Relaxed) {
+        trace_exchange(&method, &url, status, started, &body, &json);
+    }
+
+    if let (Some(Fixtures::Record(dir)), Ok(json)) = (FIXTURES.get(), &json) {
+        record(dir, &method, &url, status, json);
+    }
+
+    json.map_err(|source| Error::InvalidJson {
+        url: url.to_string(),
+        source,
+    })
+}
+
+/// Like `try_send_json`, but panics if the request fails or doesn't return JSON
+pub async fn send_json(request: reqwest::RequestBuilder) -> Value {
+    try_send_json(request)
+        .await
+        .unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Send a request whose response body we don't care about. With
+/// --trace-http, logs the method, URL, status and latency.
+#[instrument(skip_all, fields(method, url, status))]
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let started = Instant::now();
+    record_span(&method, &url);
+
+    let response = client.execute(request).await?;
+    Span::current().record("status", response.status().as_u16());
+
+    if ENABLED.load(Ordering::Relaxed) {
+        info!(
+            %method,
+            %url,
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "HTTP request"
+        );
+    }
+
+    Ok(response)
+}
+
+/// Identifies the request on its span; the query is left out since it can
+/// carry search terms
+fn record_span(method: &reqwest::Method, url: &reqwest::Url) {
+    let span = Span::current();
+    span.record("method", method.as_str());
+    span.record(
+        "url",
+        format!("{}{}", url.origin().ascii_serialization(), url.path()),
+    );
+}
+
+fn trace_exchange(
+    method: &reqwest::Method,
+    url: &reqwest::Url,
+    status: reqwest::StatusCode,
+    started: Instant,
+    body: &str,
+    json: &Result<Value, serde_json::Error>,
+) {
+    info!(
+        %method,
+        %url,
+        status = status.as_u16(),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "HTTP request"
+    );
+
+    trace!(
+        %method,
+        %url,
+        body = truncate(&match &json {
+            Ok(json) => redact(json.clone()).to_string(),
+            Err(_) => body.to_owned(),
+        }),
+        "HTTP response body"
+    );
+}
+
+fn redact(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    if REDACTED_KEYS.contains(&key.as_str()) {
+                        (key, Value::String("[REDACTED]".to_owned()))
+                    } else {
+                        (key, redact(value))
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(redact).collect()),
+        value => value,
+    }
+}
+
+fn truncate(body: &str) -> String {
+    match body.char_indices().nth(MAX_BODY_CHARS) {
+        Some((index, _)) => format!("{}… ({} bytes total)", &body[..index], body.len()),
+        None => body.to_owned(),
+    }
+}
@@ -0,0 +1,415 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_imap::{extensions::idle::IdleResponse, types::Flag, Session};
+use async_trait::async_trait;
+use clap::Args;
+use futures_util::TryStreamExt;
+use mailparse::{addrparse_header, MailHeaderMap};
+use percent_encoding::percent_decode_str;
+use tokio::{net::TcpStream, sync::Notify};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    backend::MailBackend,
+    error::{Error, Result},
+    mail::{MinimalMessage, UsableMessageDetails},
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Servers may drop a connection that has been idle for 30 minutes (RFC 2177)
+const IDLE_TIMEOUT: Duration = Duration::from_secs(29 * 60);
+
+const FETCH_QUERY: &str = "(UID FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[HEADER])";
+
+#[derive(Debug, Clone, Args)]
+pub struct ImapOptions {
+    /// Watch this IMAP server instead of Gmail, e.g. `imaps://me%40fastmail.com@imap.fastmail.com`.
+    /// Only implicit TLS (usually port 993) is supported.
+    #[arg(long, env = "IMAP_URL", conflicts_with = "pubsub_topic")]
+    pub imap_url: Option<Url>,
+
+    /// Used if --imap-url doesn't include a password
+    #[arg(long, env = "IMAP_PASSWORD", hide_env_values = true)]
+    pub imap_password: Option<String>,
+
+    /// Folder to watch; its unread count is reported as `gmail_inbox_unread_messages`
+    #[arg(long, env = "IMAP_MAILBOX", default_value = "INBOX")]
+    pub imap_mailbox: String,
+}
+
+type ImapSession = Session<TlsStream<TcpStream>>;
+
+#[derive(Debug, Clone)]
+struct ImapSettings {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    mailbox: String,
+}
+
+/// Watches one folder. The cursor is `<UIDVALIDITY>:<next UID>`, so a
+/// changed UIDVALIDITY (the folder was recreated) is treated like an expired
+/// Gmail history ID.
+pub struct ImapBackend {
+    settings: ImapSettings,
+    /// Connected on first use, and again after an error
+    session: Option<ImapSession>,
+    uid_validity: u32,
+    /// Set by a second connection sitting in IDLE, if the server supports it
+    wake: Option<Arc<Notify>>,
+}
+
+/// Runs `$body` with `$session` bound to a connected session, reconnecting and
+/// trying once more if it fails. Failing twice returns an `Error::Imap` from
+/// the enclosing function.
+macro_rules! with_session {
+    ($self:ident, |$session:ident| $body:expr) => {{
+        let mut reconnected = false;
+        loop {
+            let $session = $self.session().await?;
+            let result: async_imap::error::Result<_> = async { $body }.await;
+            match result {
+                Ok(value) => break value,
+                Err(err) if !reconnected => {
+                    warn!("IMAP request failed, reconnecting: {}", err);
+                    $self.session = None;
+                    reconnected = true;
+                }
+                Err(err) => return Err(Error::Imap(err.to_string())),
+            }
+        }
+    }};
+}
+
+impl ImapBackend {
+    pub async fn connect(options: &ImapOptions) -> Self {
+        let url = options
+            .imap_url
+            .as_ref()
+            .expect("ImapBackend::connect needs --imap-url");
+        assert!(
+            url.scheme() == "imaps",
+            "Expected --imap-url to be an imaps:// URL"
+        );
+
+        let settings = ImapSettings {
+            host: url
+                .host_str()
+                .expect("Expected --imap-url to have a host")
+                .to_owned(),
+            port: url.port().unwrap_or(993),
+            user: percent_decode_str(url.username())
+                .decode_utf8_lossy()
+                .into_owned(),
+            password: url
+                .password()
+                .map(|password| {
+                    percent_decode_str(password)
+                        .decode_utf8_lossy()
+                        .into_owned()
+                })
+                .or_else(|| options.imap_password.clone())
+                .expect("Expected a password in --imap-url or --imap-password"),
+            mailbox: options.imap_mailbox.clone(),
+        };
+
+        let mut session = connect(&settings)
+            .await
+            .unwrap_or_else(|err| panic!("Failed to connect to {}: {}", settings.host, err));
+        let supports_idle = session
+            .capabilities()
+            .await
+            .is_ok_and(|capabilities| capabilities.has_str("IDLE"));
+        info!(
+            "Connected to {} as {}, watching {}",
+            settings.host, settings.user, settings.mailbox
+        );
+
+        let wake = if supports_idle {
+            let wake = Arc::new(Notify::new());
+            tokio::spawn(idle(settings.clone(), wake.clone()));
+            Some(wake)
+        } else {
+            warn!("{} doesn't support IDLE, only polling", settings.host);
+            None
+        };
+
+        Self {
+            settings,
+            session: Some(session),
+            uid_validity: 0,
+            wake,
+        }
+    }
+
+    async fn session(&mut self) -> Result<&mut ImapSession> {
+        if self.session.is_none() {
+            let session = connect(&self.settings).await.map_err(|err| {
+                Error::Imap(format!(
+                    "Failed to reconnect to {}: {}",
+                    self.settings.host, err
+                ))
+            })?;
+            self.session = Some(session);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    /// Reselects the folder, which also refreshes its UIDNEXT
+    async fn select(&mut self) -> Result<(u32, u32)> {
+        let mailbox = self.settings.mailbox.clone();
+        let (uid_validity, uid_next) = with_session!(self, |session| {
+            let selected = session.select(&mailbox).await?;
+            let uid_next = match selected.uid_next {
+                Some(uid_next) => uid_next,
+                None => {
+                    session
+                        .uid_search("ALL")
+                        .await?
+                        .into_iter()
+                        .max()
+                        .unwrap_or(0)
+                        + 1
+                }
+            };
+            Ok((selected.uid_validity.unwrap_or_default(), uid_next))
+        });
+        self.uid_validity = uid_validity;
+        Ok((uid_validity, uid_next))
+    }
+}
+
+async fn connect(settings: &ImapSettings) -> Result<ImapSession, String> {
+    let tls = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    })
+    .with_no_client_auth();
+
+    let tcp = TcpStream::connect((settings.host.as_str(), settings.port))
+        .await
+        .map_err(|err| err.to_string())?;
+    let server_name = ServerName::try_from(settings.host.clone()).map_err(|err| err.to_string())?;
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect(server_name, tcp)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let mut client = async_imap::Client::new(stream);
+    client
+        .read_response()
+        .await
+        .map_err(|err| err.to_string())?
+        .ok_or("Connection closed before the server greeting")?;
+
+    let mut session = client
+        .login(&settings.user, &settings.password)
+        .await
+        .map_err(|(err, _)| err.to_string())?;
+    session
+        .select(&settings.mailbox)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(session)
+}
+
+/// Keeps a second connection in IDLE on the folder, notifying `wake` whenever
+/// the server reports a change
+async fn idle(settings: ImapSettings, wake: Arc<Notify>) {
+    loop {
+        let mut session = match connect(&settings).await {
+            Ok(session) => session,
+            Err(err) => {
+                warn!("Failed to connect IMAP IDLE session: {}", err);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+
+        loop {
+            let mut handle = session.idle();
+            if let Err(err) = handle.init().await {
+                warn!("Failed to start IMAP IDLE: {}", err);
+                break;
+            }
+
+            let (response, _interrupt) = handle.wait_with_timeout(IDLE_TIMEOUT);
+            match response.await {
+                Ok(IdleResponse::NewData(_)) => wake.notify_one(),
+                Ok(IdleResponse::Timeout | IdleResponse::ManualInterrupt) => {}
+                Err(err) => {
+                    warn!("IMAP IDLE connection failed: {}", err);
+                    break;
+                }
+            }
+
+            session = match handle.done().await {
+                Ok(session) => session,
+                Err(err) => {
+                    warn!("Failed to end IMAP IDLE: {}", err);
+                    break;
+                }
+            };
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[async_trait]
+impl MailBackend for ImapBackend {
+    async fn current_cursor(&mut self) -> Result<String> {
+        let (uid_validity, uid_next) = self.select().await?;
+        Ok(format!("{}:{}", uid_validity, uid_next))
+    }
+
+    /// The mailbox and flags each message is labelled with
+    fn system_labels(&self) -> HashSet<String> {
+        HashSet::from([
+            self.settings.mailbox.to_uppercase(),
+            "UNREAD".to_owned(),
+            "STARRED".to_owned(),
+        ])
+    }
+
+    async fn changes_since(&mut self, cursor: &str) -> Result<Option<Vec<MinimalMessage>>> {
+        let Some((uid_validity, uid_next)): Option<(u32, u32)> = cursor
+            .split_once(':')
+            .and_then(|(validity, next)| Some((validity.parse().ok()?, next.parse().ok()?)))
+        else {
+            warn!("{:?} isn't an IMAP cursor (`<uidvalidity>:<uid>`)", cursor);
+            return Ok(None);
+        };
+
+        let (current_uid_validity, _) = self.select().await?;
+        if current_uid_validity != uid_validity {
+            return Ok(None);
+        }
+
+        // `n:*` always matches the newest message, even if its UID is below n
+        let mut uids = with_session!(self, |session| session
+            .uid_search(format!("UID {}:*", uid_next))
+            .await)
+        .into_iter()
+        .filter(|uid: &u32| *uid >= uid_next)
+        .collect::<Vec<_>>();
+        uids.sort();
+
+        Ok(Some(
+            uids.into_iter()
+                .map(|uid| MinimalMessage {
+                    id: uid.to_string(),
+                    thread_id: String::new(),
+                })
+                .collect(),
+        ))
+    }
+
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>> {
+        if messages.is_empty() {
+            return Ok(vec![]);
+        }
+        let uid_set = messages
+            .iter()
+            .map(|message| message.id.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut fetched = with_session!(self, |session| {
+            session
+                .uid_fetch(&uid_set, FETCH_QUERY)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await
+        });
+        fetched.sort_by_key(|fetch| fetch.uid);
+
+        Ok(fetched
+            .iter()
+            .filter_map(|fetch| {
+                let uid = fetch.uid?;
+                let (headers, _) = mailparse::parse_headers(fetch.header()?).ok()?;
+                let addresses = |name: &str| {
+                    headers
+                        .get_first_header(name)
+                        .and_then(|header| addrparse_header(header).ok())
+                        .unwrap_or_else(|| Vec::new().into())
+                };
+
+                let mut labels = vec![self.settings.mailbox.to_uppercase()];
+                let flags = fetch.flags().collect::<Vec<_>>();
+                if !flags.contains(&Flag::Seen) {
+                    labels.push("UNREAD".to_owned());
+                }
+                if flags.contains(&Flag::Flagged) {
+                    labels.push("STARRED".to_owned());
+                }
+
+                Some(UsableMessageDetails {
+                    id: uid.to_string(),
+                    // No threads in plain IMAP; the Message-ID at least identifies the message
+                    thread_id: headers
+                        .get_first_value("Message-ID")
+                        .unwrap_or_else(|| uid.to_string()),
+                    history_id: format!("{}:{}", self.uid_validity, uid + 1),
+                    labels,
+                    internal_date: fetch
+                        .internal_date()
+                        .map(|date| date.to_utc())
+                        .unwrap_or_else(chrono::Utc::now),
+                    from: addresses("From"),
+                    to: addresses("To"),
+                    subject: headers.get_first_value("Subject").unwrap_or_default(),
+                    // Only headers are fetched, so there's no text to preview
+                    snippet: String::new(),
+                    size_estimate: fetch.size.unwrap_or_default().into(),
+                    attachments: vec![],
+                    list_unsubscribe: headers.get_first_header("List-Unsubscribe").is_some(),
+                    body: None,
+                    message_id: headers.get_first_value("Message-ID"),
+                })
+            })
+            .collect())
+    }
+
+    async fn inbox_unread(&mut self) -> Result<Option<u64>> {
+        let unseen = with_session!(self, |session| session.uid_search("UNSEEN").await);
+        Ok(Some(unseen.len() as u64))
+    }
+
+    async fn search_since(
+        &mut self,
+        query: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>> {
+        // SINCE only has day granularity, which is fine since the result is
+        // only checked against the messages being processed
+        let criteria = format!("SINCE {} {}", since.format("%-d-%b-%Y"), query);
+        Ok(
+            with_session!(self, |session| session.uid_search(&criteria).await)
+                .into_iter()
+                .map(|uid| uid.to_string())
+                .collect(),
+        )
+    }
+
+    fn wake(&self) -> Option<Arc<Notify>> {
+        self.wake.clone()
+    }
+}
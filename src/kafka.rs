@@ -0,0 +1,112 @@
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+
+use clap::Args;
+use rskafka::{
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        ClientBuilder,
+    },
+    record::Record,
+};
+use tracing::{info, warn};
+
+use crate::events::QueuedSink;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Args)]
+pub struct KafkaOptions {
+    /// Produce a record for every observed message to these brokers (comma separated host:port)
+    #[arg(long, env = "KAFKA_BROKERS", value_delimiter = ',')]
+    pub kafka_brokers: Vec<String>,
+
+    /// Topic to produce to; it must already exist
+    #[arg(long, env = "KAFKA_TOPIC", default_value = "gmail-messages")]
+    pub kafka_topic: String,
+}
+
+/// Records are keyed by thread ID, so a thread's messages land on one
+/// partition and stay in order.
+pub fn spawn(options: &KafkaOptions, client_id: &str) -> QueuedSink {
+    let (sink, mut events) = QueuedSink::new("kafka");
+    let brokers = options.kafka_brokers.clone();
+    let topic = options.kafka_topic.clone();
+    let client_id = client_id.to_owned();
+
+    let delivery = tokio::spawn(async move {
+        let partitions = loop {
+            match connect(&brokers, &topic, &client_id).await {
+                Ok(partitions) => break partitions,
+                Err(err) => {
+                    warn!("Failed to connect to Kafka: {}", err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        };
+        info!(
+            "Connected to Kafka, producing to {} ({} partitions)",
+            topic,
+            partitions.len()
+        );
+
+        while let Some(event) = events.recv().await {
+            let mut hasher = DefaultHasher::new();
+            event.thread_id.hash(&mut hasher);
+            let partition = &partitions[hasher.finish() as usize % partitions.len()];
+
+            let record = Record {
+                key: Some(event.thread_id.clone().into_bytes()),
+                value: Some(serde_json::to_vec(&event).unwrap()),
+                headers: BTreeMap::new(),
+                timestamp: event.observed_at,
+            };
+            if let Err(err) = partition
+                .produce(vec![record], Compression::NoCompression)
+                .await
+            {
+                warn!(
+                    message_id = event.id,
+                    "Failed to produce Kafka record: {}", err
+                );
+            }
+        }
+    });
+
+    sink.delivered_by(delivery)
+}
+
+async fn connect(
+    brokers: &[String],
+    topic: &str,
+    client_id: &str,
+) -> Result<Vec<PartitionClient>, String> {
+    let client = ClientBuilder::new(brokers.to_vec())
+        .client_id(client_id)
+        .build()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let partition_ids = client
+        .list_topics()
+        .await
+        .map_err(|err| err.to_string())?
+        .into_iter()
+        .find(|found| found.name == topic)
+        .ok_or_else(|| format!("topic {} doesn't exist", topic))?
+        .partitions;
+
+    let mut partitions = vec![];
+    for partition in partition_ids {
+        partitions.push(
+            client
+                .partition_client(topic, partition, UnknownTopicHandling::Retry)
+                .await
+                .map_err(|err| err.to_string())?,
+        );
+    }
+    Ok(partitions)
+}
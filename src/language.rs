@@ -0,0 +1,49 @@
+use whatlang::Lang;
+
+/// Only the start of a body is looked at; more text rarely changes the answer
+const MAX_BODY_CHARS: usize = 2000;
+
+/// Decides the `lang` label of `email_received`: an ISO 639-3 code like
+/// `eng`, `other` for a language not in the list, or `unknown` when the text
+/// is too short or mixed to tell.
+#[derive(Debug, Clone)]
+pub struct LanguageLabels {
+    /// Empty to allow every language the detector knows
+    languages: Vec<Lang>,
+}
+
+impl LanguageLabels {
+    pub fn new(languages: Vec<Lang>) -> Self {
+        Self { languages }
+    }
+
+    /// Detects from the subject, and the start of the body if it was fetched
+    pub fn label(&self, subject: &str, body: Option<&str>) -> &'static str {
+        let mut text = subject.to_owned();
+        if let Some(body) = body {
+            text.push('\n');
+            text.extend(body.chars().take(MAX_BODY_CHARS));
+        }
+
+        match whatlang::detect(&text) {
+            Some(info) if info.is_reliable() => {
+                if self.languages.is_empty() || self.languages.contains(&info.lang()) {
+                    info.lang().code()
+                } else {
+                    "other"
+                }
+            }
+            _ => "unknown",
+        }
+    }
+}
+
+/// Clap parser for `--languages`
+pub fn parse_language(code: &str) -> Result<Lang, String> {
+    Lang::from_code(code.to_lowercase()).ok_or_else(|| {
+        format!(
+            "{:?} isn't an ISO 639-3 code of a detectable language",
+            code
+        )
+    })
+}
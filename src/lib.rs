@@ -0,0 +1,127 @@
+//! Watch a Gmail inbox and turn what arrives into Prometheus metrics.
+//!
+//! This is the library behind the `gmail-prom-exporter-rs` binary. The
+//! supported API is [`auth`] and [`mail`] for talking to Gmail, and
+//! [`pipeline`] for turning messages into metrics; [`backend`] wraps the
+//! same polling for other providers. Embedding programs install their own
+//! `metrics` recorder.
+//!
+//! ```no_run
+//! use gmail_prom_exporter_rs::{auth::GoogleAuth, mail::MailClient};
+//!
+//! # async fn run() -> gmail_prom_exporter_rs::error::Result<()> {
+//! let mut mail = MailClient::new(GoogleAuth::load_from_env().await?);
+//! let labels = mail.load_labels().await?;
+//! let profile = mail.fetch_profile().await?;
+//! // ...later, everything that arrived since:
+//! let added = mail.fetch_history(&profile.history_id).await?.unwrap_or_default();
+//! for message in mail.fetch_mail_details(added, &labels).await? {
+//!     println!("{}: {}", message.internal_date, message.subject);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod auth;
+pub mod backend;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod hashing;
+pub mod mail;
+pub mod mail_rules;
+pub mod pipeline;
+pub mod top_senders;
+
+// Used by the binary; not part of the library API and may change at any time
+#[doc(hidden)]
+pub mod anomaly;
+#[doc(hidden)]
+pub mod api;
+#[doc(hidden)]
+pub mod backfill;
+#[doc(hidden)]
+pub mod clickhouse;
+#[doc(hidden)]
+pub mod debug_status;
+#[doc(hidden)]
+pub mod dmarc;
+#[doc(hidden)]
+pub mod duplicates;
+#[doc(hidden)]
+pub mod export;
+#[doc(hidden)]
+pub mod exposition;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
+pub mod http_trace;
+#[cfg(feature = "imap")]
+#[doc(hidden)]
+pub mod imap;
+#[cfg(feature = "kafka")]
+#[doc(hidden)]
+pub mod kafka;
+#[doc(hidden)]
+pub mod language;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod loki;
+#[doc(hidden)]
+pub mod loops;
+#[doc(hidden)]
+pub mod mailbox_settings;
+#[cfg(feature = "mqtt")]
+#[doc(hidden)]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+#[doc(hidden)]
+pub mod nats;
+#[doc(hidden)]
+pub mod notify;
+#[doc(hidden)]
+pub mod openmetrics;
+#[cfg(feature = "postgres")]
+#[doc(hidden)]
+pub mod postgres;
+#[doc(hidden)]
+pub mod pubsub;
+#[doc(hidden)]
+pub mod replay_ids;
+#[doc(hidden)]
+pub mod report;
+#[doc(hidden)]
+pub mod reputation;
+#[doc(hidden)]
+pub mod risky_attachments;
+#[doc(hidden)]
+pub mod rules;
+#[doc(hidden)]
+pub mod scopes;
+#[doc(hidden)]
+pub mod self_metrics;
+#[doc(hidden)]
+pub mod senders;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod simulate;
+#[doc(hidden)]
+pub mod snapshot;
+#[doc(hidden)]
+pub mod snippets;
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod status_page;
+#[doc(hidden)]
+pub mod store;
+#[doc(hidden)]
+pub mod sync;
+#[doc(hidden)]
+pub mod systemd;
+#[doc(hidden)]
+pub mod threads;
+#[doc(hidden)]
+pub mod watch;
@@ -0,0 +1,151 @@
+#[cfg(feature = "sentry")]
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+#[cfg(feature = "otlp")]
+use opentelemetry::{trace::TracerProvider, KeyValue};
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+#[cfg(feature = "sentry")]
+use sentry::{types::Dsn, ClientInitGuard};
+#[cfg(feature = "sentry")]
+use tracing::error;
+#[cfg(feature = "otlp")]
+use tracing::warn;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// How long to wait for Sentry to accept queued events before exiting
+#[cfg(feature = "sentry")]
+const SENTRY_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LoggingOptions {
+    /// Log filter, e.g. `info` or `gmail_prom_exporter_rs=debug,warn`
+    #[arg(long, env = "LOG_LEVEL", global = true, default_value = "info")]
+    pub log_level: String,
+
+    #[arg(long, env = "LOG_FORMAT", global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// OTLP/HTTP collector to export tracing spans to, e.g.
+    /// `http://localhost:4318`; polls, API calls and token refreshes each get one
+    #[cfg(feature = "otlp")]
+    #[arg(long, env = "OTLP_ENDPOINT", global = true)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Report panics and errors to this Sentry DSN, with the fields of the
+    /// spans they happened in (e.g. the request URL or message ID); warnings
+    /// and info logs before them are attached as breadcrumbs
+    #[cfg(feature = "sentry")]
+    #[arg(long, env = "SENTRY_DSN", global = true)]
+    pub sentry_dsn: Option<Dsn>,
+}
+
+/// `log_level` accepts anything EnvFilter does. Spans that pass the filter are
+/// also exported to `otlp_endpoint` (an OTLP/HTTP collector) if given; keep
+/// the returned guard alive until exiting so the last batch is sent.
+pub fn init(options: &LoggingOptions) -> TracingGuard {
+    let filter = EnvFilter::try_new(&options.log_level).expect("Invalid --log-level");
+
+    #[cfg(feature = "sentry")]
+    let sentry = options.sentry_dsn.clone().map(|dsn| {
+        let mut client_options = sentry::ClientOptions::default();
+        client_options.dsn = Some(dsn);
+        client_options.release = sentry::release_name!();
+        client_options.attach_stacktrace = true;
+        sentry::init(client_options)
+    });
+
+    #[cfg(feature = "otlp")]
+    let provider = options.otlp_endpoint.as_deref().map(|endpoint| {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .expect("Failed to create OTLP exporter");
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+                    .build(),
+            )
+            .build()
+    });
+
+    let registry = tracing_subscriber::registry().with(filter);
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    }));
+    #[cfg(feature = "sentry")]
+    let registry = registry.with(
+        sentry
+            .as_ref()
+            .map(|_| sentry::integrations::tracing::layer()),
+    );
+    match options.log_format {
+        LogFormat::Text => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry.with(fmt::layer().json()).init(),
+    }
+
+    #[cfg(feature = "sentry")]
+    if sentry.is_some() {
+        // Logged rather than captured directly, since the hook runs inside the
+        // panicking span and the Sentry layer attaches its fields
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            error!("{}", info);
+            flush();
+            default_hook(info);
+        }));
+    }
+
+    TracingGuard {
+        #[cfg(feature = "otlp")]
+        provider,
+        #[cfg(feature = "sentry")]
+        sentry,
+    }
+}
+
+/// Sends errors waiting to be reported. Dropping the guard does this too, but
+/// `std::process::exit` skips destructors.
+pub fn flush() {
+    #[cfg(feature = "sentry")]
+    if let Some(client) = sentry::Hub::current().client() {
+        client.flush(Some(SENTRY_FLUSH_TIMEOUT));
+    }
+}
+
+/// Flushes spans and errors waiting to be exported when dropped
+#[must_use]
+pub struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<SdkTracerProvider>,
+    #[cfg(feature = "sentry")]
+    sentry: Option<ClientInitGuard>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                warn!("Failed to export the last spans: {}", err);
+            }
+        }
+        // Flushes on drop
+        #[cfg(feature = "sentry")]
+        self.sentry.take();
+    }
+}
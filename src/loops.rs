@@ -0,0 +1,148 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mail::{ParseForMetrics, UsableMessageDetails},
+    notify::{Notifier, NotifyConfig},
+};
+
+/// The `[loop_detection]` section of the config. Its counts start over when
+/// the config is reloaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoopDetectionConfig {
+    /// How far back (in seconds) messages are counted
+    pub window_seconds: u64,
+    /// Messages in one thread within the window that look like a loop
+    pub thread_messages: usize,
+    /// Messages from one sender with the same subject (ignoring `Re:` and
+    /// the like) within the window that look like an autoresponder storm
+    pub repeated_subjects: usize,
+    /// Webhook to tell when a thread or subject first looks like a loop
+    pub notify: Option<NotifyConfig>,
+}
+
+impl Default for LoopDetectionConfig {
+    fn default() -> Self {
+        Self {
+            window_seconds: 300,
+            thread_messages: 10,
+            repeated_subjects: 5,
+            notify: None,
+        }
+    }
+}
+
+/// Why a message looks like part of a mail loop, as the `reason` label
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LoopReason {
+    Thread,
+    Subject,
+}
+
+impl LoopReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoopReason::Thread => "thread",
+            LoopReason::Subject => "subject",
+        }
+    }
+}
+
+/// A message that pushed a thread or subject over its limit
+#[derive(Debug)]
+pub struct LoopMatch {
+    pub reason: LoopReason,
+    /// The first message over the limit, rather than one after it
+    pub first: bool,
+}
+
+/// Arrival times of recent messages per thread and per sender and subject
+#[derive(Debug)]
+pub struct LoopDetector {
+    window: Duration,
+    thread_messages: usize,
+    repeated_subjects: usize,
+    pub notify: Option<Arc<Notifier>>,
+    recent: Mutex<HashMap<(LoopReason, String), VecDeque<Instant>>>,
+}
+
+impl LoopDetector {
+    pub fn new(config: &LoopDetectionConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_seconds),
+            thread_messages: config.thread_messages,
+            repeated_subjects: config.repeated_subjects,
+            notify: config
+                .notify
+                .clone()
+                .map(|notify| Arc::new(Notifier::new(notify))),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn observe(&self, message: &UsableMessageDetails) -> Vec<LoopMatch> {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, arrivals| {
+            while arrivals
+                .front()
+                .is_some_and(|arrived| now.duration_since(*arrived) >= self.window)
+            {
+                arrivals.pop_front();
+            }
+            !arrivals.is_empty()
+        });
+
+        let sender = message.from.first_address().unwrap_or_default();
+        let keys = [
+            (
+                LoopReason::Thread,
+                message.thread_id.clone(),
+                self.thread_messages,
+            ),
+            (
+                LoopReason::Subject,
+                format!("{}\n{}", sender, normalize_subject(&message.subject)),
+                self.repeated_subjects,
+            ),
+        ];
+
+        let mut matches = vec![];
+        for (reason, key, limit) in keys {
+            if key.trim().is_empty() {
+                continue;
+            }
+
+            let arrivals = recent.entry((reason, key)).or_default();
+            arrivals.push_back(now);
+            if arrivals.len() >= limit {
+                matches.push(LoopMatch {
+                    reason,
+                    first: arrivals.len() == limit,
+                });
+            }
+        }
+        matches
+    }
+}
+
+/// Loops tend to pile up reply and forward prefixes, e.g. `Re: RE: AW: ...`
+fn normalize_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        let lowercase = subject.to_lowercase();
+        let Some(prefix) = ["re:", "fw:", "fwd:", "aw:", "wg:", "sv:"]
+            .into_iter()
+            .find(|prefix| lowercase.starts_with(prefix))
+        else {
+            return lowercase;
+        };
+        subject = subject[prefix.len()..].trim_start();
+    }
+}
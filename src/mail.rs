@@ -1,30 +1,44 @@
-#![allow(dead_code)]
-
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
 use chrono::TimeZone;
+use clap::Args;
 use mailparse::{addrparse, MailAddr, MailAddrList, SingleInfo};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::{instrument, warn};
 
-use crate::auth::GoogleAuth;
+use crate::{
+    auth::GoogleAuth,
+    error::{ApiErrorReason, Error, Result},
+    http_trace,
+};
 
+/// A message ID from a listing, before its details are fetched
 #[derive(Debug, Clone, Deserialize)]
 pub struct MinimalMessage {
-    id: String,
+    pub id: String,
     #[serde(rename = "threadId")]
-    thread_id: String,
+    pub thread_id: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MessagesList {
+    #[serde(default)]
     messages: Vec<MinimalMessage>,
     #[serde(rename = "nextPageToken")]
     next_page_token: Option<String>,
-    #[serde(rename = "resultSizeEstimate")]
-    result_size_estimate: u64,
 }
 
+/// The parts of a message the metrics are built from
 #[derive(Debug)]
 pub struct UsableMessageDetails {
     pub id: String,
@@ -35,28 +49,53 @@ pub struct UsableMessageDetails {
     pub from: MailAddrList,
     pub to: MailAddrList,
     pub subject: String,
+    /// A plain text preview of the start of the message
+    pub snippet: String,
+    /// Gmail's estimate of the message size in bytes
+    pub size_estimate: u64,
+    /// Attached files, to download with `MailClient::fetch_attachment`
+    pub attachments: Vec<Attachment>,
+    /// Has a `List-Unsubscribe` header, i.e. is a newsletter or other bulk mail
+    pub list_unsubscribe: bool,
+    /// Text of the message, if it was fetched in full; only ever matched
+    /// against, never exported
+    pub body: Option<String>,
+    /// The `Message-ID` header, which the sender picked
+    pub message_id: Option<String>,
+}
+
+/// A file attached to a message
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub attachment_id: String,
 }
 
 impl UsableMessageDetails {
     pub fn as_labels(&self) -> Vec<(String, String)> {
-        let mut metrics_labels = vec![];
-
-        metrics_labels.push((
-            "from".to_owned(),
-            self.from.first_address().unwrap_or("unknown".to_string()),
-        ));
-        metrics_labels.push((
-            "to".to_owned(),
-            self.to.first_address().unwrap_or("unknown".to_string()),
-        ));
-        metrics_labels.push((
-            "from_domain".to_owned(),
-            self.from.first_domain().unwrap_or("unknown".to_string()),
-        ));
-        metrics_labels.push((
-            "to_domain".to_owned(),
-            self.to.first_domain().unwrap_or("unknown".to_string()),
-        ));
+        let mut metrics_labels = vec![
+            (
+                "from".to_owned(),
+                self.from.first_address().unwrap_or("unknown".to_string()),
+            ),
+            (
+                "to".to_owned(),
+                self.to.first_address().unwrap_or("unknown".to_string()),
+            ),
+            (
+                "from_domain".to_owned(),
+                self.from
+                    .first_registrable_domain()
+                    .unwrap_or("unknown".to_string()),
+            ),
+            (
+                "to_domain".to_owned(),
+                self.to
+                    .first_registrable_domain()
+                    .unwrap_or("unknown".to_string()),
+            ),
+        ];
 
         self.labels.iter().for_each(|label| {
             metrics_labels.push((format!("label_{}", label), "true".to_owned()));
@@ -64,21 +103,47 @@ impl UsableMessageDetails {
 
         metrics_labels
     }
+
+    /// The Gmail inbox tab this message was sorted into, if any
+    pub fn category(&self) -> Option<&'static str> {
+        category_of(&self.labels)
+    }
+}
+
+/// The Gmail inbox tab a message with these labels was sorted into, if any
+pub fn category_of(labels: &[String]) -> Option<&'static str> {
+    labels.iter().find_map(|label| match label.as_str() {
+        "CATEGORY_PERSONAL" => Some("primary"),
+        "CATEGORY_PROMOTIONS" => Some("promotions"),
+        "CATEGORY_SOCIAL" => Some("social"),
+        "CATEGORY_UPDATES" => Some("updates"),
+        "CATEGORY_FORUMS" => Some("forums"),
+        _ => None,
+    })
 }
 
+/// Picks the first address out of a header, lowercased
 pub trait ParseForMetrics {
     fn first_single_mailer(&self) -> Option<SingleInfo>;
     fn first_address(&self) -> Option<String>;
     fn first_domain(&self) -> Option<String>;
+    /// The registrable part (eTLD+1) of the first domain, per the public
+    /// suffix list, e.g. `example.co.uk` for `mail.news.example.co.uk`
+    fn first_registrable_domain(&self) -> Option<String>;
     fn first_display_name(&self) -> Option<String>;
 }
 
+/// The registrable part (eTLD+1) of `domain`. Domains the public suffix list
+/// doesn't know (like bare hostnames) are kept as they are.
+pub fn registrable_domain(domain: &str) -> String {
+    psl::domain_str(domain).unwrap_or(domain).to_owned()
+}
+
 impl ParseForMetrics for MailAddrList {
     fn first_single_mailer(&self) -> Option<SingleInfo> {
         for addr in self.iter() {
-            match addr {
-                MailAddr::Single(x) => return Some(x.clone()),
-                _ => {}
+            if let MailAddr::Single(x) = addr {
+                return Some(x.clone());
             }
         }
 
@@ -86,27 +151,18 @@ impl ParseForMetrics for MailAddrList {
     }
 
     fn first_address(&self) -> Option<String> {
-        if let Some(first) = self.first_single_mailer() {
-            Some(first.addr.to_lowercase())
-        } else {
-            None
-        }
+        self.first_single_mailer()
+            .map(|first| first.addr.to_lowercase())
     }
 
     fn first_domain(&self) -> Option<String> {
-        if let Some(first) = self.first_address() {
-            Some(
-                first
-                    .split("@")
-                    .into_iter()
-                    .last()
-                    .unwrap()
-                    .to_lowercase()
-                    .to_owned(),
-            )
-        } else {
-            None
-        }
+        self.first_address()
+            .map(|first| first.rsplit('@').next().unwrap().to_lowercase())
+    }
+
+    fn first_registrable_domain(&self) -> Option<String> {
+        self.first_domain()
+            .map(|domain| registrable_domain(&domain))
     }
 
     fn first_display_name(&self) -> Option<String> {
@@ -119,24 +175,46 @@ impl ParseForMetrics for MailAddrList {
 }
 
 impl UsableMessageDetails {
-    fn from(message: MessageDetails, labels: &HashMap<String, String>) -> Self {
+    /// Label IDs are translated to names with `labels`, as returned by
+    /// `MailClient::load_labels`
+    pub fn from(message: MessageDetails, labels: &HashMap<String, String>) -> Result<Self> {
         let mut from = String::new();
         let mut to = String::new();
         let mut subject = String::new();
+        let mut list_unsubscribe = false;
+        let mut message_id = None;
+        let attachments = message.payload.attachments();
+        let body = message.payload.text();
 
         for header in message.payload.headers {
             match header.name.as_str() {
                 "From" => from = header.value.clone(),
                 "To" => to = header.value.clone(),
                 "Subject" => subject = header.value.clone(),
+                name if name.eq_ignore_ascii_case("List-Unsubscribe") => list_unsubscribe = true,
+                name if name.eq_ignore_ascii_case("Message-ID") => {
+                    message_id = Some(header.value.clone())
+                }
                 _ => {}
             }
         }
 
-        let to_parsed = addrparse(&to).unwrap();
-        let from_parsed = addrparse(&from).unwrap();
+        // One sender with a broken header shouldn't stop the rest of the mail
+        // being counted, so it's counted as from an unknown address instead
+        let parse = |header: &str, value: &str| {
+            addrparse(value).unwrap_or_else(|err| {
+                warn!(
+                    "Failed to parse {} header {:?} of message {}: {}",
+                    header, value, message.id, err
+                );
+                MailAddrList::from(vec![])
+            })
+        };
+        let to_parsed = parse("To", &to);
+        let from_parsed = parse("From", &from);
+        let internal_date = parse_internal_date(&message.internal_date)?;
 
-        Self {
+        Ok(Self {
             id: message.id,
             thread_id: message.thread_id,
             history_id: message.history_id,
@@ -145,17 +223,78 @@ impl UsableMessageDetails {
                 .iter()
                 .map(|x| labels.get(x).cloned().unwrap_or(x.clone()))
                 .collect(),
-            internal_date: chrono::Utc
-                .timestamp_millis_opt(message.internal_date.parse().unwrap())
-                .latest()
-                .expect("Expected to be able to parse out a timestamp from message.internal_date"),
+            internal_date,
             from: from_parsed,
             to: to_parsed,
             subject,
+            snippet: unescape_html(&message.snippet),
+            size_estimate: message.size_estimate,
+            attachments,
+            list_unsubscribe,
+            body,
+            message_id,
+        })
+    }
+}
+
+/// Gmail escapes snippets for HTML, e.g. apostrophes become `&#39;`
+fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let decoded = entity.and_then(|(entity, end)| {
+            let character = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix('#')
+                    .and_then(|code| match code.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => code.parse().ok(),
+                    })
+                    .and_then(char::from_u32),
+            };
+            character.map(|character| (character, end))
+        });
+
+        match decoded {
+            Some((character, end)) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
         }
     }
+
+    unescaped.push_str(rest);
+    unescaped
 }
 
+/// messages.get's `internalDate`, Unix milliseconds as a string
+fn parse_internal_date(internal_date: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    internal_date
+        .parse()
+        .ok()
+        .and_then(|millis| chrono::Utc.timestamp_millis_opt(millis).latest())
+        .ok_or_else(|| Error::UnexpectedResponse {
+            what: "internalDate to be a timestamp in milliseconds",
+            body: Value::String(internal_date.to_owned()),
+        })
+}
+
+/// A message as returned by messages.get
 #[derive(Debug, Deserialize)]
 pub struct MessageDetails {
     id: String,
@@ -175,40 +314,135 @@ pub struct MessageDetails {
 
 #[derive(Debug, Deserialize)]
 pub struct MessagePart {
-    #[serde(rename = "partId")]
-    part_id: String,
-    #[serde(rename = "mimeType")]
+    #[serde(rename = "mimeType", default)]
     mime_type: String,
+    #[serde(default)]
     filename: String,
+    #[serde(default)]
     headers: Vec<MessageHeader>,
-    // body: MessagePartBody,
-    // parts: Vec<MessagePart>,
+    #[serde(default)]
+    body: MessagePartBody,
+    #[serde(default)]
+    parts: Vec<MessagePart>,
+}
+
+impl MessagePart {
+    /// Parts anywhere under this one that are files
+    fn attachments(&self) -> Vec<Attachment> {
+        let mut attachments = vec![];
+        if let (false, Some(attachment_id)) = (self.filename.is_empty(), &self.body.attachment_id) {
+            attachments.push(Attachment {
+                filename: self.filename.clone(),
+                mime_type: self.mime_type.clone(),
+                attachment_id: attachment_id.clone(),
+            });
+        }
+        for part in &self.parts {
+            attachments.extend(part.attachments());
+        }
+        attachments
+    }
+
+    /// The plain text parts, or failing that the HTML ones with their tags
+    /// stripped. None without a full format fetch, which leaves out bodies.
+    fn text(&self) -> Option<String> {
+        let plain = self.decoded_parts("text/plain");
+        if !plain.is_empty() {
+            return Some(plain.join("\n"));
+        }
+
+        let html = self.decoded_parts("text/html");
+        if !html.is_empty() {
+            let tags = Regex::new("<[^>]*>").unwrap();
+            return Some(tags.replace_all(&html.join("\n"), " ").into_owned());
+        }
+
+        None
+    }
+
+    fn decoded_parts(&self, mime_type: &str) -> Vec<String> {
+        let mut decoded = vec![];
+        if self.mime_type == mime_type && self.filename.is_empty() {
+            if let Some(data) = &self.body.data {
+                if let Ok(bytes) = URL_SAFE_INDIFFERENT.decode(data) {
+                    decoded.push(String::from_utf8_lossy(&bytes).into_owned());
+                }
+            }
+        }
+        for part in &self.parts {
+            decoded.extend(part.decoded_parts(mime_type));
+        }
+        decoded
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MessageHeader {
-    name: String,
-    value: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// Headers that UsableMessageDetails is built from
+pub const SELECTED_HEADERS: [&str; 3] = ["From", "To", "Subject"];
+
+impl MessageDetails {
+    pub fn selected_headers(&self) -> impl Iterator<Item = &MessageHeader> {
+        self.payload
+            .headers
+            .iter()
+            .filter(|header| SELECTED_HEADERS.contains(&header.name.as_str()))
+    }
 }
 
-// #[derive(Debug, Deserialize)]
-// struct MessagePartBody {
-//     size: u64,
-//     data: String,
-//     #[serde(rename = "attachmentId")]
-//     attachment_id: String,
-// }
+#[derive(Debug, Default, Deserialize)]
+struct MessagePartBody {
+    data: Option<String>,
+    #[serde(rename = "attachmentId")]
+    attachment_id: Option<String>,
+}
 
 #[derive(Debug, Deserialize)]
 pub struct MessageAdded {
     message: MinimalMessage,
 }
 
+/// Labels put on a message after it arrived, e.g. starring it
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabelsAdded {
+    pub message: MinimalMessage,
+    #[serde(rename = "labelIds")]
+    pub label_ids: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct History {
     id: String,
     #[serde(rename = "messagesAdded")]
     messages_added: Option<Vec<MessageAdded>>,
+    #[serde(rename = "labelsAdded")]
+    labels_added: Option<Vec<LabelsAdded>>,
+    /// Same shape as `messagesAdded`
+    #[serde(rename = "messagesDeleted")]
+    messages_deleted: Option<Vec<MessageAdded>>,
+}
+
+/// What changed in a mailbox since a history ID, oldest first
+#[derive(Debug, Default)]
+pub struct HistoryChanges {
+    pub messages_added: Vec<MinimalMessage>,
+    /// Message ID to the ID of the history record that added it. A message's
+    /// own `historyId` moves on with every later change to it, past records
+    /// of other messages that came after it.
+    pub added_in: HashMap<String, String>,
+    pub labels_added: Vec<LabelsAdded>,
+    /// Messages deleted for good, bypassing or emptied out of the trash
+    pub messages_deleted: u64,
+    /// The mailbox's history ID as of the listing, or that of the last change
+    /// listed if it was cut short; either way past all of the changes above
+    pub history_id: Option<String>,
+    /// The listing was cut short with pages left, which a listing from
+    /// `history_id` picks up
+    pub more: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -220,214 +454,655 @@ pub struct HistoryResponse {
     history_id: String,
 }
 
+/// Labels counted from a new message's own labels, so one put on a message
+/// listed as added in the same history listing would be counted twice
+pub const COUNTED_ON_ARRIVAL: &[&str] = &["SPAM", "TRASH"];
+
+pub const GMAIL_API: &str = "https://gmail.googleapis.com/gmail/v1/users/me";
+
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    #[serde(rename = "emailAddress")]
+    pub email_address: String,
+    #[serde(rename = "messagesTotal")]
+    pub messages_total: u64,
+    #[serde(rename = "threadsTotal")]
+    pub threads_total: u64,
+    #[serde(rename = "historyId")]
+    pub history_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Label {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub label_type: Option<String>,
+    #[serde(rename = "messagesTotal", skip_serializing_if = "Option::is_none")]
+    pub messages_total: Option<u64>,
+    #[serde(rename = "messagesUnread", skip_serializing_if = "Option::is_none")]
+    pub messages_unread: Option<u64>,
+}
+
+/// The parts of the vacation responder settings that are exposed
+#[derive(Debug, Deserialize)]
+pub struct VacationSettings {
+    #[serde(rename = "enableAutoReply", default)]
+    pub enable_auto_reply: bool,
+}
+
+/// An address the mailbox can send as, including its own
+#[derive(Debug, Deserialize)]
+pub struct SendAs {
+    #[serde(rename = "sendAsEmail")]
+    pub send_as_email: String,
+    /// The mailbox's own address rather than an alias
+    #[serde(rename = "isPrimary", default)]
+    pub is_primary: bool,
+}
+
+/// An address mail can be forwarded to, whether or not it's been verified
+#[derive(Debug, Deserialize)]
+pub struct ForwardingAddress {
+    #[serde(rename = "forwardingEmail")]
+    pub forwarding_email: String,
+}
+
+/// A Gmail filter; what it matches and what it does are kept as JSON, since
+/// they're only ever fingerprinted
+#[derive(Debug, Deserialize)]
+pub struct Filter {
+    pub id: String,
+    #[serde(default)]
+    pub criteria: Value,
+    #[serde(default)]
+    pub action: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchResponse {
+    #[serde(rename = "historyId")]
+    pub history_id: String,
+    /// Milliseconds since the epoch
+    pub expiration: String,
+}
+
+/// Parse a response, or the API error it holds instead
+fn parse<T: serde::de::DeserializeOwned>(json: Value, what: &'static str) -> Result<T> {
+    if let Some(err) = Error::from_api(&json) {
+        return Err(err);
+    }
+    serde_json::from_value(json.clone()).map_err(|_| Error::UnexpectedResponse { what, body: json })
+}
+
+/// Gmail's base64url, which may or may not be padded
+const URL_SAFE_INDIFFERENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Rate-limited requests and backend errors are retried this many times
+/// before giving up
+const MAX_TRANSIENT_RETRIES: u32 = 5;
+
+/// How much of messages.list and history.list to ask for at a time
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct PagingOptions {
+    /// Results per page of messages.list and history.list, up to 500, rather
+    /// than Gmail's default of 100
+    #[arg(
+        long,
+        env = "PAGE_SIZE",
+        global = true,
+        value_parser = clap::value_parser!(u32).range(1..=500)
+    )]
+    pub page_size: Option<u32>,
+
+    /// Stop following a listing after this many pages. A poll that stops
+    /// early picks up the rest of the history at the next one; searches are
+    /// cut short. `export` and `sync` always list everything.
+    #[arg(
+        long,
+        env = "MAX_PAGES",
+        global = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_pages: Option<u32>,
+}
+
+impl PagingOptions {
+    /// The `maxResults` query parameter, if a page size was given
+    fn page_size_part(&self) -> String {
+        match self.page_size {
+            Some(page_size) => format!("&maxResults={}", page_size),
+            None => "".to_string(),
+        }
+    }
+
+    /// Whether `pages` pages are all that may be fetched
+    fn last_page(&self, pages: u32) -> bool {
+        self.max_pages.is_some_and(|max_pages| pages >= max_pages)
+    }
+}
+
+/// A Gmail API client for one mailbox
 pub struct MailClient {
     pub google_client: GoogleAuth,
+    api_base: String,
+    retry_backoff: Duration,
+    /// Fetch messages with their bodies and attachment parts, not just headers
+    full_format: bool,
+    paging: PagingOptions,
 }
 
 impl MailClient {
-    pub async fn test_auth(&mut self) -> bool {
-        let client = reqwest::Client::new();
+    pub fn new(google_client: GoogleAuth) -> Self {
+        Self {
+            google_client,
+            api_base: GMAIL_API.to_owned(),
+            retry_backoff: Duration::from_secs(1),
+            full_format: false,
+            paging: PagingOptions::default(),
+        }
+    }
 
-        let res = client
-            .get("https://www.googleapis.com/gmail/v1/users/me/profile")
-            .header(
-                "Authorization",
-                format!(
-                    "Bearer {}",
-                    self.google_client.access_token.as_ref().unwrap()
-                ),
-            )
-            .send()
-            .await
-            .unwrap();
+    /// Fetch whole messages rather than only their headers, so that
+    /// `UsableMessageDetails` has their `body` and `attachments`
+    pub fn with_full_format(mut self, full_format: bool) -> Self {
+        self.full_format = full_format;
+        self
+    }
 
-        let json: Value = res.json().await.unwrap();
+    pub fn with_paging(mut self, paging: PagingOptions) -> Self {
+        self.paging = paging;
+        self
+    }
 
-        !json["error"].is_object()
+    /// Whether `pages` pages of a listing are all --max-pages allows
+    pub(crate) fn last_page(&self, pages: u32) -> bool {
+        self.paging.last_page(pages)
     }
 
-    pub async fn load_labels(&mut self) -> HashMap<String, String> {
-        let client = reqwest::Client::new();
+    /// `--max-pages`, for listings that page themselves
+    pub(crate) fn max_pages(&self) -> Option<u32> {
+        self.paging.max_pages
+    }
 
-        let res = loop {
-            let res = client
-                .get("https://www.googleapis.com/gmail/v1/users/me/labels")
+    /// Send requests somewhere other than `GMAIL_API`, e.g. a mock server
+    pub fn with_api_base(mut self, api_base: impl Into<String>) -> Self {
+        self.api_base = api_base.into();
+        self
+    }
+
+    /// How long to wait before the first retry of a rate-limited request;
+    /// doubled for each retry after that
+    pub fn with_retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    async fn get_json(&mut self, path: &str) -> Result<Value> {
+        self.send_json(reqwest::Method::GET, path, None).await
+    }
+
+    async fn post_json(&mut self, path: &str, body: &Value) -> Result<Value> {
+        self.send_json(reqwest::Method::POST, path, Some(body))
+            .await
+    }
+
+    /// Call a Gmail API path (relative to `users/me`), refreshing the access
+    /// token and retrying once if it has expired, and backing off and
+    /// retrying if rate limited or on a backend error.
+    async fn send_json(
+        &mut self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Value> {
+        let client = http_trace::client();
+        let mut refreshed = false;
+        let mut retries = 0;
+
+        loop {
+            let mut request = client
+                .request(method.clone(), format!("{}{}", self.api_base, path))
                 .header(
                     "Authorization",
                     format!(
                         "Bearer {}",
-                        self.google_client.access_token.as_ref().unwrap()
+                        self.google_client
+                            .access_token
+                            .as_deref()
+                            .unwrap_or_default()
                     ),
-                )
-                .send()
-                .await
-                .unwrap();
+                );
+            if let Some(body) = body {
+                request = request.json(body);
+            }
 
-            let json: Value = res.json().await.unwrap();
+            let json = http_trace::try_send_json(request).await?;
+            let reason = ApiErrorReason::count(&json, self.google_client.account.as_deref());
 
-            if GoogleAuth::needs_refresh(&json).await {
-                self.google_client.do_refresh().await;
+            if GoogleAuth::needs_refresh(&json).await && !refreshed {
+                self.google_client.do_refresh().await?;
+                refreshed = true;
+            } else if let Some(reason) =
+                reason.filter(|reason| reason.is_transient() && retries < MAX_TRANSIENT_RETRIES)
+            {
+                let backoff = self.retry_backoff * 2u32.pow(retries);
+                warn!(
+                    "Gmail answered {}, retrying {} in {:?}",
+                    reason.label(),
+                    path,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                retries += 1;
             } else {
-                break json;
+                break Ok(json);
             }
-        };
+        }
+    }
 
-        let mut labels = HashMap::new();
+    /// Whether the current access token works, without refreshing it
+    #[instrument(skip_all)]
+    pub async fn test_auth(&mut self) -> Result<bool> {
+        let client = http_trace::client();
 
-        for label in res["labels"].as_array().unwrap() {
-            labels.insert(
-                label["id"].as_str().unwrap().to_owned(),
-                label["name"].as_str().unwrap().to_owned(),
-            );
+        let json =
+            http_trace::try_send_json(client.get(format!("{}/profile", self.api_base)).header(
+                "Authorization",
+                format!(
+                "Bearer {}",
+                self.google_client.access_token.as_deref().unwrap_or_default()
+            ),
+            ))
+            .await?;
+
+        Ok(!json["error"].is_object())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn fetch_profile(&mut self) -> Result<Profile> {
+        let res = self.get_json("/profile").await?;
+
+        parse(res, "users/me/profile to return a profile")
+    }
+
+    /// Label ID to name, for `UsableMessageDetails::from`
+    #[instrument(skip_all)]
+    pub async fn load_labels(&mut self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .list_labels()
+            .await?
+            .into_iter()
+            .map(|label| (label.id, label.name))
+            .collect())
+    }
+
+    /// labels.list, which doesn't include message counts
+    #[instrument(skip_all)]
+    pub async fn list_labels(&mut self) -> Result<Vec<Label>> {
+        #[derive(Deserialize)]
+        struct LabelsList {
+            labels: Vec<Label>,
         }
 
-        labels
+        let res = self.get_json("/labels").await?;
+
+        Ok(parse::<LabelsList>(res, "labels.list to return labels")?.labels)
     }
 
-    pub async fn fetch_mail(&mut self) -> Vec<MinimalMessage> {
-        let client = reqwest::Client::new();
+    /// labels.get, which includes message counts
+    #[instrument(skip(self))]
+    pub async fn get_label(&mut self, id: &str) -> Result<Label> {
+        let res = self.get_json(&format!("/labels/{}", id)).await?;
 
-        let res = loop {
-            let res = client
-                .get("https://www.googleapis.com/gmail/v1/users/me/messages")
-                .header(
-                    "Authorization",
-                    format!(
-                        "Bearer {}",
-                        self.google_client.access_token.as_ref().unwrap()
-                    ),
-                )
-                .send()
-                .await
-                .unwrap();
+        parse(res, "labels.get to return a label")
+    }
 
-            let json: Value = res.json().await.unwrap();
+    /// settings.getVacation
+    #[instrument(skip_all)]
+    pub async fn get_vacation(&mut self) -> Result<VacationSettings> {
+        let res = self.get_json("/settings/vacation").await?;
 
-            if GoogleAuth::needs_refresh(&json).await {
-                self.google_client.do_refresh().await;
-            } else {
-                break json;
+        parse(res, "settings.getVacation to return vacation settings")
+    }
+
+    /// settings.sendAs.list
+    #[instrument(skip_all)]
+    pub async fn list_send_as(&mut self) -> Result<Vec<SendAs>> {
+        #[derive(Deserialize)]
+        struct SendAsList {
+            #[serde(rename = "sendAs", default)]
+            send_as: Vec<SendAs>,
+        }
+
+        let res = self.get_json("/settings/sendAs").await?;
+
+        Ok(parse::<SendAsList>(res, "settings.sendAs.list to return addresses")?.send_as)
+    }
+
+    /// settings.forwardingAddresses.list
+    #[instrument(skip_all)]
+    pub async fn list_forwarding_addresses(&mut self) -> Result<Vec<ForwardingAddress>> {
+        #[derive(Deserialize)]
+        struct ForwardingAddressesList {
+            #[serde(rename = "forwardingAddresses", default)]
+            forwarding_addresses: Vec<ForwardingAddress>,
+        }
+
+        let res = self.get_json("/settings/forwardingAddresses").await?;
+
+        Ok(parse::<ForwardingAddressesList>(
+            res,
+            "settings.forwardingAddresses.list to return addresses",
+        )?
+        .forwarding_addresses)
+    }
+
+    /// settings.filters.list
+    #[instrument(skip_all)]
+    pub async fn list_filters(&mut self) -> Result<Vec<Filter>> {
+        #[derive(Deserialize)]
+        struct FiltersList {
+            // Left out entirely when there are no filters
+            #[serde(default)]
+            filter: Vec<Filter>,
+        }
+
+        let res = self.get_json("/settings/filters").await?;
+
+        Ok(parse::<FiltersList>(res, "settings.filters.list to return filters")?.filter)
+    }
+
+    /// users.watch: have Gmail publish mailbox changes to a Pub/Sub topic
+    #[instrument(skip(self))]
+    pub async fn watch(&mut self, topic_name: &str) -> Result<WatchResponse> {
+        let res = self
+            .post_json("/watch", &serde_json::json!({ "topicName": topic_name }))
+            .await?;
+
+        parse(res, "users.watch to succeed")
+    }
+
+    /// IDs of every message matching a Gmail search query
+    pub async fn search_message_ids(&mut self, query: &str) -> Result<HashSet<String>> {
+        Ok(self
+            .search_messages(query)
+            .await?
+            .into_iter()
+            .map(|message| message.id)
+            .collect())
+    }
+
+    /// Every message matching a Gmail search query, newest first
+    pub async fn search_messages(&mut self, query: &str) -> Result<Vec<MinimalMessage>> {
+        let (messages, complete) = self
+            .search_message_pages(query, self.paging.max_pages)
+            .await?;
+        if !complete {
+            warn!(
+                "Search for {:?} stopped after {} pages (--max-pages)",
+                query,
+                self.paging.max_pages.unwrap_or_default()
+            );
+        }
+        Ok(messages)
+    }
+
+    /// Like `search_messages`, but stops after `max_pages` pages rather than
+    /// `--max-pages`, and says whether it got to the end of the listing
+    #[instrument(skip(self))]
+    pub async fn search_message_pages(
+        &mut self,
+        query: &str,
+        max_pages: Option<u32>,
+    ) -> Result<(Vec<MinimalMessage>, bool)> {
+        let mut messages = vec![];
+        let mut page_token: Option<String> = None;
+        let mut pages = 0;
+
+        loop {
+            let page_token_part = match &page_token {
+                Some(page_token) => format!("&pageToken={}", page_token),
+                None => "".to_string(),
+            };
+
+            let res = self
+                .get_json(&format!(
+                    "/messages?q={}{}{}",
+                    url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>(),
+                    self.paging.page_size_part(),
+                    page_token_part
+                ))
+                .await?;
+            pages += 1;
+
+            // `messages` is left out entirely when nothing matches
+            let list: MessagesList = parse(res, "messages.list to return a listing")?;
+            messages.extend(list.messages);
+
+            match list.next_page_token {
+                Some(_) if max_pages.is_some_and(|max_pages| pages >= max_pages) => {
+                    return Ok((messages, false))
+                }
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => return Ok((messages, true)),
             }
-        };
+        }
+    }
 
-        serde_json::from_value::<MessagesList>(res)
-            .unwrap()
-            .messages
+    /// The first page of messages.list
+    #[instrument(skip_all)]
+    pub async fn fetch_mail(&mut self) -> Result<Vec<MinimalMessage>> {
+        let res = self.get_json("/messages").await?;
+
+        Ok(parse::<MessagesList>(res, "messages.list to return a listing")?.messages)
     }
 
+    /// Skips messages that no longer exist
+    #[instrument(skip_all, fields(messages = listing.len()))]
     pub async fn fetch_mail_details(
         &mut self,
         listing: Vec<MinimalMessage>,
         labels: &HashMap<String, String>,
-    ) -> Vec<UsableMessageDetails> {
+    ) -> Result<Vec<UsableMessageDetails>> {
         let mut results = vec![];
-        let client = reqwest::Client::new();
 
         for message in listing {
-            let res = loop {
-                let res = client
-                    .get(&format!(
-                        "https://www.googleapis.com/gmail/v1/users/me/messages/{}",
-                        message.id
-                    ))
-                    .header(
-                        "Authorization",
-                        format!(
-                            "Bearer {}",
-                            self.google_client.access_token.as_ref().unwrap()
-                        ),
-                    )
-                    .send()
-                    .await
-                    .unwrap();
-
-                let json: Value = res.json().await.unwrap();
-
-                if GoogleAuth::needs_refresh(&json).await {
-                    self.google_client.do_refresh().await;
-                } else {
-                    break json;
-                }
+            let Some(json) = self.fetch_message(&message.id).await? else {
+                continue;
             };
+            let usable = UsableMessageDetails::from(json, labels)?;
 
-            if res["error"]["code"] == 404 {
-                continue;
-            }
+            results.push(usable);
+        }
+
+        Ok(results)
+    }
 
-            let json: MessageDetails = serde_json::from_value(res).unwrap();
-            let usable = UsableMessageDetails::from(json, &labels);
+    /// Returns None if the message no longer exists
+    #[instrument(skip(self))]
+    pub async fn fetch_message(&mut self, id: &str) -> Result<Option<MessageDetails>> {
+        let format = if self.full_format { "full" } else { "metadata" };
+        let res = self
+            .get_json(&format!("/messages/{}?format={}", id, format))
+            .await?;
 
-            results.push(usable);
+        if res["error"]["code"] == 404 {
+            return Ok(None);
         }
 
-        results
+        parse(res, "messages.get to return a message").map(Some)
     }
 
-    pub async fn fetch_history(&mut self, starting_from: &str) -> Vec<MinimalMessage> {
-        let client = reqwest::Client::new();
-        let mut history_list: Vec<MinimalMessage> = vec![];
+    /// When a message was received, from messages.get's minimal format;
+    /// None if the message no longer exists
+    #[instrument(skip(self))]
+    pub async fn fetch_internal_date(
+        &mut self,
+        id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        #[derive(Deserialize)]
+        struct MinimalDetails {
+            #[serde(rename = "internalDate")]
+            internal_date: String,
+        }
+
+        let res = self
+            .get_json(&format!("/messages/{}?format=minimal", id))
+            .await?;
+
+        if res["error"]["code"] == 404 {
+            return Ok(None);
+        }
+
+        let message: MinimalDetails = parse(res, "messages.get to return a message")?;
+        parse_internal_date(&message.internal_date).map(Some)
+    }
+
+    /// messages.attachments.get, decoded
+    #[instrument(skip(self))]
+    pub async fn fetch_attachment(
+        &mut self,
+        message_id: &str,
+        attachment_id: &str,
+    ) -> Result<Vec<u8>> {
+        #[derive(Deserialize)]
+        struct AttachmentBody {
+            data: String,
+        }
+
+        let res = self
+            .get_json(&format!(
+                "/messages/{}/attachments/{}",
+                message_id, attachment_id
+            ))
+            .await?;
+        let body: AttachmentBody = parse(res, "messages.attachments.get to return data")?;
+
+        URL_SAFE_INDIFFERENT
+            .decode(&body.data)
+            .map_err(|_| Error::UnexpectedResponse {
+                what: "attachment data to be base64url",
+                body: Value::String(body.data),
+            })
+    }
+
+    /// Returns None if `starting_from` is too old for Gmail to still have history for it
+    pub async fn fetch_history(
+        &mut self,
+        starting_from: &str,
+    ) -> Result<Option<Vec<MinimalMessage>>> {
+        Ok(self
+            .fetch_history_changes(starting_from)
+            .await?
+            .map(|changes| changes.messages_added))
+    }
+
+    /// Like `fetch_history`, but also with the labels added to messages
+    pub async fn fetch_history_changes(
+        &mut self,
+        starting_from: &str,
+    ) -> Result<Option<HistoryChanges>> {
+        let changes = self
+            .fetch_history_pages(starting_from, self.paging.max_pages)
+            .await?;
+        if let Some(changes) = changes.as_ref().filter(|changes| changes.more) {
+            warn!(
+                "History listing stopped at {:?} (--max-pages), continuing next poll",
+                changes.history_id
+            );
+        }
+        Ok(changes)
+    }
+
+    /// Like `fetch_history_changes`, but stops after `max_pages` pages, so
+    /// a long history can be worked through a page or a few at a time
+    #[instrument(skip(self))]
+    pub async fn fetch_history_pages(
+        &mut self,
+        starting_from: &str,
+        max_pages: Option<u32>,
+    ) -> Result<Option<HistoryChanges>> {
+        let mut changes = HistoryChanges::default();
         let mut page_token: Option<String> = None;
+        let mut pages = 0;
+        let mut last_listed = None;
 
         loop {
-            let res = loop {
-                let page_token_part = if page_token.is_none() {
-                    "".to_string()
-                } else {
-                    format!("&pageToken={}", page_token.as_ref().unwrap())
-                };
-
-                let res = client
-                    .get(format!(
-                        "https://gmail.googleapis.com/gmail/v1/users/me/history?startHistoryId={}{}",
-                        starting_from,
-                        page_token_part
-                    ))
-                    .header(
-                        "Authorization",
-                        format!(
-                            "Bearer {}",
-                            self.google_client.access_token.as_ref().unwrap()
-                        ),
-                    )
-                    .send()
-                    .await
-                    .unwrap();
-
-                let json: Value = res.json().await.unwrap();
-
-                if GoogleAuth::needs_refresh(&json).await {
-                    self.google_client.do_refresh().await;
-                } else {
-                    break json;
-                }
+            let page_token_part = match &page_token {
+                Some(page_token) => format!("&pageToken={}", page_token),
+                None => "".to_string(),
             };
 
-            let history = match serde_json::from_value::<HistoryResponse>(res.clone()) {
-                Ok(h) => h,
-                Err(_) => {
-                    println!("Failed to parse HistoryResponse out of response: {:?}", res);
-                    println!("starting_from is probably no longer valid");
-                    panic!();
-                }
+            let res = self
+                .get_json(&format!(
+                    "/history?startHistoryId={}{}{}",
+                    starting_from,
+                    self.paging.page_size_part(),
+                    page_token_part
+                ))
+                .await?;
+            pages += 1;
+
+            if res["error"]["code"] == 404 {
+                return Ok(None);
+            }
+
+            let history: HistoryResponse = parse(res, "users.history.list to return history")?;
+            if let Some(last) = history.history.iter().flatten().last() {
+                last_listed = Some(last.id.clone());
+            }
+            // Only once something was listed, so the next listing gets further
+            let truncated = history.next_page_token.is_some()
+                && last_listed.is_some()
+                && max_pages.is_some_and(|max_pages| pages >= max_pages);
+            changes.history_id = match truncated {
+                // Resume after the last change listed rather than skip the rest
+                true => last_listed.clone(),
+                false => Some(history.history_id),
             };
 
             if let Some(history) = history.history {
                 history.into_iter().for_each(|h| {
                     if let Some(messages_added) = h.messages_added {
                         messages_added.into_iter().for_each(|m| {
-                            history_list.push(m.message);
+                            changes.added_in.insert(m.message.id.clone(), h.id.clone());
+                            changes.messages_added.push(m.message);
                         });
                     }
+                    if let Some(labels_added) = h.labels_added {
+                        changes.labels_added.extend(labels_added);
+                    }
+                    if let Some(messages_deleted) = h.messages_deleted {
+                        changes.messages_deleted += messages_deleted.len() as u64;
+                    }
                 });
             }
 
-            if history.next_page_token.is_none() {
+            if truncated {
+                changes.more = true;
+                break;
+            } else if history.next_page_token.is_none() {
                 break;
             } else {
                 page_token = history.next_page_token;
             }
         }
 
-        history_list
+        for labels_added in &mut changes.labels_added {
+            if changes.added_in.contains_key(&labels_added.message.id) {
+                labels_added
+                    .label_ids
+                    .retain(|label| !COUNTED_ON_ARRIVAL.contains(&label.as_str()));
+            }
+        }
+        changes
+            .labels_added
+            .retain(|labels_added| !labels_added.label_ids.is_empty());
+
+        Ok(Some(changes))
     }
 }
@@ -0,0 +1,226 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mail::{ParseForMetrics, UsableMessageDetails},
+    notify::{Notifier, NotifyConfig},
+    senders::NewSender,
+};
+
+/// A `[[rules]]` entry in the config: count matching messages in a counter of
+/// our own, e.g. `ci_failure_emails_total{repo="..."}`, and/or send them to a
+/// webhook.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    /// Identifies the rule in logs and notifications; defaults to `metric`
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub metric: Option<String>,
+    #[serde(default)]
+    pub help: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matches: MatchConfig,
+    /// Label name to value. `${name}` in a value is replaced with the named
+    /// capture group of that name from the match regexes.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub notify: Option<NotifyConfig>,
+}
+
+/// Every condition given has to match
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Regex over the sender's address
+    pub from: Option<String>,
+    /// Regex over the first recipient's address
+    pub to: Option<String>,
+    pub subject: Option<String>,
+    /// Gmail label names the message must all have
+    pub labels: Vec<String>,
+    /// Inbox tab, e.g. `updates` (see `email_received_by_category_total`)
+    pub category: Option<String>,
+    /// Only the first message from a sender; needs --new-senders
+    pub new_sender: Option<NewSenderMatch>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NewSenderMatch {
+    /// The first message from the address
+    Address,
+    /// The first message from anyone at the registrable domain
+    Domain,
+}
+
+/// A rule with its regexes compiled, ready to evaluate against messages
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+    pub name: String,
+    pub metric: Option<String>,
+    pub help: Option<String>,
+    pub notify: Option<Arc<Notifier>>,
+    from: Option<Regex>,
+    to: Option<Regex>,
+    subject: Option<Regex>,
+    labels: Vec<String>,
+    category: Option<String>,
+    new_sender: Option<NewSenderMatch>,
+    label_templates: Vec<(String, String)>,
+}
+
+pub fn compile_all(rules: &[RuleConfig]) -> Result<Vec<CompiledRule>, String> {
+    rules.iter().map(CompiledRule::compile).collect()
+}
+
+fn compile_regex(
+    rule: &RuleConfig,
+    field: &str,
+    pattern: &Option<String>,
+) -> Result<Option<Regex>, String> {
+    pattern
+        .as_ref()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|err| {
+                format!(
+                    "Invalid {} regex in rule {}: {}",
+                    field,
+                    rule_name(rule),
+                    err
+                )
+            })
+        })
+        .transpose()
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn rule_name(rule: &RuleConfig) -> &str {
+    rule.name
+        .as_deref()
+        .or(rule.metric.as_deref())
+        .unwrap_or("<unnamed>")
+}
+
+impl CompiledRule {
+    fn compile(rule: &RuleConfig) -> Result<Self, String> {
+        let name = rule
+            .name
+            .clone()
+            .or_else(|| rule.metric.clone())
+            .ok_or("Every rule needs a name or a metric")?;
+        if rule.metric.is_none() && rule.notify.is_none() {
+            return Err(format!("Rule {} has neither a metric nor notify", name));
+        }
+        if let Some(metric) = rule.metric.as_ref().filter(|metric| !is_valid_name(metric)) {
+            return Err(format!("Invalid metric name in rule: {:?}", metric));
+        }
+        if let Some(label) = rule.labels.keys().find(|label| !is_valid_name(label)) {
+            return Err(format!("Invalid label name {:?} in rule {}", label, name));
+        }
+
+        Ok(Self {
+            name,
+            metric: rule.metric.clone(),
+            help: rule.help.clone(),
+            notify: rule
+                .notify
+                .clone()
+                .map(|notify| Arc::new(Notifier::new(notify))),
+            from: compile_regex(rule, "from", &rule.matches.from)?,
+            to: compile_regex(rule, "to", &rule.matches.to)?,
+            subject: compile_regex(rule, "subject", &rule.matches.subject)?,
+            labels: rule.matches.labels.clone(),
+            category: rule.matches.category.clone(),
+            new_sender: rule.matches.new_sender,
+            label_templates: rule
+                .labels
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        })
+    }
+
+    /// The labels to count `message` under, or None if the rule doesn't match it
+    pub fn evaluate(
+        &self,
+        message: &UsableMessageDetails,
+        new_sender: NewSender,
+    ) -> Option<Vec<(String, String)>> {
+        if !self
+            .labels
+            .iter()
+            .all(|label| message.labels.contains(label))
+        {
+            return None;
+        }
+        if self.category.is_some() && self.category.as_deref() != message.category() {
+            return None;
+        }
+        match self.new_sender {
+            Some(NewSenderMatch::Address) if !new_sender.address => return None,
+            Some(NewSenderMatch::Domain) if !new_sender.domain => return None,
+            _ => {}
+        }
+
+        let mut captures = HashMap::new();
+        let fields = [
+            (&self.from, message.from.first_address().unwrap_or_default()),
+            (&self.to, message.to.first_address().unwrap_or_default()),
+            (&self.subject, message.subject.clone()),
+        ];
+        for (regex, value) in fields {
+            let Some(regex) = regex else {
+                continue;
+            };
+            let matched = regex.captures(&value)?;
+
+            for name in regex.capture_names().flatten() {
+                if let Some(capture) = matched.name(name) {
+                    captures.insert(name.to_owned(), capture.as_str().to_owned());
+                }
+            }
+        }
+
+        Some(
+            self.label_templates
+                .iter()
+                .map(|(name, template)| (name.clone(), expand(template, &captures)))
+                .collect(),
+        )
+    }
+}
+
+/// Replace `${name}` with the capture of that name, or nothing if it didn't capture
+fn expand(template: &str, captures: &HashMap<String, String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        if let Some(capture) = captures.get(&rest[start + 2..start + end]) {
+            expanded.push_str(capture);
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
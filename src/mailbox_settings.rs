@@ -0,0 +1,68 @@
+use std::sync::Mutex;
+
+use crate::exposition::{self, Collector};
+
+/// Account settings worth watching, since changing them is how a
+/// compromised account is usually put to use
+#[derive(Debug, Default)]
+pub struct MailboxSettings {
+    pub vacation_responder: bool,
+    /// Send-as addresses besides the mailbox's own
+    pub send_as_aliases: u64,
+    /// Addresses mail may be forwarded to
+    pub forwarding_addresses: Vec<String>,
+    pub filters: Vec<FilterFingerprint>,
+}
+
+/// A Gmail filter, identified without exposing what it matches or does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterFingerprint {
+    pub id: String,
+    pub criteria_hash: String,
+    pub action_hash: String,
+}
+
+/// The filters from the last settings check, as `gmail_filter_info` series
+/// that disappear again when a filter is deleted
+pub struct FilterInventory {
+    base_labels: Vec<(String, String)>,
+    filters: Mutex<Vec<FilterFingerprint>>,
+}
+
+impl FilterInventory {
+    pub fn new(base_labels: Vec<(String, String)>) -> Self {
+        Self {
+            base_labels,
+            filters: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn set(&self, filters: Vec<FilterFingerprint>) {
+        *self.filters.lock().unwrap() = filters;
+    }
+}
+
+impl Collector for FilterInventory {
+    fn header(&self) -> String {
+        "# HELP gmail_filter_info Always 1, for each Gmail filter, labeled with short hashes of its criteria and its action.\n# TYPE gmail_filter_info gauge\n".to_owned()
+    }
+
+    fn render_series(&self) -> String {
+        let mut output = String::new();
+
+        for filter in self.filters.lock().unwrap().iter() {
+            let mut labels = self.base_labels.clone();
+            labels.extend([
+                ("filter_id".to_owned(), filter.id.clone()),
+                ("criteria_hash".to_owned(), filter.criteria_hash.clone()),
+                ("action_hash".to_owned(), filter.action_hash.clone()),
+            ]);
+            output.push_str(&format!(
+                "{} 1\n",
+                exposition::format_series("gmail_filter_info", &labels)
+            ));
+        }
+
+        output
+    }
+}
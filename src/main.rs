@@ -1,18 +1,103 @@
-use crate::auth::GoogleAuth;
-mod auth;
-mod mail;
+use axum::Router;
 use chrono::Duration;
-use clap::{Parser, Subcommand};
-use metrics::{counter, describe_counter};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use gmail_prom_exporter_rs::api::{ApiOptions, EventStream, MessageSource, RecentMessages};
+use gmail_prom_exporter_rs::auth::GoogleAuth;
+use gmail_prom_exporter_rs::backend::{GmailBackend, MailBackend};
+use gmail_prom_exporter_rs::clickhouse::ClickHouseOptions;
+use gmail_prom_exporter_rs::config::{Config, MailProvider};
+use gmail_prom_exporter_rs::duplicates::DuplicateDetector;
+use gmail_prom_exporter_rs::error::Error;
+use gmail_prom_exporter_rs::events::{DryRunSink, EventSink};
+use gmail_prom_exporter_rs::export::ExportOptions;
+use gmail_prom_exporter_rs::graph::{GraphAuth, GraphAuthOptions, GraphBackend, GraphOptions};
+use gmail_prom_exporter_rs::hashing::AddressHasher;
+#[cfg(feature = "imap")]
+use gmail_prom_exporter_rs::imap::{ImapBackend, ImapOptions};
+#[cfg(feature = "kafka")]
+use gmail_prom_exporter_rs::kafka::{self, KafkaOptions};
+use gmail_prom_exporter_rs::language::LanguageLabels;
+use gmail_prom_exporter_rs::logging::LoggingOptions;
+use gmail_prom_exporter_rs::loki::LokiOptions;
+use gmail_prom_exporter_rs::mail::PagingOptions;
+#[cfg(feature = "mqtt")]
+use gmail_prom_exporter_rs::mqtt::{MqttOptions, MqttPublisher};
+#[cfg(feature = "nats")]
+use gmail_prom_exporter_rs::nats::{self, NatsOptions};
+use gmail_prom_exporter_rs::pipeline::{MetricsPipeline, PipelineSettings};
+#[cfg(feature = "postgres")]
+use gmail_prom_exporter_rs::postgres::{self, PostgresOptions};
+use gmail_prom_exporter_rs::pubsub::{PubSubOptions, PubSubWatch};
+use gmail_prom_exporter_rs::replay_ids::IdReplayBackend;
+use gmail_prom_exporter_rs::report::ReportOptions;
+use gmail_prom_exporter_rs::reputation::SenderReputation;
+use gmail_prom_exporter_rs::rules::RulesOptions;
+use gmail_prom_exporter_rs::scopes::{Access, ScopeNeed};
+use gmail_prom_exporter_rs::senders::SenderHistory;
+use gmail_prom_exporter_rs::server::MetricsServerOptions;
+use gmail_prom_exporter_rs::simulate::{SimulateOptions, SimulatedBackend};
+use gmail_prom_exporter_rs::snapshot::{Change, SnapshotDiff, SnapshotOptions};
+use gmail_prom_exporter_rs::state::StateFile;
+use gmail_prom_exporter_rs::store::MessageStore;
+use gmail_prom_exporter_rs::sync::SyncOptions;
+use gmail_prom_exporter_rs::threads::ThreadTracker;
+use gmail_prom_exporter_rs::watch::{
+    Delivery, FailurePolicy, PollSchedule, ScrapeTrigger, Watcher,
+};
+use gmail_prom_exporter_rs::{
+    api, clickhouse, config, export, exposition, graph, http_trace, language, logging, loki, mail,
+    pubsub, replay_ids, report, reputation, rules, scopes, server, snapshot, state, sync, systemd,
+    watch,
+};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use metrics_util::MetricKindMask;
+use std::{path::PathBuf, sync::Arc};
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+/// How long shutdown waits for watchers to finish the batch they're on
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Export Gmail inbox activity as Prometheus metrics
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Every flag can also be set through the environment variable shown in its \
+                  help. A flag given on the command line takes precedence over its \
+                  environment variable, which takes precedence over the built-in default."
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[command(flatten)]
+    logging: LoggingOptions,
+
+    /// Log every HTTP request with its status and latency; response bodies
+    /// (with tokens redacted) are logged too at the trace level
+    #[arg(long, env = "TRACE_HTTP", global = true)]
+    trace_http: bool,
+
+    /// Save every Gmail / Microsoft Graph API response into this directory,
+    /// with tokens redacted, to replay later with --replay
+    #[arg(long, env = "RECORD", global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer API requests from responses saved with --record instead of
+    /// calling the real APIs; no credentials are needed. IMAP isn't covered.
+    #[arg(long, env = "REPLAY", global = true)]
+    replay: Option<PathBuf>,
+
+    /// User-Agent sent with every Gmail API and auth request, for egress
+    /// proxies that only let identified clients through
+    #[arg(long, env = "USER_AGENT", global = true, default_value = http_trace::DEFAULT_USER_AGENT)]
+    user_agent: String,
+
+    #[command(flatten)]
+    paging: PagingOptions,
 }
 #[derive(Subcommand)]
 enum Commands {
@@ -26,23 +111,529 @@ enum Commands {
         // #[arg(long)]
         // end_ts: Option<i64>,
     },
-    WatchInbox {
-        #[arg(long)]
-        starting_from: String,
+    WatchInbox(Box<WatchArgs>),
+    /// Watch generated messages instead of a real mailbox, to check
+    /// cardinality limits and scrape sizes before pointing it at a big one
+    Simulate(Box<SimulateArgs>),
+    /// Print every label's ID, name and type, e.g. to pick IDs for filters
+    ListLabels {
+        /// Also fetch total and unread message counts (one request per label)
+        #[arg(long, env = "COUNTS")]
+        counts: bool,
 
-        #[arg(long)]
-        sleep_interval: u64,
+        #[arg(long, env = "FORMAT", value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
     },
+    /// Print Prometheus alerting rules for the metrics this exporter emits
+    Rules(RulesOptions),
+    /// Show how a single message is parsed and which metric labels it produces
+    InspectMessage {
+        /// Gmail message ID
+        id: String,
+    },
+    /// Run the listed messages through the pipeline as if they had just
+    /// arrived, and print the metrics they produce, to reproduce miscounts
+    ReplayIds(Box<ReplayIdsArgs>),
+    /// Write metadata for every message in a date range to a CSV or Parquet file
+    Export(ExportOptions),
+    /// Print or email a digest of the mail recorded in the --sqlite-path database
+    Report(ReportOptions),
+    /// Save label counts and top senders to --state-file and print how they
+    /// changed since the last snapshot, e.g. from a daily cron job
+    Snapshot {
+        #[command(flatten)]
+        options: SnapshotOptions,
+
+        #[arg(long, env = "FORMAT", value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Record every message from a recent window in a --sqlite-path database,
+    /// so `report` has history from day one, and publish each new one to the
+    /// configured sinks
+    Sync(Box<SyncArgs>),
+    /// Sign in to a Microsoft 365 / Outlook.com mailbox and print a GRAPH_REFRESH_TOKEN
+    GraphLogin(GraphAuthOptions),
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page, or write one page per subcommand into a directory
+    Man {
+        // MAN_ rather than OUT_DIR, which cargo sets for build scripts
+        #[arg(long, env = "MAN_OUT_DIR")]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Args)]
+struct SimulateArgs {
+    #[command(flatten)]
+    simulation: SimulateOptions,
+
+    #[command(flatten)]
+    watch: WatchArgs,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    #[command(flatten)]
+    options: SyncOptions,
+
+    #[command(flatten)]
+    sinks: EventSinkOptions,
+}
+
+#[derive(Args)]
+struct ReplayIdsArgs {
+    /// File of message IDs, one per line; `#` starts a comment
+    ids: PathBuf,
+
+    #[command(flatten)]
+    watch: WatchArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// History ID to start watching from; overrides the one saved in --state-file
+    #[arg(long, env = "STARTING_FROM")]
+    starting_from: Option<String>,
+
+    /// Seconds to wait between polls (the starting interval in adaptive mode)
+    #[arg(
+        long,
+        env = "WATCH_SLEEP_INTERVAL",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    sleep_interval: u64,
+
+    /// URL to GET after every poll that gets through, for a dead-man-switch
+    /// service like healthchecks.io to notice when the exporter stops
+    #[arg(long, env = "HEARTBEAT_URL")]
+    heartbeat_url: Option<String>,
+
+    /// With --state-file, what a crash mid-poll may do: at-least-once saves
+    /// the counters and position together after recording each batch, so
+    /// sinks may see a batch twice; at-most-once saves the position first, so
+    /// the rest of a batch may go uncounted
+    #[arg(long, env = "DELIVERY", value_enum, default_value_t = Delivery::AtLeastOnce)]
+    delivery: Delivery,
+
+    /// What to do once polls have been failing for --failure-budget: keep
+    /// retrying, exit with an error, or stop polling but keep serving /metrics
+    /// (with `gmail_poll_failing` at 1)
+    #[arg(long, env = "ON_FAILURE", value_enum, default_value_t = FailurePolicy::Exit)]
+    on_failure: FailurePolicy,
+
+    /// Seconds polls may keep failing, retrying every interval, before
+    /// --on-failure applies
+    #[arg(long, env = "FAILURE_BUDGET", default_value_t = 0)]
+    failure_budget: u64,
+
+    /// Poll once, print the resulting metrics, save the state file and exit.
+    /// Meant for cron jobs; pair with --state-file so counters and position carry over.
+    #[arg(long, env = "ONCE", conflicts_with_all = ["clickhouse_url", "loki_url"])]
+    #[cfg_attr(feature = "mqtt", arg(conflicts_with = "mqtt_url"))]
+    #[cfg_attr(feature = "kafka", arg(conflicts_with = "kafka_brokers"))]
+    #[cfg_attr(feature = "nats", arg(conflicts_with = "nats_url"))]
+    #[cfg_attr(feature = "postgres", arg(conflicts_with = "postgres_url"))]
+    once: bool,
+
+    /// Process at most this many new messages per poll; the rest carry over to
+    /// following polls, spreading out catch-up after downtime
+    #[arg(long, env = "MAX_MESSAGES_PER_POLL")]
+    max_messages_per_poll: Option<usize>,
+
+    /// Log the labels and metric increments each message would produce, without
+    /// serving metrics or touching the state file
+    #[arg(long, env = "DRY_RUN", conflicts_with = "once")]
+    dry_run: bool,
+
+    /// Poll Gmail when /metrics is scraped instead of on a timer, at most once per
+    /// --sleep-interval
+    #[arg(long, env = "POLL_ON_SCRAPE", conflicts_with_all = ["once", "dry_run", "adaptive_polling"])]
+    poll_on_scrape: bool,
+
+    /// Poll more often after finding mail and back off while idle
+    #[arg(long, env = "ADAPTIVE_POLLING")]
+    adaptive_polling: bool,
+
+    /// Shortest interval (in seconds) adaptive polling will use
+    #[arg(
+        long,
+        env = "MIN_SLEEP_INTERVAL",
+        default_value_t = 15,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    min_sleep_interval: u64,
+
+    /// Longest interval (in seconds) adaptive polling will back off to
+    #[arg(
+        long,
+        env = "MAX_SLEEP_INTERVAL",
+        default_value_t = 600,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_sleep_interval: u64,
+
+    /// Randomly vary each interval by up to this fraction, e.g. 0.1 for ±10%
+    #[arg(
+        long,
+        env = "POLL_JITTER",
+        default_value_t = 0.0,
+        value_parser = watch::parse_jitter
+    )]
+    poll_jitter: f64,
+
+    /// Alias added as an `account` label on every metric, to tell mailboxes apart
+    #[arg(long, env = "ACCOUNT")]
+    account: Option<String>,
+
+    /// Maximum distinct label sets for `email_received`, counting ones restored
+    /// from the state file; beyond it, new ones have their sender, recipient,
+    /// domain, language and time labels recorded as "__overflow__" and their
+    /// `label_*` labels left out
+    #[arg(long, env = "MAX_RECEIVED_SERIES")]
+    max_received_series: Option<usize>,
+
+    /// Expose the N heaviest senders as `gmail_top_sender_messages` gauges
+    #[arg(long, env = "TOP_SENDERS")]
+    top_senders: Option<usize>,
+
+    /// Rolling window (in seconds) the top senders and newsletter senders are computed over
+    #[arg(long, env = "TOP_SENDERS_WINDOW", default_value_t = 86400)]
+    top_senders_window: u64,
+
+    /// Replace the addresses in metric labels (and sender gauges) with short
+    /// hashes salted with the given value, keeping their domains readable.
+    /// The salt is required, since unsalted hashes of guessable addresses
+    /// can be reversed. Labels produced by config rules are left as configured
+    #[arg(
+        long,
+        env = "HASH_ADDRESSES",
+        value_name = "SALT",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    hash_addresses: Option<String>,
+
+    /// With --hash-addresses, hash the domains too
+    #[arg(long, env = "HASH_DOMAINS", requires = "hash_addresses")]
+    hash_domains: bool,
+
+    /// Keep an inventory of the N heaviest senders of mail with a
+    /// `List-Unsubscribe` header, exposed as `gmail_newsletter_sender_messages`
+    /// gauges and on /api/v1/newsletters
+    #[arg(long, env = "NEWSLETTER_SENDERS")]
+    newsletter_senders: Option<usize>,
+
+    /// Learn how much mail usually arrives in each hour of the week (in
+    /// --timezone, kept in the state file) and expose how far the last full
+    /// hour was from it as `gmail_arrival_anomaly_score`
+    #[arg(long, env = "ANOMALY_SCORE")]
+    anomaly_score: bool,
+
+    /// Remember every sender (in the state file) and count the first message
+    /// from each address and domain in `email_new_sender_total`; rules can
+    /// match these with `new_sender`. Every sender is new at first, so expect
+    /// a burst until regular correspondents have all written once.
+    #[arg(long, env = "NEW_SENDERS")]
+    new_senders: bool,
+
+    /// Count conversations in `email_threads_started_total` and messages by
+    /// thread length in `email_thread_messages_total`, keeping threads in the
+    /// state file
+    #[arg(long, env = "THREAD_METRICS")]
+    thread_metrics: bool,
+
+    /// With --thread-metrics, forget threads without a message in this many
+    /// seconds, so a late reply starts a new conversation
+    #[arg(
+        long,
+        env = "THREAD_IDLE",
+        default_value_t = 30 * 24 * 3600,
+        requires = "thread_metrics"
+    )]
+    thread_idle: u64,
+
+    /// Count messages arriving again within this many seconds of each other
+    /// (by Message-ID, or sender, subject and date without one) in
+    /// `email_duplicates_total`
+    #[arg(long, env = "DUPLICATE_WINDOW")]
+    duplicate_window: Option<u64>,
+
+    /// Files or URLs listing trusted sender addresses and domains, one per
+    /// line; senders are counted by reputation in
+    /// `email_received_by_reputation_total` and get a `reputation` label on
+    /// `email_received`
+    #[arg(long, env = "TRUSTED_SENDERS", value_delimiter = ',')]
+    trusted_senders: Vec<String>,
+
+    /// Files or URLs listing blocked sender addresses and domains, like
+    /// --trusted-senders; blocked wins when a sender is on both
+    #[arg(long, env = "BLOCKED_SENDERS", value_delimiter = ',')]
+    blocked_senders: Vec<String>,
+
+    /// How often (in seconds) the trusted and blocked sender lists are reloaded
+    #[arg(long, env = "REPUTATION_REFRESH", default_value_t = 3600)]
+    reputation_refresh: u64,
+
+    /// Add `hour` and `weekday` labels (from the message's internal date) to `email_received`
+    #[arg(long, env = "TIME_LABELS")]
+    time_labels: bool,
+
+    /// Also add `label_*` labels for Gmail's system labels (INBOX, UNREAD,
+    /// IMPORTANT, CATEGORY_* and so on); by default only user labels get them
+    #[arg(long, env = "SYSTEM_LABELS")]
+    system_labels: bool,
+
+    /// Besides `from_domain` and `to_domain`, which are trimmed to the
+    /// registrable domain (e.g. `example.com` for `mail1.notifications.example.com`),
+    /// add `from_raw_domain` and `to_raw_domain` labels with the full domains
+    /// to `email_received`
+    #[arg(long, env = "RAW_DOMAIN_LABELS")]
+    raw_domain_labels: bool,
+
+    /// Add a `lang` label to `email_received` with the language detected from
+    /// the subject (and the body, when bodies are fetched), as an ISO 639-3
+    /// code like `eng`
+    #[arg(long, env = "LANGUAGE_LABELS")]
+    language_labels: bool,
+
+    /// With --language-labels, ISO 639-3 codes of the languages to tell
+    /// apart; the rest are labelled `other`
+    #[arg(
+        long,
+        env = "LANGUAGES",
+        value_delimiter = ',',
+        value_parser = language::parse_language,
+        requires = "language_labels"
+    )]
+    languages: Vec<whatlang::Lang>,
+
+    /// IANA timezone used for derived time labels, quiet hours and the anomaly
+    /// score; `timezone` in the config overrides it
+    #[arg(long, env = "TIMEZONE", default_value = "UTC")]
+    timezone: chrono_tz::Tz,
+
+    /// Notify systemd when ready (Type=notify) and ping its watchdog after every poll.
+    /// WatchdogSec must be longer than the largest interval between polls.
+    #[arg(long, env = "SYSTEMD")]
+    systemd: bool,
+
+    /// TOML file with label filters, cardinality settings and `[[accounts]]` to
+    /// watch; filters and cardinality are reloaded on SIGHUP
+    #[arg(long, env = "CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Value of the `instance_id` label; a random one is generated per run if unset
+    #[arg(long, env = "INSTANCE_ID")]
+    instance_id: Option<String>,
+
+    /// File to persist counter values and the current history ID in, so they survive restarts
+    #[arg(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// How often (in seconds) to write counters to --state-file
+    #[arg(long, env = "STATE_SAVE_INTERVAL", default_value_t = 60)]
+    state_save_interval: u64,
+
+    /// SQLite database to record every observed message in; messages already
+    /// in it are not counted again, e.g. after restarting from an older history ID
+    #[arg(long, env = "SQLITE_PATH")]
+    sqlite_path: Option<PathBuf>,
+
+    /// Download the reports attached to DMARC aggregate report mail (subject
+    /// `Report Domain: ...`) and count the messages they cover as
+    /// `dmarc_messages{source_ip,disposition,dkim,spf}`; Gmail only
+    #[arg(long, env = "DMARC_REPORTS")]
+    dmarc_reports: bool,
+
+    /// Expose the number of drafts as `gmail_drafts_total`; Gmail only
+    #[arg(long, env = "DRAFTS")]
+    drafts: bool,
+
+    /// With --drafts, also expose how many drafts are older than this many
+    /// seconds as `gmail_stale_drafts`, for the GmailDraftsSitting alert
+    #[arg(long, env = "STALE_DRAFT_AGE", requires = "drafts")]
+    stale_draft_age: Option<u64>,
+
+    /// Expose the number of starred messages as `gmail_starred_messages`;
+    /// Gmail only
+    #[arg(long, env = "STARRED")]
+    starred: bool,
+
+    /// Expose the size of the spam folder as `gmail_spam_messages` and count
+    /// mail arriving in it, or reported as spam, as `gmail_spam_received_total`;
+    /// Gmail only
+    #[arg(long, env = "SPAM")]
+    spam: bool,
+
+    /// Expose the size of the trash as `gmail_trash_messages`, and count mail
+    /// moved to it as `gmail_trashed_total` and mail deleted for good as
+    /// `gmail_messages_deleted_total`; Gmail only
+    #[arg(long, env = "TRASH")]
+    trash: bool,
+
+    /// Check the vacation responder, send-as aliases, forwarding addresses and
+    /// filters this often (in seconds), exposed as
+    /// `gmail_vacation_responder_enabled`, `gmail_send_as_aliases`,
+    /// `gmail_forwarding_addresses`, `gmail_forwarding_address_changes_total`,
+    /// `gmail_filters_total` and `gmail_filter_info`; Gmail only
+    #[arg(long, env = "SETTINGS_INTERVAL")]
+    settings_interval: Option<u64>,
+
+    /// Count the inbox messages older than 1, 7 and 30 days this often (in
+    /// seconds), exposed as `gmail_inbox_age_bucket`; Gmail only
+    #[arg(long, env = "INBOX_AGE_INTERVAL")]
+    inbox_age_interval: Option<u64>,
+
+    /// Fetch whole messages instead of just their headers, so `[[bodies]]` in
+    /// the config can be matched against their text; Gmail only
+    #[arg(long, env = "SCAN_BODIES")]
+    scan_bodies: bool,
+
+    #[command(flatten)]
+    metrics: MetricsServerOptions,
+
+    #[command(flatten)]
+    api: ApiOptions,
+
+    #[cfg(feature = "imap")]
+    #[command(flatten)]
+    imap: ImapOptions,
+
+    #[command(flatten)]
+    graph: GraphOptions,
+
+    #[command(flatten)]
+    pubsub: PubSubOptions,
+
+    #[command(flatten)]
+    sinks: EventSinkOptions,
+}
+
+/// Where to publish an event for every observed message
+#[derive(Args)]
+struct EventSinkOptions {
+    #[cfg(feature = "mqtt")]
+    #[command(flatten)]
+    mqtt: MqttOptions,
+
+    #[cfg(feature = "kafka")]
+    #[command(flatten)]
+    kafka: KafkaOptions,
+
+    #[cfg(feature = "nats")]
+    #[command(flatten)]
+    nats: NatsOptions,
+
+    #[cfg(feature = "postgres")]
+    #[command(flatten)]
+    postgres: PostgresOptions,
+
+    #[command(flatten)]
+    clickhouse: ClickHouseOptions,
+
+    #[command(flatten)]
+    loki: LokiOptions,
+}
+
+impl WatchArgs {
+    /// What the turned on Gmail features need the token to allow
+    fn scope_needs(&self, config: Option<&Config>) -> Vec<ScopeNeed> {
+        let mut needs = vec![ScopeNeed {
+            feature: "watching the mailbox",
+            access: Access::Metadata,
+        }];
+        let mut need = |on: bool, feature, access| {
+            if on {
+                needs.push(ScopeNeed { feature, access });
+            }
+        };
+        need(self.scan_bodies, "--scan-bodies", Access::Read);
+        need(self.dmarc_reports, "--dmarc-reports", Access::Read);
+        need(
+            config.is_some_and(|config| config.risky_attachments.is_some()),
+            "[risky_attachments]",
+            Access::Read,
+        );
+        need(
+            self.stale_draft_age.is_some(),
+            "--stale-draft-age",
+            Access::Read,
+        );
+        need(
+            config.is_some_and(|config| !config.streams.is_empty()),
+            "[[streams]]",
+            Access::Read,
+        );
+        need(
+            self.settings_interval.is_some(),
+            "--settings-interval",
+            Access::Settings,
+        );
+        need(
+            self.inbox_age_interval.is_some(),
+            "--inbox-age-interval",
+            Access::Read,
+        );
+        needs
+    }
+
+    /// Headers are enough unless bodies or attachments are looked at
+    fn full_format(&self, config: Option<&Config>) -> bool {
+        self.scan_bodies
+            || self.dmarc_reports
+            || config.is_some_and(|config| config.risky_attachments.is_some())
+    }
 }
 
 #[::tokio::main]
 async fn main() {
-    let google_auth = GoogleAuth::load_from_env().await;
-    let mut mail = mail::MailClient {
-        google_client: google_auth,
-    };
-
     let cli = Cli::parse();
+    let _tracing = logging::init(&cli.logging);
+    if cli.trace_http {
+        http_trace::enable();
+    }
+    if let Some(dir) = cli.record.clone() {
+        http_trace::record_to(dir);
+    }
+    if let Some(dir) = cli.replay.clone() {
+        http_trace::replay_from(dir);
+    }
+    http_trace::set_user_agent(cli.user_agent.clone());
+
+    // These don't talk to Gmail, so don't require auth for them
+    match &cli.command {
+        Commands::Rules(options) => {
+            print!("{}", rules::render(options));
+            return;
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(*shell, &mut command, name, &mut std::io::stdout());
+            return;
+        }
+        Commands::Man { out_dir } => {
+            match out_dir {
+                Some(out_dir) => clap_mangen::generate_to(Cli::command(), out_dir)
+                    .expect("Failed to write man pages"),
+                None => clap_mangen::Man::new(Cli::command())
+                    .render(&mut std::io::stdout())
+                    .expect("Failed to write man page"),
+            }
+            return;
+        }
+        _ => {}
+    }
 
     match cli.command {
         Commands::FetchLatestMessageId {
@@ -50,69 +641,889 @@ async fn main() {
             // start_ts,
             // end_ts,
         } => {
-            println!("fetching latest message id...");
-            let labels = mail.load_labels().await;
-            let mail_listing = mail.fetch_mail().await;
-            let mail_details = mail.fetch_mail_details(mail_listing, &labels).await;
+            let mut mail = env_mail_client(cli.paging).await;
+            info!("fetching latest message id...");
+            let profile = or_exit(mail.fetch_profile().await);
 
-            if let Some(message) = mail_details.first() {
-                println!("Latest message history id: {}", message.history_id);
+            println!("Latest message history id: {}", profile.history_id);
+        }
+        Commands::Rules(_) | Commands::Completions { .. } | Commands::Man { .. } => {
+            unreachable!()
+        }
+        Commands::ListLabels { counts, format } => {
+            let mut mail = env_mail_client(cli.paging).await;
+            let mut labels = or_exit(mail.list_labels().await);
+            if counts {
+                for label in labels.iter_mut() {
+                    *label = or_exit(mail.get_label(&label.id).await);
+                }
+            }
+            labels.sort_by(|a, b| (&a.label_type, &a.name).cmp(&(&b.label_type, &b.name)));
+
+            match format {
+                OutputFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&labels).unwrap())
+                }
+                OutputFormat::Table => print_labels_table(&labels, counts),
             }
         }
-        Commands::WatchInbox {
-            starting_from: initial_starting_from,
-            sleep_interval,
-        } => {
-            let mut starting_from = initial_starting_from.clone();
-            let labels = mail.load_labels().await;
-
-            PrometheusBuilder::new()
-                .idle_timeout(
-                    MetricKindMask::ALL,
-                    Some(
-                        Duration::days(365)
-                            .to_std()
-                            .unwrap(),
-                    ),
+        Commands::Export(options) => {
+            let mut mail = env_mail_client(cli.paging).await;
+            match export::run(&options, &mut mail).await {
+                Ok(exported) => println!(
+                    "Exported {} messages to {}",
+                    exported,
+                    options.out.display()
+                ),
+                Err(err) => {
+                    error!("Export failed: {}", err);
+                    logging::flush();
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Sync(args) => {
+            let SyncArgs { options, sinks } = *args;
+            let mut mail = env_mail_client(cli.paging).await;
+            let event_sinks = build_event_sinks(
+                &sinks,
+                false,
+                &format!("gmail-prom-exporter-{}", Uuid::new_v4()),
+            );
+            match sync::run(&options, &mut mail, &event_sinks).await {
+                Ok(summary) => println!(
+                    "Synced {} messages to {}, {} of them new",
+                    summary.listed,
+                    options.sqlite_path.display(),
+                    summary.stored
+                ),
+                Err(err) => {
+                    error!("Sync failed: {}", err);
+                    logging::flush();
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Report(options) => {
+            if let Err(err) = report::run(&options).await {
+                error!("Report failed: {}", err);
+                logging::flush();
+                std::process::exit(1);
+            }
+        }
+        Commands::Snapshot { options, format } => {
+            let mut mail = env_mail_client(cli.paging).await;
+            match snapshot::run(&options, &mut mail).await {
+                Ok(diff) => match format {
+                    OutputFormat::Json => {
+                        println!("{}", serde_json::to_string_pretty(&diff).unwrap())
+                    }
+                    OutputFormat::Table => print_snapshot_diff(&diff),
+                },
+                Err(err) => {
+                    error!("Snapshot failed: {}", err);
+                    logging::flush();
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::InspectMessage { id } => {
+            let mut mail = env_mail_client(cli.paging).await;
+            let labels = or_exit(mail.load_labels().await);
+            let Some(message) = or_exit(mail.fetch_message(&id).await) else {
+                println!("Message {} not found", id);
+                std::process::exit(1);
+            };
+
+            println!("Raw headers:");
+            for header in message.selected_headers() {
+                println!("  {}: {}", header.name, header.value);
+            }
+
+            let usable = or_exit(mail::UsableMessageDetails::from(message, &labels));
+            println!();
+            println!("Parsed message:");
+            println!("{:#?}", usable);
+
+            println!();
+            println!("Category: {}", usable.category().unwrap_or("none"));
+
+            println!();
+            println!("Metric labels:");
+            for (key, value) in usable.as_labels() {
+                println!("  {}=\"{}\"", key, value);
+            }
+        }
+        Commands::GraphLogin(options) => or_exit(graph::login(&options).await),
+        Commands::WatchInbox(args) => watch_inbox(*args, cli.paging, None, None).await,
+        Commands::Simulate(args) => watch_inbox(args.watch, cli.paging, Some(args.simulation), None).await,
+        Commands::ReplayIds(args) => {
+            let ids = replay_ids::read_ids(&args.ids).unwrap_or_else(|err| {
+                error!("{}", err);
+                logging::flush();
+                std::process::exit(1)
+            });
+            // Nothing is saved, so the same IDs always count the same
+            let watch = WatchArgs {
+                once: true,
+                state_file: None,
+                starting_from: None,
+                ..args.watch
+            };
+            watch_inbox(watch, cli.paging, None, Some(ids)).await
+        }
+    }
+}
+
+/// The mailbox given through the GOOGLE_* environment variables. Exits
+/// with instructions if it isn't authenticated yet.
+async fn env_mail_client(paging: PagingOptions) -> mail::MailClient {
+    if http_trace::replaying() {
+        let replay = || "replay".to_owned();
+        return mail::MailClient::new(GoogleAuth::new(
+            replay(),
+            replay(),
+            Some(replay()),
+            Some(replay()),
+        ))
+        .with_paging(paging);
+    }
+
+    match GoogleAuth::load_from_env().await {
+        Ok(google_client) => mail::MailClient::new(google_client).with_paging(paging),
+        Err(Error::NotAuthenticated { auth_url }) => {
+            warn!("Auth URL: {}", auth_url);
+            warn!("Please visit the URL above to authenticate.");
+            warn!("Set the GOOGLE_CALLBACK environment variable to the code you receive.");
+            // Last, so the instructions above are attached to the report
+            error!("Not authenticated!");
+
+            logging::flush();
+            std::process::exit(1);
+        }
+        Err(err) => or_exit(Err(err)),
+    }
+}
+
+/// Exits naming the missing scopes if the token can't do what's turned on
+async fn check_scopes(mut mail: mail::MailClient, needs: &[ScopeNeed]) -> mail::MailClient {
+    if http_trace::replaying() {
+        return mail;
+    }
+    if let Err(missing) = scopes::check(&mut mail.google_client, needs).await {
+        error!("{}", missing);
+        logging::flush();
+        std::process::exit(1);
+    }
+    mail
+}
+
+/// Log why a one-shot command failed and exit
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|err| {
+        error!("{}", err);
+        logging::flush();
+        std::process::exit(1)
+    })
+}
+
+/// One mailbox to watch, from the environment or an `[[accounts]]` entry
+struct Mailbox {
+    account: Option<String>,
+    mail: Box<dyn MailBackend>,
+    state_file: Option<PathBuf>,
+    starting_from: Option<String>,
+}
+
+/// Watches simulated mailboxes instead of real ones if `simulation` is set
+/// With `replay_ids`, those messages are the only ones polled
+async fn watch_inbox(
+    args: WatchArgs,
+    paging: PagingOptions,
+    simulation: Option<SimulateOptions>,
+    replay_ids: Option<Vec<String>>,
+) {
+    let instance_id = args
+        .instance_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let loaded_config = args
+        .config
+        .as_ref()
+        .map(|path| Config::load(path).unwrap_or_else(|err| panic!("{}", err)));
+    let mut global_labels = vec![("instance_id".to_owned(), instance_id.clone())];
+    if let Some(loaded_config) = &loaded_config {
+        global_labels.extend(loaded_config.global_labels.clone());
+    }
+    exposition::install(global_labels.clone());
+
+    let prometheus_handle = (!args.dry_run).then(|| {
+        let mut builder = PrometheusBuilder::new().idle_timeout(
+            MetricKindMask::ALL,
+            Some(Duration::days(365).to_std().unwrap()),
+        );
+        for (key, value) in global_labels {
+            builder = builder.add_global_label(key, value);
+        }
+        builder
+            .install_recorder()
+            .expect("Failed to install Prometheus recorder")
+    });
+
+    MetricsPipeline::describe();
+    let base_settings = PipelineSettings {
+        max_received_series: args.max_received_series,
+        time_labels: args.time_labels,
+        timezone: args.timezone,
+        label_filters: Default::default(),
+        raw_domain_labels: args.raw_domain_labels,
+        language_labels: args
+            .language_labels
+            .then(|| LanguageLabels::new(args.languages.clone())),
+        system_labels: args.system_labels,
+        label_renames: vec![],
+        streams: vec![],
+        subject_matchers: vec![],
+        body_matchers: vec![],
+        rules: vec![],
+        risky_attachments: None,
+        loop_detection: None,
+        quiet_hours: None,
+        snippets: None,
+        hash_addresses: args
+            .hash_addresses
+            .clone()
+            .map(|salt| AddressHasher::new(salt, args.hash_domains)),
+    };
+
+    let accounts = loaded_config
+        .as_ref()
+        .map(|loaded_config| loaded_config.accounts.clone())
+        .unwrap_or_default();
+    let mut mailboxes = if accounts.is_empty() {
+        let mail: Box<dyn MailBackend> = if let Some(simulation) = &simulation {
+            Box::new(SimulatedBackend::new(simulation, 0))
+        } else if let Some(imap) = connect_imap(&args).await {
+            imap
+        } else if args.graph.graph_refresh_token.is_some() {
+            Box::new(or_exit(
+                GraphBackend::new(
+                    or_exit(GraphAuth::from_options(&args.graph, args.account.clone())),
+                    args.graph.graph_mail_folder.clone(),
+                )
+                .await,
+            ))
+        } else {
+            Box::new(or_exit(
+                GmailBackend::new(
+                    check_scopes(
+                        env_mail_client(paging)
+                            .await
+                            .with_full_format(args.full_format(loaded_config.as_ref())),
+                        &args.scope_needs(loaded_config.as_ref()),
+                    )
+                    .await,
                 )
-                .add_global_label("instance_id", Uuid::new_v4())
-                .with_http_listener(([0, 0, 0, 0], 9090))
-                .install()
-                .expect("Failed to install Prometheus recorder");
-
-            describe_counter!(
-                "email_received",
-                "A counter for every email received."
+                .await,
+            ))
+        };
+        vec![Mailbox {
+            account: args.account.clone(),
+            mail,
+            state_file: args.state_file.clone(),
+            starting_from: args.starting_from.clone(),
+        }]
+    } else {
+        assert!(
+            args.account.is_none() && args.state_file.is_none() && args.starting_from.is_none(),
+            "--account, --state-file and --starting-from can't be combined with [[accounts]] \
+             in the config; set name and state_file per account instead"
+        );
+        assert!(
+            !args.poll_on_scrape && args.pubsub.pubsub_topic.is_none(),
+            "--poll-on-scrape and --pubsub-topic only support a single account"
+        );
+        assert!(
+            args.graph.graph_refresh_token.is_none(),
+            "--graph-refresh-token can't be combined with [[accounts]] in the config; set \
+             provider per account instead"
+        );
+        #[cfg(feature = "imap")]
+        assert!(
+            args.imap.imap_url.is_none(),
+            "--imap-url can't be combined with [[accounts]] in the config"
+        );
+
+        let mut mailboxes = vec![];
+        for (index, account) in accounts.iter().enumerate() {
+            let mail: Box<dyn MailBackend> = match (&simulation, account.provider) {
+                (Some(simulation), _) => Box::new(SimulatedBackend::new(simulation, index as u64)),
+                (None, MailProvider::Gmail) => Box::new(or_exit(
+                    GmailBackend::new(
+                        check_scopes(
+                            mail::MailClient::new(or_exit(GoogleAuth::for_account(account).await))
+                                .with_full_format(args.full_format(loaded_config.as_ref()))
+                                .with_paging(paging),
+                            &args.scope_needs(loaded_config.as_ref()),
+                        )
+                        .await,
+                    )
+                    .await,
+                )),
+                (None, MailProvider::Graph) => Box::new(or_exit(
+                    GraphBackend::new(
+                        or_exit(GraphAuth::for_account(account)),
+                        account.folder.clone().unwrap_or_else(|| "inbox".to_owned()),
+                    )
+                    .await,
+                )),
+            };
+            mailboxes.push(Mailbox {
+                account: Some(account.name.clone()),
+                mail,
+                state_file: account.state_file.clone(),
+                starting_from: None,
+            });
+        }
+        mailboxes
+    };
+    if let Some(ids) = replay_ids {
+        assert!(
+            mailboxes.len() == 1,
+            "replay-ids can't be combined with [[accounts]] in the config"
+        );
+        mailboxes = mailboxes
+            .into_iter()
+            .map(|mailbox| Mailbox {
+                mail: Box::new(IdReplayBackend::new(mailbox.mail, ids.clone())),
+                ..mailbox
+            })
+            .collect();
+    }
+
+    let mut event_sinks = build_event_sinks(
+        &args.sinks,
+        args.dry_run,
+        &format!("gmail-prom-exporter-{}", instance_id),
+    );
+
+    // Shared by every account; rows carry the account name
+    let store = args
+        .sqlite_path
+        .as_deref()
+        .filter(|_| !args.dry_run)
+        .map(|path| Arc::new(MessageStore::open(path)));
+
+    let reputation = if args.trusted_senders.is_empty() && args.blocked_senders.is_empty() {
+        None
+    } else {
+        let reputation = Arc::new(SenderReputation::new(
+            args.trusted_senders.clone(),
+            args.blocked_senders.clone(),
+        ));
+        reputation.refresh().await;
+        if !args.once {
+            reputation::spawn_refreshing(
+                reputation.clone(),
+                std::time::Duration::from_secs(args.reputation_refresh),
             );
-            describe_counter!(
-                "email_polls",
-                "A counter for every time we checked for emails."
+        }
+        Some(reputation)
+    };
+
+    let mut api_routes = (args.api.messages_api || args.api.event_stream).then(|| {
+        let source = args.api.messages_api.then(|| match &store {
+            Some(store) => MessageSource::Sqlite(store.clone()),
+            None => {
+                let recent = Arc::new(RecentMessages::new(&args.api));
+                event_sinks.push(recent.clone());
+                MessageSource::Memory(recent)
+            }
+        });
+        let stream = args.api.event_stream.then(|| {
+            let stream = Arc::new(EventStream::new());
+            event_sinks.push(stream.clone());
+            stream
+        });
+        api::router(&args.api, source, stream)
+    });
+
+    let pubsub_wake = Arc::new(tokio::sync::Notify::new());
+    let mut watchers = vec![];
+    for mailbox in mailboxes {
+        let settings = match &loaded_config {
+            Some(loaded_config) => loaded_config.apply(&base_settings, mailbox.account.as_deref()),
+            None => base_settings.clone(),
+        };
+        let pubsub_watch = args.pubsub.pubsub_topic.clone().map(|topic| {
+            PubSubWatch::new(
+                topic,
+                pubsub_wake.clone(),
+                std::time::Duration::from_secs(args.pubsub.pubsub_renewal_lead),
+            )
+        });
+
+        watchers.push(
+            build_watcher(
+                &args,
+                mailbox,
+                settings,
+                pubsub_watch,
+                event_sinks.clone(),
+                store.clone(),
+                reputation.clone(),
+            )
+            .await,
+        );
+    }
+
+    let newsletter_inventories = watchers
+        .iter()
+        .filter_map(|watcher| {
+            let inventory = watcher.pipeline.newsletter_senders.clone()?;
+            Some((watcher.pipeline.account.clone(), inventory))
+        })
+        .collect::<Vec<_>>();
+    if !newsletter_inventories.is_empty() {
+        let newsletters = api::newsletters_router(Arc::new(newsletter_inventories));
+        api_routes = Some(api_routes.unwrap_or_default().merge(newsletters));
+    }
+
+    if let (Some(path), Some(loaded_config)) = (args.config.clone(), loaded_config) {
+        config::spawn_reload_on_sighup(
+            path,
+            loaded_config,
+            base_settings,
+            watchers
+                .iter()
+                .map(|watcher| watcher.pipeline.clone())
+                .collect(),
+        );
+    }
+
+    let state_files = watchers
+        .iter()
+        .filter_map(|watcher| watcher.state_file.clone())
+        .collect::<Vec<_>>();
+
+    if args.once {
+        let mut failed = false;
+        for watcher in &mut watchers {
+            if let Err(err) = watcher.poll_once().await {
+                error!("Poll failed: {}", err);
+                failed = true;
+            }
+        }
+
+        for state_file in &state_files {
+            state_file.save();
+        }
+
+        if let Some(prometheus_handle) = prometheus_handle {
+            print!(
+                "{}{}",
+                prometheus_handle.render(),
+                exposition::render_collectors()
             );
+        }
+        if failed {
+            logging::flush();
+            std::process::exit(1);
+        }
+        return;
+    }
 
-            println!("Beginning silent watch for new mail...");
+    let mut failed = false;
+    if args.poll_on_scrape {
+        let scrape_trigger = Arc::new(ScrapeTrigger::new(
+            watchers.pop().expect("Expected a watcher"),
+            std::time::Duration::from_secs(args.sleep_interval),
+        ));
+        let server = start_metrics_server(
+            prometheus_handle,
+            args.metrics,
+            Some(scrape_trigger.clone()),
+            api_routes,
+            None,
+        )
+        .await;
 
-            loop {
-                let history = mail.fetch_history(&starting_from).await;
-                let mail_details = mail.fetch_mail_details(history, &labels).await;
-                counter!("email_polls", 1);
+        tokio::select! {
+            err = scrape_trigger.gave_up() => {
+                error!("{}", err);
+                failed = true;
+            }
+            result = server => {
+                report_server_stopped(result);
+                failed = true;
+            }
+            _ = shutdown_signal() => info!("Shutting down..."),
+        }
+        if tokio::time::timeout(SHUTDOWN_GRACE, scrape_trigger.stop())
+            .await
+            .is_err()
+        {
+            warn!(
+                "The poll in progress didn't stop within {:?}, saving the last checkpoint",
+                SHUTDOWN_GRACE
+            );
+        }
+    } else {
+        let push_routes = args
+            .pubsub
+            .pubsub_topic
+            .is_some()
+            .then(|| pubsub::router(args.pubsub, pubsub_wake));
+        let server = start_metrics_server(
+            prometheus_handle,
+            args.metrics,
+            None,
+            api_routes,
+            push_routes,
+        )
+        .await;
 
-                if !mail_details.is_empty() {
-                    println!("Found more mail: {} messages", mail_details.len());
-                    // println!("{:#?}", mail_details);
-                    starting_from = mail_details.last().unwrap().history_id.clone();
+        let mut tasks = tokio::task::JoinSet::new();
+        for watcher in watchers {
+            tasks.spawn(watcher.run());
+        }
 
-                    for message in mail_details {
-                        counter!(
-                            "email_received",
-                            1,
-                            &message.as_labels()
-                        );
-                    }
+        tokio::select! {
+            Some(result) = tasks.join_next() => {
+                if let Err(err) = result.expect("Watch task failed") {
+                    error!("{}", err);
+                    failed = true;
                 }
+            }
+            result = server => {
+                report_server_stopped(result);
+                failed = true;
+            }
+            _ = shutdown_signal() => info!("Shutting down..."),
+        }
+
+        // Let the others finish the batch they're on. Whatever they're cut
+        // off in the middle of after that is left out of the final save.
+        watch::stop();
+        let finishing = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(SHUTDOWN_GRACE, finishing)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Watchers didn't stop within {:?}, saving the last checkpoint",
+                SHUTDOWN_GRACE
+            );
+        }
+    }
+
+    if args.systemd {
+        systemd::notify_stopping();
+    }
+
+    for state_file in state_files {
+        state_file.save();
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Binds every listener and loads TLS up front, exiting if that fails, then
+/// serves in the background. The handle resolves if a server stops.
+async fn start_metrics_server(
+    prometheus_handle: Option<PrometheusHandle>,
+    options: MetricsServerOptions,
+    scrape_trigger: Option<Arc<ScrapeTrigger>>,
+    routes: Option<Router>,
+    push_routes: Option<Router>,
+) -> JoinHandle<Result<(), String>> {
+    let Some(prometheus_handle) = prometheus_handle else {
+        return tokio::spawn(std::future::pending());
+    };
+    let server = or_exit(
+        server::bind_metrics(
+            prometheus_handle,
+            options,
+            scrape_trigger,
+            routes,
+            push_routes,
+        )
+        .await,
+    );
+    tokio::spawn(server.serve())
+}
+
+fn report_server_stopped(result: Result<Result<(), String>, JoinError>) {
+    match result {
+        Ok(Err(err)) => error!("{}", err),
+        Ok(Ok(())) => error!("Metrics server stopped"),
+        Err(err) => error!("Metrics server failed: {}", err),
+    }
+}
+
+/// The IMAP backend if `--imap-url` was given
+#[cfg(feature = "imap")]
+async fn connect_imap(args: &WatchArgs) -> Option<Box<dyn MailBackend>> {
+    args.imap.imap_url.as_ref()?;
+    Some(Box::new(ImapBackend::connect(&args.imap).await))
+}
+
+#[cfg(not(feature = "imap"))]
+async fn connect_imap(_: &WatchArgs) -> Option<Box<dyn MailBackend>> {
+    None
+}
+
+/// Every sink configured on the command line, shared by all accounts
+#[cfg_attr(not(any(feature = "mqtt", feature = "kafka")), allow(unused_variables))]
+fn build_event_sinks(
+    args: &EventSinkOptions,
+    dry_run: bool,
+    client_id: &str,
+) -> Vec<Arc<dyn EventSink>> {
+    let mut sinks: Vec<Arc<dyn EventSink>> = vec![];
+    let mut add = |name: &'static str, connect: &dyn Fn() -> Arc<dyn EventSink>| {
+        sinks.push(if dry_run {
+            Arc::new(DryRunSink(name))
+        } else {
+            connect()
+        });
+    };
+
+    #[cfg(feature = "mqtt")]
+    if args.mqtt.mqtt_url.is_some() {
+        add("mqtt", &|| {
+            Arc::new(MqttPublisher::connect(&args.mqtt, client_id))
+        });
+    }
+    #[cfg(feature = "kafka")]
+    if !args.kafka.kafka_brokers.is_empty() {
+        add("kafka", &|| Arc::new(kafka::spawn(&args.kafka, client_id)));
+    }
+    #[cfg(feature = "nats")]
+    if args.nats.nats_url.is_some() {
+        add("nats", &|| Arc::new(nats::spawn(&args.nats)));
+    }
+    #[cfg(feature = "postgres")]
+    if args.postgres.postgres_url.is_some() {
+        add("postgres", &|| Arc::new(postgres::spawn(&args.postgres)));
+    }
+    if args.clickhouse.clickhouse_url.is_some() {
+        add("clickhouse", &|| {
+            Arc::new(clickhouse::spawn(&args.clickhouse))
+        });
+    }
+    if args.loki.loki_url.is_some() {
+        add("loki", &|| Arc::new(loki::spawn(&args.loki)));
+    }
+
+    sinks
+}
+
+async fn build_watcher(
+    args: &WatchArgs,
+    mut mailbox: Mailbox,
+    settings: PipelineSettings,
+    pubsub: Option<PubSubWatch>,
+    event_sinks: Vec<Arc<dyn EventSink>>,
+    store: Option<Arc<MessageStore>>,
+    reputation: Option<Arc<SenderReputation>>,
+) -> Watcher {
+    // A dry run may resume from the state file, but never writes to it
+    let saved_state_file = mailbox
+        .state_file
+        .map(|path| Arc::new(StateFile::new(path, mailbox.account.clone())));
+    let state_file = saved_state_file.clone().filter(|_| !args.dry_run);
+    if let Some(state_file) = &state_file {
+        state_file.restore_counters();
+        if !args.once {
+            state::spawn_snapshotting(
+                state_file.clone(),
+                std::time::Duration::from_secs(args.state_save_interval),
+            );
+        }
+    }
+
+    // Learned per hour of the week, so unlike the rest of the settings this
+    // can't follow a reload
+    let timezone = settings.timezone;
+    let mut pipeline = MetricsPipeline::new(mailbox.account, settings);
+    if let Some(top_senders) = args.top_senders {
+        pipeline = pipeline.with_top_senders(
+            top_senders,
+            std::time::Duration::from_secs(args.top_senders_window),
+        );
+    }
+    if let Some(newsletter_senders) = args.newsletter_senders {
+        pipeline = pipeline.with_newsletter_senders(
+            newsletter_senders,
+            std::time::Duration::from_secs(args.top_senders_window),
+        );
+    }
+    if args.anomaly_score {
+        pipeline = pipeline.with_arrival_baseline(timezone);
+    }
+    if let (Some(state_file), Some(arrival_baseline)) = (&state_file, &pipeline.arrival_baseline) {
+        state_file.track_arrival_baseline(arrival_baseline.clone());
+    }
+    if args.new_senders {
+        pipeline.sender_history = Some(Arc::new(SenderHistory::default()));
+    }
+    if let (Some(state_file), Some(sender_history)) = (&state_file, &pipeline.sender_history) {
+        state_file.track_senders(sender_history.clone());
+    }
+    if args.thread_metrics {
+        pipeline.threads = Some(Arc::new(ThreadTracker::new(
+            std::time::Duration::from_secs(args.thread_idle),
+        )));
+    }
+    if let (Some(state_file), Some(threads)) = (&state_file, &pipeline.threads) {
+        state_file.track_threads(threads.clone());
+    }
+    pipeline.duplicates = args
+        .duplicate_window
+        .map(|window| DuplicateDetector::new(std::time::Duration::from_secs(window)));
+    pipeline.reputation = reputation;
+    pipeline.event_sinks = event_sinks;
+    pipeline.dry_run = args.dry_run;
+    pipeline.system_labels = mailbox.mail.system_labels();
+    if let Some(state_file) = &state_file {
+        pipeline.restore_received_series(&state_file.load().counters);
+    }
+    let pipeline = Arc::new(pipeline);
+
+    let saved_history_id = saved_state_file
+        .as_ref()
+        .and_then(|state_file| state_file.load().history_id);
+
+    let starting_from = match (mailbox.starting_from, saved_history_id) {
+        (Some(starting_from), _) => starting_from,
+        (None, Some(history_id)) => {
+            info!("Resuming from saved history id {}", history_id);
+            history_id
+        }
+        // Nothing to resume from, so start watching from whatever is newest right now
+        (None, None) => {
+            let history_id = or_exit(mailbox.mail.current_cursor().await);
+            info!(
+                "No starting point given, bootstrapping from current history id {}",
+                history_id
+            );
+            history_id
+        }
+    };
+
+    Watcher {
+        mail: mailbox.mail,
+        pipeline,
+        starting_from,
+        schedule: PollSchedule {
+            current: std::time::Duration::from_secs(args.sleep_interval),
+            adaptive: args.adaptive_polling.then(|| {
+                (
+                    std::time::Duration::from_secs(args.min_sleep_interval),
+                    std::time::Duration::from_secs(args.max_sleep_interval),
+                )
+            }),
+            jitter: args.poll_jitter,
+        },
+        state_file,
+        systemd: args.systemd,
+        max_messages_per_poll: args.max_messages_per_poll,
+        backlog: Default::default(),
+        pubsub,
+        store,
+        dmarc_reports: args.dmarc_reports,
+        drafts: args.drafts,
+        starred: args.starred,
+        spam: args.spam,
+        trash: args.trash,
+        stale_draft_age: args.stale_draft_age.map(std::time::Duration::from_secs),
+        settings_interval: args.settings_interval.map(std::time::Duration::from_secs),
+        settings_checked_at: None,
+        inbox_age_interval: args.inbox_age_interval.map(std::time::Duration::from_secs),
+        inbox_age_checked_at: None,
+        delivery: args.delivery,
+        heartbeat_url: args.heartbeat_url.clone(),
+        on_failure: args.on_failure,
+        failure_budget: std::time::Duration::from_secs(args.failure_budget),
+        failing_since: None,
+        stopping: watch::stopping(),
+    }
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+fn print_labels_table(labels: &[mail::Label], counts: bool) {
+    let mut rows = vec![vec!["ID".to_owned(), "NAME".to_owned(), "TYPE".to_owned()]];
+    if counts {
+        rows[0].extend(["TOTAL".to_owned(), "UNREAD".to_owned()]);
+    }
+
+    for label in labels {
+        let mut row = vec![
+            label.id.clone(),
+            label.name.clone(),
+            label.label_type.clone().unwrap_or_default(),
+        ];
+        if counts {
+            row.push(label.messages_total.unwrap_or_default().to_string());
+            row.push(label.messages_unread.unwrap_or_default().to_string());
+        }
+        rows.push(row);
+    }
+
+    let widths = (0..rows[0].len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+fn print_snapshot_diff(diff: &SnapshotDiff) {
+    let Some(since) = diff.since else {
+        println!("First snapshot saved; the next run prints what changed since this one");
+        return;
+    };
+    if diff.changes.is_empty() {
+        println!("Nothing changed since {}", since);
+        return;
+    }
 
-                // Sleep
-                let sleep_duration = std::time::Duration::from_secs(sleep_interval);
-                std::thread::sleep(sleep_duration);
+    println!("Changes since {}:", since);
+    for change in &diff.changes {
+        match change {
+            Change::Label {
+                name,
+                messages,
+                unread,
+            } => println!(
+                "  label {}: {:+} messages, {:+} unread",
+                name, messages, unread
+            ),
+            Change::Sender { address, messages } => {
+                println!("  sender {}: {:+} messages", address, messages)
             }
         }
     }
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::events::QueuedSink;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Args)]
+pub struct NatsOptions {
+    /// Publish an event for every observed message to this NATS server, e.g. `nats://localhost:4222`
+    #[arg(long, env = "NATS_URL")]
+    pub nats_url: Option<String>,
+
+    #[arg(long, env = "NATS_SUBJECT", default_value = "gmail.messages")]
+    pub nats_subject: String,
+}
+
+pub fn spawn(options: &NatsOptions) -> QueuedSink {
+    let (sink, mut events) = QueuedSink::new("nats");
+    let url = options
+        .nats_url
+        .clone()
+        .expect("nats::spawn needs --nats-url");
+    let subject = options.nats_subject.clone();
+
+    let delivery = tokio::spawn(async move {
+        // The client reconnects by itself once it has connected the first time
+        let client = loop {
+            match async_nats::connect(&url).await {
+                Ok(client) => break client,
+                Err(err) => {
+                    warn!("Failed to connect to NATS: {}", err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        };
+        info!("Connected to NATS, publishing to {}", subject);
+
+        while let Some(event) = events.recv().await {
+            let payload = serde_json::to_vec(&event).unwrap();
+            if let Err(err) = client.publish(subject.clone(), payload.into()).await {
+                warn!(message_id = event.id, "Failed to publish to NATS: {}", err);
+            }
+        }
+        // Publishing only buffers; send what's left before the sink closes
+        if let Err(err) = client.flush().await {
+            warn!("Failed to flush NATS messages: {}", err);
+        }
+    });
+
+    sink.delivered_by(delivery)
+}
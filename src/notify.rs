@@ -0,0 +1,317 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{http_trace, mail::ParseForMetrics, mail::UsableMessageDetails, state};
+
+/// First retry waits this long, doubling after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Discord rejects embed descriptions longer than this
+const DISCORD_DESCRIPTION_LIMIT: usize = 4096;
+
+/// `notify` action of a rule: POST matching messages to a webhook
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: NotifyFormat,
+    /// Message text for Slack and Discord; `{from}`, `{to}`, `{subject}`,
+    /// `{labels}`, `{link}`, `{rule}`, `{account}` and `{snippet}` are filled
+    /// in, the last only with `[snippets]` in the config
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Drop notifications beyond this rate, so a mail storm doesn't flood a channel
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Extra request headers, e.g. for an auth token
+    #[serde(default, skip_serializing)]
+    pub headers: BTreeMap<String, String>,
+    /// Attempts before giving up on a delivery
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyFormat {
+    /// The Notification itself, for custom receivers
+    #[default]
+    Json,
+    /// Slack incoming webhook, as Block Kit
+    Slack,
+    /// Discord webhook, as an embed
+    Discord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub max: usize,
+    pub per_seconds: u64,
+}
+
+/// The JSON body of a webhook delivery
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub account: Option<String>,
+    pub id: String,
+    pub thread_id: String,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub labels: Vec<String>,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub link: String,
+    /// A preview of the text, if `[snippets]` is in the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+impl Notification {
+    pub fn new(
+        rule: &str,
+        account: Option<&str>,
+        message: &UsableMessageDetails,
+        snippet: Option<String>,
+    ) -> Self {
+        Self {
+            rule: rule.to_owned(),
+            account: account.map(str::to_owned),
+            id: message.id.clone(),
+            thread_id: message.thread_id.clone(),
+            from: message.from.first_address().unwrap_or_default(),
+            to: message.to.first_address().unwrap_or_default(),
+            subject: message.subject.clone(),
+            labels: message.labels.clone(),
+            date: message.internal_date,
+            link: format!("https://mail.google.com/mail/#all/{}", message.id),
+            snippet,
+        }
+    }
+
+    /// Fills placeholders in one pass, so braces inside message fields are left alone
+    fn render(&self, template: &str, escape: fn(&str) -> String) -> String {
+        let mut rendered = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            rendered.push_str(&rest[..start]);
+
+            let value = match &rest[start + 1..start + end] {
+                "from" => escape(&self.from),
+                "to" => escape(&self.to),
+                "subject" => escape(&self.subject),
+                "labels" => escape(&self.labels.join(", ")),
+                "link" => escape(&self.link),
+                "rule" => escape(&self.rule),
+                "account" => escape(self.account.as_deref().unwrap_or_default()),
+                "snippet" => escape(self.snippet.as_deref().unwrap_or_default()),
+                _ => rest[start..=start + end].to_owned(),
+            };
+            rendered.push_str(&value);
+            rest = &rest[start + end + 1..];
+        }
+
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+/// Slack mrkdwn treats these as control characters
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn body(config: &NotifyConfig, notification: &Notification) -> Value {
+    match config.format {
+        NotifyFormat::Json => serde_json::to_value(notification).unwrap(),
+        NotifyFormat::Slack => {
+            let text = notification.render(
+                config
+                    .template
+                    .as_deref()
+                    .unwrap_or("*New mail from {from}*\n{subject}"),
+                escape_slack,
+            );
+            json!({
+                "text": text,
+                "blocks": [
+                    {
+                        "type": "section",
+                        "text": { "type": "mrkdwn", "text": text },
+                    },
+                    {
+                        "type": "context",
+                        "elements": [{
+                            "type": "mrkdwn",
+                            "text": format!("<{}|Open in Gmail> · {}", notification.link, escape_slack(&notification.rule)),
+                        }],
+                    },
+                ],
+            })
+        }
+        NotifyFormat::Discord => {
+            let description = notification.render(
+                config.template.as_deref().unwrap_or("New mail from {from}"),
+                str::to_owned,
+            );
+            json!({
+                "embeds": [{
+                    "title": notification.subject.chars().take(256).collect::<String>(),
+                    "description": description.chars().take(DISCORD_DESCRIPTION_LIMIT).collect::<String>(),
+                    "url": notification.link,
+                    "timestamp": notification.date,
+                    "footer": { "text": notification.rule },
+                }],
+            })
+        }
+    }
+}
+
+/// A rule's notify action, along with its rate limiting state
+#[derive(Debug)]
+pub struct Notifier {
+    pub config: NotifyConfig,
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl Notifier {
+    pub fn new(config: NotifyConfig) -> Self {
+        Self {
+            config,
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn notify(&self, notification: Notification, dry_run: bool) {
+        if !self.allow() {
+            warn!(
+                rule = notification.rule,
+                message_id = notification.id,
+                "Rate limit reached, dropping notification"
+            );
+            record_delivery(&notification, "rate_limited");
+            return;
+        }
+
+        if dry_run {
+            info!(
+                url = self.config.url,
+                body = %body(&self.config, &notification),
+                "dry run: would notify"
+            );
+            return;
+        }
+
+        spawn_delivery(self.config.clone(), notification);
+    }
+
+    fn allow(&self) -> bool {
+        let Some(rate_limit) = self.config.rate_limit else {
+            return true;
+        };
+        let window = Duration::from_secs(rate_limit.per_seconds);
+        let mut recent = self.recent.lock().unwrap();
+
+        while recent.front().is_some_and(|sent| sent.elapsed() >= window) {
+            recent.pop_front();
+        }
+        if recent.len() >= rate_limit.max {
+            return false;
+        }
+
+        recent.push_back(Instant::now());
+        true
+    }
+}
+
+/// Delivers in the background, retrying with exponential backoff, so a slow
+/// or broken webhook never holds up polling.
+fn spawn_delivery(config: NotifyConfig, notification: Notification) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut backoff = INITIAL_BACKOFF;
+        let body = body(&config, &notification);
+
+        for attempt in 1..=config.max_attempts {
+            let mut request = client.post(&config.url).json(&body);
+            for (name, value) in &config.headers {
+                request = request.header(name, value);
+            }
+
+            let retryable = match http_trace::send(request).await {
+                Ok(response) if response.status().is_success() => {
+                    info!(
+                        rule = notification.rule,
+                        message_id = notification.id,
+                        "Sent webhook notification"
+                    );
+                    record_delivery(&notification, "sent");
+                    return;
+                }
+                Ok(response) => {
+                    warn!(
+                        rule = notification.rule,
+                        attempt,
+                        status = response.status().as_u16(),
+                        "Webhook notification was rejected"
+                    );
+                    // Anything but rate limiting or a server error won't get better by retrying
+                    response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error()
+                }
+                Err(err) => {
+                    warn!(
+                        rule = notification.rule,
+                        attempt, "Webhook notification failed: {}", err
+                    );
+                    true
+                }
+            };
+
+            if !retryable {
+                break;
+            }
+            if attempt < config.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        warn!(
+            rule = notification.rule,
+            message_id = notification.id,
+            "Giving up on webhook notification"
+        );
+        record_delivery(&notification, "failed");
+    });
+}
+
+fn record_delivery(notification: &Notification, result: &str) {
+    let mut labels = vec![
+        ("rule".to_owned(), notification.rule.clone()),
+        ("result".to_owned(), result.to_owned()),
+    ];
+    if let Some(account) = &notification.account {
+        labels.insert(0, ("account".to_owned(), account.clone()));
+    }
+    state::increment_counter("gmail_webhook_notifications_total", &labels, 1);
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use metrics_exporter_prometheus::formatting::sanitize_label_value;
+
+use crate::exposition;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone)]
+struct SeriesInfo {
+    created: f64,
+    exemplar: Option<(String, f64)>,
+}
+
+/// Bookkeeping the Prometheus recorder doesn't do for us: when each counter
+/// series first appeared, and the last message that incremented it.
+static SERIES: Mutex<Option<HashMap<String, SeriesInfo>>> = Mutex::new(None);
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+/// Note that a counter series was incremented, optionally by a specific message.
+pub fn observe(name: &str, labels: &[(String, String)], message_id: Option<&str>) {
+    let key = exposition::format_series(name, labels);
+    let now = now();
+    let mut series = SERIES.lock().unwrap();
+    let info = series
+        .get_or_insert_default()
+        .entry(key)
+        .or_insert(SeriesInfo {
+            created: now,
+            exemplar: None,
+        });
+
+    if let Some(message_id) = message_id {
+        info.exemplar = Some((message_id.to_owned(), now));
+    }
+}
+
+/// Converts the Prometheus text rendered by the recorder into OpenMetrics,
+/// adding `_created` series and exemplars for the counters we've observed.
+pub fn render(prometheus_text: &str) -> String {
+    let series = SERIES.lock().unwrap().clone().unwrap_or_default();
+
+    // Known up front, since a family's HELP comes before its TYPE
+    let counters = prometheus_text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|rest| rest.split_once(' '))
+        .filter(|(_, kind)| *kind == "counter")
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    let mut output = String::with_capacity(prometheus_text.len());
+
+    for line in prometheus_text.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (kind, name, rest) = (
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+            );
+
+            // Only counters are named without `_total` in OpenMetrics; a
+            // gauge like `gmail_drafts_total` keeps its name
+            let family = match counters.contains(name) {
+                true => name.strip_suffix("_total").unwrap_or(name),
+                false => name,
+            };
+            output.push_str(&format!("# {} {} {}\n", kind, family, rest));
+            continue;
+        }
+
+        let (series_part, value) = line.rsplit_once(' ').unwrap_or((line, ""));
+        let name = series_part.split('{').next().unwrap_or_default();
+
+        if !counters.contains(name) {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        }
+
+        let labels = &series_part[name.len()..];
+        let family = name.strip_suffix("_total").unwrap_or(name);
+        let key = if labels.is_empty() {
+            format!("{}{{}}", name)
+        } else {
+            series_part.to_owned()
+        };
+        let info = series.get(&key);
+
+        output.push_str(&format!("{}_total{} {}", family, labels, value));
+        if let Some((message_id, timestamp)) = info.and_then(|info| info.exemplar.as_ref()) {
+            output.push_str(&format!(
+                " # {{message_id=\"{}\"}} 1 {:.3}",
+                sanitize_label_value(message_id),
+                timestamp
+            ));
+        }
+        output.push('\n');
+
+        if let Some(info) = info {
+            output.push_str(&format!(
+                "{}_created{} {:.3}\n",
+                family, labels, info.created
+            ));
+        }
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_counter_families_lose_their_total_suffix() {
+        let prometheus_text = "\
+# HELP email_received_total A counter for every email received.
+# TYPE email_received_total counter
+email_received_total{from=\"a@example.com\"} 3
+# HELP gmail_drafts_total Drafts in the mailbox.
+# TYPE gmail_drafts_total gauge
+gmail_drafts_total 2
+";
+
+        let rendered = render(prometheus_text);
+
+        assert!(rendered.contains("# TYPE email_received counter\n"));
+        assert!(rendered.contains("# HELP email_received A counter"));
+        assert!(rendered.contains("email_received_total{from=\"a@example.com\"} 3\n"));
+        assert!(rendered.contains("# HELP gmail_drafts_total Drafts in the mailbox.\n"));
+        assert!(rendered.contains("# TYPE gmail_drafts_total gauge\n"));
+        assert!(rendered.contains("gmail_drafts_total 2\n"));
+        assert!(rendered.ends_with("# EOF\n"));
+    }
+}
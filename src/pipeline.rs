@@ -0,0 +1,1002 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use chrono::Timelike;
+use chrono_tz::Tz;
+use metrics::{counter, describe_counter, describe_gauge, gauge};
+use tracing::{info, warn};
+
+use crate::{
+    anomaly::ArrivalBaseline,
+    config::{LabelFilters, LabelRename, NestedLabels, QuietHours, StreamConfig, TextMatcher},
+    debug_status,
+    dmarc::Feedback,
+    duplicates::DuplicateDetector,
+    events::{EventSink, MessageEvent},
+    exposition,
+    hashing::AddressHasher,
+    language::LanguageLabels,
+    loops::LoopDetector,
+    mail::{ParseForMetrics, UsableMessageDetails},
+    mail_rules::CompiledRule,
+    mailbox_settings::{FilterInventory, MailboxSettings},
+    notify::Notification,
+    openmetrics,
+    reputation::SenderReputation,
+    risky_attachments::RiskyAttachments,
+    senders::{NewSender, SenderHistory},
+    snippets::Snippets,
+    state,
+    threads::ThreadTracker,
+    top_senders::TopSenders,
+};
+
+/// Turns poll results into metric updates, stamping every series with the
+/// labels that identify which mailbox it came from.
+pub struct MetricsPipeline {
+    pub account: Option<String>,
+    settings: RwLock<PipelineSettings>,
+    received_series: Mutex<HashSet<Vec<(String, String)>>>,
+    pub top_senders: Option<Arc<TopSenders>>,
+    /// Forwarding addresses from the last settings check, to tell when they change
+    forwarding_addresses: Mutex<Option<BTreeSet<String>>>,
+    /// Gmail filters from the last settings check, once there has been one
+    filter_inventory: OnceLock<Arc<FilterInventory>>,
+    /// Every sender seen, to count the first message from each
+    pub sender_history: Option<Arc<SenderHistory>>,
+    /// Messages seen per thread, for the thread metrics
+    pub threads: Option<Arc<ThreadTracker>>,
+    /// How much mail usually arrives in each hour of the week
+    pub arrival_baseline: Option<Arc<ArrivalBaseline>>,
+    /// Heaviest senders of mail with a `List-Unsubscribe` header
+    pub newsletter_senders: Option<Arc<TopSenders>>,
+    /// Counts messages that arrive again within its window
+    pub duplicates: Option<DuplicateDetector>,
+    /// Sorts senders into trusted, unknown and blocked; shared by every account
+    pub reputation: Option<Arc<SenderReputation>>,
+    /// Where an event for every message is published
+    pub event_sinks: Vec<Arc<dyn EventSink>>,
+    /// Log each increment instead of recording it
+    pub dry_run: bool,
+    /// Names of the mailbox's own labels, left out of `label_*` labels
+    /// unless `system_labels` is set
+    pub system_labels: HashSet<String>,
+}
+
+/// The parts of the pipeline that can be changed while it's running
+#[derive(Debug, Clone)]
+pub struct PipelineSettings {
+    /// Cap on distinct `email_received` label sets before new ones have their
+    /// per-message labels folded into `__overflow__`
+    pub max_received_series: Option<usize>,
+    /// Add `hour` and `weekday` labels to `email_received`, in `timezone`
+    pub time_labels: bool,
+    pub timezone: Tz,
+    pub label_filters: LabelFilters,
+    /// Add `from_raw_domain` and `to_raw_domain` labels with the domains as
+    /// they were, before trimming them to their registrable part
+    pub raw_domain_labels: bool,
+    /// Add a `lang` label to `email_received`
+    pub language_labels: Option<LanguageLabels>,
+    /// Also make `label_*` labels of Gmail's system labels, like UNREAD and INBOX
+    pub system_labels: bool,
+    /// `label_filters.rename`, compiled
+    pub label_renames: Vec<LabelRename>,
+    pub streams: Vec<StreamConfig>,
+    /// `[[subjects]]` from the config
+    pub subject_matchers: Vec<TextMatcher>,
+    /// `[[bodies]]` from the config
+    pub body_matchers: Vec<TextMatcher>,
+    /// `[[rules]]` from the config
+    pub rules: Vec<CompiledRule>,
+    /// `[risky_attachments]` from the config
+    pub risky_attachments: Option<RiskyAttachments>,
+    /// `[loop_detection]` from the config, shared by the settings' clones
+    pub loop_detection: Option<Arc<LoopDetector>>,
+    /// `[quiet_hours]` from the config
+    pub quiet_hours: Option<QuietHours>,
+    /// `[snippets]` from the config
+    pub snippets: Option<Snippets>,
+    /// Hash the addresses in `email_received` and sender gauge labels
+    pub hash_addresses: Option<AddressHasher>,
+}
+
+impl PipelineSettings {
+    /// The names a Gmail label is exposed as: truncated to `labels.max_depth`,
+    /// exploded into its parents if `labels.nested = "explode"`, and then
+    /// renamed by `[[labels.rename]]`
+    pub fn label_names(&self, label: &str) -> Vec<String> {
+        let filters = &self.label_filters;
+        let mut levels = label.split('/').collect::<Vec<_>>();
+        if let Some(max_depth) = filters.max_depth {
+            levels.truncate(max_depth.max(1));
+        }
+
+        let names = match filters.nested {
+            NestedLabels::Leaf => vec![levels.join("/")],
+            NestedLabels::Explode => (1..=levels.len())
+                .map(|depth| levels[..depth].join("/"))
+                .collect(),
+        };
+
+        names
+            .into_iter()
+            .map(|name| {
+                self.label_renames
+                    .iter()
+                    .find_map(|rename| rename.apply(&name))
+                    .unwrap_or(name)
+            })
+            .collect()
+    }
+}
+
+pub const OVERFLOW_VALUE: &str = "__overflow__";
+
+/// `email_received` labels that vary with who a message is from or to, what
+/// it's labelled, or when it arrived, rather than with the mailbox
+fn is_per_message_label(key: &str) -> bool {
+    matches!(
+        key,
+        "from"
+            | "to"
+            | "from_domain"
+            | "to_domain"
+            | "from_raw_domain"
+            | "to_raw_domain"
+            | "lang"
+            | "hour"
+            | "weekday"
+    ) || key.starts_with("label_")
+}
+
+fn describe_rule_metrics(settings: &PipelineSettings) {
+    for rule in &settings.rules {
+        if let (Some(metric), Some(help)) = (&rule.metric, &rule.help) {
+            describe_counter!(metric.clone(), help.clone());
+        }
+    }
+}
+
+impl MetricsPipeline {
+    pub fn new(account: Option<String>, settings: PipelineSettings) -> Self {
+        debug_status::record_settings(account.as_deref(), &settings);
+        describe_rule_metrics(&settings);
+        Self {
+            account,
+            settings: RwLock::new(settings),
+            received_series: Mutex::new(HashSet::new()),
+            top_senders: None,
+            newsletter_senders: None,
+            forwarding_addresses: Mutex::new(None),
+            filter_inventory: OnceLock::new(),
+            sender_history: None,
+            threads: None,
+            arrival_baseline: None,
+            duplicates: None,
+            reputation: None,
+            event_sinks: vec![],
+            dry_run: false,
+            system_labels: HashSet::new(),
+        }
+    }
+
+    pub fn set_settings(&self, settings: PipelineSettings) {
+        debug_status::record_settings(self.account.as_deref(), &settings);
+        describe_rule_metrics(&settings);
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// Track the heaviest senders over `window`, exposing only the top `top_n`.
+    pub fn with_top_senders(mut self, top_n: usize, window: std::time::Duration) -> Self {
+        let top_senders = Arc::new(TopSenders::new(top_n, window, self.base_labels()));
+        exposition::register_collector(top_senders.clone());
+        debug_status::register_top_senders(self.account.as_deref(), top_senders.clone());
+        self.top_senders = Some(top_senders);
+        self
+    }
+
+    /// Score each hour's arrivals against the same hour in past weeks, in `timezone`
+    pub fn with_arrival_baseline(mut self, timezone: Tz) -> Self {
+        let arrival_baseline = Arc::new(ArrivalBaseline::new(timezone, self.base_labels()));
+        exposition::register_collector(arrival_baseline.clone());
+        self.arrival_baseline = Some(arrival_baseline);
+        self
+    }
+
+    /// Likewise for senders of newsletters, exposed as `gmail_newsletter_sender_messages`
+    pub fn with_newsletter_senders(mut self, top_n: usize, window: std::time::Duration) -> Self {
+        let newsletter_senders = Arc::new(
+            TopSenders::new(top_n, window, self.base_labels()).with_metric(
+                "gmail_newsletter_sender_messages",
+                "Approximate messages from each of the heaviest newsletter senders over the rolling window.",
+            ),
+        );
+        exposition::register_collector(newsletter_senders.clone());
+        self.newsletter_senders = Some(newsletter_senders);
+        self
+    }
+
+    pub fn describe() {
+        describe_counter!("email_received", "A counter for every email received.");
+        describe_counter!(
+            "email_polls",
+            "A counter for every time we checked for emails."
+        );
+        describe_counter!(
+            "gmail_exporter_heartbeat_total",
+            "Polls that got through; stops increasing when the exporter is stuck."
+        );
+        describe_counter!(
+            "email_received_overflowed",
+            "Emails whose per-message labels were recorded as __overflow__ because the series limit was reached."
+        );
+        describe_gauge!(
+            "gmail_last_poll_success_timestamp_seconds",
+            "Unix time of the last poll that completed successfully."
+        );
+        describe_counter!(
+            "gmail_poll_failures_total",
+            "Polls that failed, e.g. on an API error."
+        );
+        describe_gauge!(
+            "gmail_poll_failing",
+            "1 while polls are failing, 0 once one succeeds again."
+        );
+        describe_gauge!(
+            "gmail_inbox_unread_messages",
+            "Unread messages currently in the inbox."
+        );
+        describe_counter!(
+            "gmail_api_errors_total",
+            "Error responses from the Gmail API and token endpoint, by reason."
+        );
+        describe_counter!(
+            "gmail_auth_refreshes_total",
+            "Times the Gmail access token had to be refreshed."
+        );
+        describe_counter!(
+            "gmail_history_resets_total",
+            "Times the saved history id had expired and watching restarted from the current one."
+        );
+        describe_counter!(
+            "email_starred_total",
+            "Messages starred after they arrived."
+        );
+        describe_counter!(
+            "email_marked_important_total",
+            "Messages marked important after they arrived, by hand or by Gmail."
+        );
+        describe_gauge!(
+            "gmail_history_lag",
+            "How many history ids the latest mailbox change is ahead of where the exporter has got to."
+        );
+        describe_gauge!("gmail_starred_messages", "Messages currently starred.");
+        describe_gauge!("gmail_drafts_total", "Drafts in the mailbox.");
+        describe_gauge!(
+            "gmail_stale_drafts",
+            "Drafts last saved longer ago than --stale-draft-age."
+        );
+        describe_gauge!("gmail_spam_messages", "Messages in the spam folder.");
+        describe_counter!(
+            "gmail_spam_received_total",
+            "Messages that arrived in the spam folder or were reported as spam."
+        );
+        describe_gauge!("gmail_trash_messages", "Messages in the trash.");
+        describe_counter!(
+            "gmail_trashed_total",
+            "Messages that arrived in the trash or were moved there."
+        );
+        describe_counter!(
+            "gmail_messages_deleted_total",
+            "Messages deleted for good, whether skipping the trash or emptied out of it."
+        );
+        describe_gauge!(
+            "gmail_inbox_age_bucket",
+            "Messages in the inbox received longer ago than older_than, as of the last --inbox-age-interval check."
+        );
+        describe_gauge!(
+            "gmail_vacation_responder_enabled",
+            "1 if the vacation auto-responder is turned on, else 0."
+        );
+        describe_gauge!(
+            "gmail_forwarding_addresses",
+            "Addresses the mailbox can forward mail to, verified or not."
+        );
+        describe_counter!(
+            "gmail_forwarding_address_changes_total",
+            "Times the forwarding addresses differed from the previous settings check."
+        );
+        describe_gauge!(
+            "gmail_filters_total",
+            "Gmail filters set up on the mailbox."
+        );
+        describe_gauge!(
+            "gmail_send_as_aliases",
+            "Addresses the mailbox can send as besides its own."
+        );
+        describe_counter!(
+            "gmail_watch_renewals_total",
+            "Times the Gmail watch on the Pub/Sub topic was registered or renewed."
+        );
+        describe_gauge!(
+            "gmail_watch_expiration_timestamp_seconds",
+            "Unix time the Gmail watch on the Pub/Sub topic expires unless renewed."
+        );
+        describe_counter!(
+            "email_received_by_category_total",
+            "Emails received, by the Gmail inbox tab they were sorted into."
+        );
+        describe_counter!(
+            "gmail_webhook_notifications_total",
+            "Webhook notifications sent for rules with a notify action, by result."
+        );
+        describe_counter!(
+            "email_received_by_stream_total",
+            "Emails received that match a configured stream's search query."
+        );
+        describe_counter!(
+            "email_new_sender_total",
+            "Emails that were the first from their sender's address or domain, by kind."
+        );
+        describe_counter!(
+            "email_threads_started_total",
+            "Conversations started, i.e. messages in threads not seen before."
+        );
+        describe_counter!(
+            "email_thread_messages_total",
+            "Emails received, by how many messages their thread had with them."
+        );
+        describe_counter!(
+            "email_duplicates_total",
+            "Emails received again, with the same Message-ID, within the duplicate window."
+        );
+        describe_counter!(
+            "email_received_by_reputation_total",
+            "Emails received, by whether the sender is on the trusted list, the blocked list or neither."
+        );
+        describe_counter!(
+            "email_subject_match_total",
+            "Emails received whose subject matches a configured pattern."
+        );
+        describe_counter!(
+            "email_body_match_total",
+            "Emails received whose body matches a configured pattern."
+        );
+        describe_counter!(
+            "email_risky_attachment_total",
+            "Attachments of a type on the risky attachment watchlist, by extension or MIME type."
+        );
+        describe_counter!(
+            "email_possible_loop_total",
+            "Messages that look like part of a mail loop or autoresponder storm: too many in one thread, or from one sender with the same subject, within the loop detection window."
+        );
+        describe_counter!(
+            "email_received_with_unsubscribe_total",
+            "Emails received with a List-Unsubscribe header, i.e. newsletters and other bulk mail."
+        );
+        describe_counter!(
+            "dmarc_messages",
+            "Messages covered by received DMARC aggregate reports, by how they were evaluated."
+        );
+    }
+
+    fn base_labels(&self) -> Vec<(String, String)> {
+        match &self.account {
+            Some(account) => vec![("account".to_owned(), account.clone())],
+            None => vec![],
+        }
+    }
+
+    fn increment(&self, name: &str, labels: &[(String, String)], message_id: Option<&str>) {
+        self.increment_by(name, labels, message_id, 1);
+    }
+
+    fn increment_by(
+        &self,
+        name: &str,
+        labels: &[(String, String)],
+        message_id: Option<&str>,
+        value: u64,
+    ) {
+        if self.dry_run {
+            info!(
+                metric = name,
+                ?labels,
+                ?message_id,
+                "dry run: would increment by {}",
+                value
+            );
+            return;
+        }
+
+        counter!(name.to_owned(), value, labels);
+        openmetrics::observe(name, labels, message_id);
+        state::record_counter(name, labels, value);
+    }
+
+    pub fn record_poll(&self) {
+        self.increment("email_polls", &self.base_labels(), None);
+        self.set_gauge(
+            "gmail_last_poll_success_timestamp_seconds",
+            chrono::Utc::now().timestamp() as f64,
+        );
+    }
+
+    /// After every poll that got through, for a dead-man-switch alert
+    pub fn record_heartbeat(&self) {
+        self.increment("gmail_exporter_heartbeat_total", &self.base_labels(), None);
+    }
+
+    /// Every failed poll is counted; the gauge stays at 1 until a poll succeeds
+    pub fn record_poll_failing(&self, failing: bool) {
+        if failing {
+            self.increment("gmail_poll_failures_total", &self.base_labels(), None);
+        }
+        self.set_gauge("gmail_poll_failing", if failing { 1.0 } else { 0.0 });
+    }
+
+    pub fn record_history_reset(&self) {
+        self.increment("gmail_history_resets_total", &self.base_labels(), None);
+    }
+
+    pub fn record_watch_renewal(&self, expires_at: chrono::DateTime<chrono::Utc>) {
+        self.increment("gmail_watch_renewals_total", &self.base_labels(), None);
+        self.set_gauge(
+            "gmail_watch_expiration_timestamp_seconds",
+            expires_at.timestamp() as f64,
+        );
+    }
+
+    /// Counts starring a message or marking it important after it arrived
+    pub fn record_label_added(&self, label: &str) {
+        match label {
+            "STARRED" => self.increment("email_starred_total", &self.base_labels(), None),
+            "IMPORTANT" => {
+                self.increment("email_marked_important_total", &self.base_labels(), None)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_history_lag(&self, lag: u64) {
+        self.set_gauge("gmail_history_lag", lag as f64);
+    }
+
+    pub fn record_starred_total(&self, starred: u64) {
+        self.set_gauge("gmail_starred_messages", starred as f64);
+    }
+
+    pub fn record_drafts(&self, drafts: u64) {
+        self.set_gauge("gmail_drafts_total", drafts as f64);
+    }
+
+    pub fn record_stale_drafts(&self, stale: u64) {
+        self.set_gauge("gmail_stale_drafts", stale as f64);
+    }
+
+    pub fn record_spam_total(&self, spam: u64) {
+        self.set_gauge("gmail_spam_messages", spam as f64);
+    }
+
+    /// A message that arrived in spam, or was moved there after it arrived
+    pub fn record_spam_received(&self, message_id: Option<&str>) {
+        self.increment("gmail_spam_received_total", &self.base_labels(), message_id);
+    }
+
+    pub fn record_trash_total(&self, trash: u64) {
+        self.set_gauge("gmail_trash_messages", trash as f64);
+    }
+
+    /// A message that arrived in the trash, or was moved there after it arrived
+    pub fn record_trashed(&self, message_id: Option<&str>) {
+        self.increment("gmail_trashed_total", &self.base_labels(), message_id);
+    }
+
+    pub fn record_messages_deleted(&self, deleted: u64) {
+        if deleted > 0 {
+            self.increment_by(
+                "gmail_messages_deleted_total",
+                &self.base_labels(),
+                None,
+                deleted,
+            );
+        }
+    }
+
+    /// `older_than` is the bucket's age as written in its label, e.g. `7d`
+    pub fn record_inbox_age(&self, older_than: &str, messages: u64) {
+        let mut labels = self.base_labels();
+        labels.push(("older_than".to_owned(), older_than.to_owned()));
+        self.set_gauge_with("gmail_inbox_age_bucket", &labels, messages as f64);
+    }
+
+    pub fn record_mailbox_settings(&self, settings: MailboxSettings) {
+        self.set_gauge(
+            "gmail_vacation_responder_enabled",
+            if settings.vacation_responder {
+                1.0
+            } else {
+                0.0
+            },
+        );
+        self.set_gauge("gmail_send_as_aliases", settings.send_as_aliases as f64);
+        self.set_gauge(
+            "gmail_forwarding_addresses",
+            settings.forwarding_addresses.len() as f64,
+        );
+        self.set_gauge("gmail_filters_total", settings.filters.len() as f64);
+
+        // The first check is only a baseline to compare later ones against
+        let forwarding_addresses = settings
+            .forwarding_addresses
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let previous = self
+            .forwarding_addresses
+            .lock()
+            .unwrap()
+            .replace(forwarding_addresses.clone());
+        if previous.is_some_and(|previous| previous != forwarding_addresses) {
+            warn!(?forwarding_addresses, "Gmail forwarding addresses changed");
+            self.increment(
+                "gmail_forwarding_address_changes_total",
+                &self.base_labels(),
+                None,
+            );
+        }
+
+        if self.dry_run {
+            info!(filters = ?settings.filters, "dry run: would expose filters");
+            return;
+        }
+        self.filter_inventory
+            .get_or_init(|| {
+                let filter_inventory = Arc::new(FilterInventory::new(self.base_labels()));
+                exposition::register_collector(filter_inventory.clone());
+                filter_inventory
+            })
+            .set(settings.filters);
+    }
+
+    pub fn record_inbox_unread(&self, unread: u64) {
+        self.set_gauge("gmail_inbox_unread_messages", unread as f64);
+    }
+
+    fn set_gauge(&self, name: &'static str, value: f64) {
+        self.set_gauge_with(name, &self.base_labels(), value);
+    }
+
+    fn set_gauge_with(&self, name: &'static str, labels: &[(String, String)], value: f64) {
+        if self.dry_run {
+            info!(metric = name, ?labels, value, "dry run: would set gauge");
+            return;
+        }
+
+        gauge!(name, value, labels);
+    }
+
+    /// `delay` stretched to the quiet hours interval while they're on
+    pub fn poll_delay(&self, delay: std::time::Duration) -> std::time::Duration {
+        let settings = self.settings.read().unwrap();
+        match &settings.quiet_hours {
+            Some(quiet_hours)
+                if quiet_hours
+                    .contains(chrono::Utc::now().with_timezone(&settings.timezone).time()) =>
+            {
+                delay.max(quiet_hours.interval)
+            }
+            _ => delay,
+        }
+    }
+
+    pub fn streams(&self) -> Vec<StreamConfig> {
+        self.settings.read().unwrap().streams.clone()
+    }
+
+    pub fn record_stream_message(&self, stream: &str, message: &UsableMessageDetails) {
+        let mut labels = self.base_labels();
+        labels.push(("stream".to_owned(), stream.to_owned()));
+        self.increment("email_received_by_stream_total", &labels, Some(&message.id));
+    }
+
+    /// Counts the messages a DMARC aggregate report (itself in `message_id`) covers
+    pub fn record_dmarc_report(&self, report: &Feedback, message_id: &str) {
+        for record in &report.record {
+            let row = &record.row;
+            let mut labels = self.base_labels();
+            labels.extend([
+                ("source_ip".to_owned(), row.source_ip.clone()),
+                (
+                    "disposition".to_owned(),
+                    row.policy_evaluated.disposition.clone(),
+                ),
+                ("dkim".to_owned(), row.policy_evaluated.dkim.clone()),
+                ("spf".to_owned(), row.policy_evaluated.spf.clone()),
+            ]);
+            self.increment_by("dmarc_messages", &labels, Some(message_id), row.count);
+        }
+    }
+
+    pub fn record_message(&self, message: &UsableMessageDetails) {
+        if let Some(category) = message.category() {
+            let mut labels = self.base_labels();
+            labels.push(("category".to_owned(), category.to_owned()));
+            self.increment(
+                "email_received_by_category_total",
+                &labels,
+                Some(&message.id),
+            );
+        }
+
+        let settings = self.settings.read().unwrap().clone();
+        let snippet = settings
+            .snippets
+            .as_ref()
+            .map(|snippets| snippets.preview(&message.snippet));
+
+        if let Some(duplicates) = &self.duplicates {
+            if duplicates.is_duplicate(message) {
+                self.increment(
+                    "email_duplicates_total",
+                    &self.base_labels(),
+                    Some(&message.id),
+                );
+            }
+        }
+
+        for matcher in &settings.subject_matchers {
+            if matcher.matches(&message.subject) {
+                let mut labels = self.base_labels();
+                labels.push(("pattern_name".to_owned(), matcher.name.clone()));
+                self.increment("email_subject_match_total", &labels, Some(&message.id));
+            }
+        }
+
+        if let Some(body) = &message.body {
+            for matcher in &settings.body_matchers {
+                if matcher.matches(body) {
+                    let mut labels = self.base_labels();
+                    labels.push(("pattern_name".to_owned(), matcher.name.clone()));
+                    self.increment("email_body_match_total", &labels, Some(&message.id));
+                }
+            }
+        }
+
+        if let Some(risky_attachments) = &settings.risky_attachments {
+            let risky_types = risky_attachments.check(message);
+            for risky_type in &risky_types {
+                let mut labels = self.base_labels();
+                labels.push(("type".to_owned(), risky_type.clone()));
+                self.increment("email_risky_attachment_total", &labels, Some(&message.id));
+            }
+
+            if let (false, Some(notifier)) = (risky_types.is_empty(), &risky_attachments.notify) {
+                notifier.notify(
+                    Notification::new(
+                        "risky_attachment",
+                        self.account.as_deref(),
+                        message,
+                        snippet.clone(),
+                    ),
+                    self.dry_run,
+                );
+            }
+        }
+
+        if let Some(loop_detection) = &settings.loop_detection {
+            for loop_match in loop_detection.observe(message) {
+                let mut labels = self.base_labels();
+                labels.push(("reason".to_owned(), loop_match.reason.as_str().to_owned()));
+                self.increment("email_possible_loop_total", &labels, Some(&message.id));
+
+                if let (true, Some(notifier)) = (loop_match.first, &loop_detection.notify) {
+                    notifier.notify(
+                        Notification::new(
+                            "possible_loop",
+                            self.account.as_deref(),
+                            message,
+                            snippet.clone(),
+                        ),
+                        self.dry_run,
+                    );
+                }
+            }
+        }
+
+        let new_sender = self.observe_sender(&settings, message);
+
+        for rule in &settings.rules {
+            let Some(rule_labels) = rule.evaluate(message, new_sender) else {
+                continue;
+            };
+
+            if let Some(metric) = &rule.metric {
+                let mut labels = self.base_labels();
+                labels.extend(rule_labels);
+                self.increment(metric, &labels, Some(&message.id));
+            }
+
+            if let Some(notifier) = &rule.notify {
+                notifier.notify(
+                    Notification::new(
+                        &rule.name,
+                        self.account.as_deref(),
+                        message,
+                        snippet.clone(),
+                    ),
+                    self.dry_run,
+                );
+            }
+        }
+
+        let mut message_labels = message.as_labels();
+        if settings.raw_domain_labels {
+            message_labels.extend([
+                (
+                    "from_raw_domain".to_owned(),
+                    message.from.first_domain().unwrap_or("unknown".to_string()),
+                ),
+                (
+                    "to_raw_domain".to_owned(),
+                    message.to.first_domain().unwrap_or("unknown".to_string()),
+                ),
+            ]);
+        }
+
+        let mut labels = self.base_labels();
+        labels.extend(
+            message_labels
+                .into_iter()
+                .flat_map(|(key, value)| match key.strip_prefix("label_") {
+                    Some(label)
+                        if !settings.system_labels && self.system_labels.contains(label) =>
+                    {
+                        vec![]
+                    }
+                    Some(label) => settings
+                        .label_names(label)
+                        .into_iter()
+                        .map(|name| (format!("label_{}", name), value.clone()))
+                        .collect(),
+                    None => vec![(key, value)],
+                })
+                .filter(|(key, _)| {
+                    key.strip_prefix("label_")
+                        .is_none_or(|label| settings.label_filters.allows(label))
+                })
+                .map(
+                    |(key, value)| match (&settings.hash_addresses, key.as_str()) {
+                        (Some(hasher), "from" | "to") => (key, hasher.address(&value)),
+                        (
+                            Some(hasher),
+                            "from_domain" | "to_domain" | "from_raw_domain" | "to_raw_domain",
+                        ) => (key, hasher.domain(&value)),
+                        _ => (key, value),
+                    },
+                ),
+        );
+
+        if let Some(language_labels) = &settings.language_labels {
+            let lang = language_labels.label(&message.subject, message.body.as_deref());
+            labels.push(("lang".to_owned(), lang.to_owned()));
+        }
+
+        if settings.time_labels {
+            let local = message.internal_date.with_timezone(&settings.timezone);
+            labels.push(("hour".to_owned(), local.hour().to_string()));
+            labels.push(("weekday".to_owned(), local.format("%a").to_string()));
+        }
+
+        if let Some(reputation) = &self.reputation {
+            let reputation = reputation
+                .classify(&message.from.first_address().unwrap_or_default())
+                .as_str()
+                .to_owned();
+            let mut reputation_labels = self.base_labels();
+            reputation_labels.push(("reputation".to_owned(), reputation.clone()));
+            self.increment(
+                "email_received_by_reputation_total",
+                &reputation_labels,
+                Some(&message.id),
+            );
+            labels.push(("reputation".to_owned(), reputation));
+        }
+
+        // Renamed and exploded labels can collide
+        let mut seen = HashSet::new();
+        labels.retain(|(key, _)| seen.insert(key.clone()));
+
+        let labels = self.limit_received_series(labels, settings.max_received_series);
+
+        if !self.event_sinks.is_empty() {
+            let event = MessageEvent::new(self.account.as_deref(), message, snippet);
+            for sink in &self.event_sinks {
+                sink.publish(&event);
+            }
+        }
+
+        let mut sender = message
+            .from
+            .first_address()
+            .unwrap_or("unknown".to_string());
+        if let Some(hasher) = &settings.hash_addresses {
+            sender = hasher.address(&sender);
+        }
+        if let Some(top_senders) = &self.top_senders {
+            top_senders.observe(&sender);
+        }
+        if let Some(arrival_baseline) = &self.arrival_baseline {
+            arrival_baseline.observe();
+        }
+        if let Some(threads) = &self.threads {
+            let thread = threads.observe(&message.thread_id);
+            if thread.started {
+                self.increment(
+                    "email_threads_started_total",
+                    &self.base_labels(),
+                    Some(&message.id),
+                );
+            }
+            let mut labels = self.base_labels();
+            labels.push(("thread_bucket".to_owned(), thread.bucket.to_owned()));
+            self.increment("email_thread_messages_total", &labels, Some(&message.id));
+        }
+
+        if message.list_unsubscribe {
+            self.increment(
+                "email_received_with_unsubscribe_total",
+                &self.base_labels(),
+                Some(&message.id),
+            );
+            if let Some(newsletter_senders) = &self.newsletter_senders {
+                newsletter_senders.observe(&sender);
+            }
+        }
+
+        self.increment("email_received", &labels, Some(&message.id));
+    }
+
+    /// Counts the first message from each address and domain, with --new-senders
+    fn observe_sender(
+        &self,
+        settings: &PipelineSettings,
+        message: &UsableMessageDetails,
+    ) -> NewSender {
+        let Some(sender_history) = &self.sender_history else {
+            return NewSender::default();
+        };
+
+        let mut address = message
+            .from
+            .first_address()
+            .unwrap_or("unknown".to_string());
+        let mut domain = message
+            .from
+            .first_registrable_domain()
+            .unwrap_or("unknown".to_string());
+        if let Some(hasher) = &settings.hash_addresses {
+            address = hasher.address(&address);
+            domain = hasher.domain(&domain);
+        }
+
+        let new_sender = sender_history.observe(&address, &domain);
+        for (kind, new) in [
+            ("address", new_sender.address),
+            ("domain", new_sender.domain),
+        ] {
+            if new {
+                let mut labels = self.base_labels();
+                labels.push(("kind".to_owned(), kind.to_owned()));
+                self.increment("email_new_sender_total", &labels, Some(&message.id));
+            }
+        }
+        new_sender
+    }
+
+    /// Once the series cap is hit, any label set we haven't seen before has
+    /// its per-message labels collapsed (and `label_*` ones dropped), so a
+    /// spam storm can't create unbounded series.
+    fn limit_received_series(
+        &self,
+        mut labels: Vec<(String, String)>,
+        max_received_series: Option<usize>,
+    ) -> Vec<(String, String)> {
+        let Some(max_series) = max_received_series else {
+            return labels;
+        };
+
+        let mut received_series = self.received_series.lock().unwrap();
+        if received_series.contains(&labels) {
+            return labels;
+        }
+        if received_series.len() < max_series {
+            received_series.insert(labels.clone());
+            return labels;
+        }
+
+        labels.retain(|(key, _)| !key.starts_with("label_"));
+        for (key, value) in labels.iter_mut() {
+            if is_per_message_label(key) {
+                *value = OVERFLOW_VALUE.to_owned();
+            }
+        }
+        self.increment("email_received_overflowed", &self.base_labels(), None);
+        labels
+    }
+
+    /// Counts the `email_received` series restored from a state file towards
+    /// the series cap
+    pub fn restore_received_series(&self, counters: &[state::CounterSnapshot]) {
+        self.received_series.lock().unwrap().extend(
+            counters
+                .iter()
+                .filter(|counter| counter.name == "email_received")
+                .map(|counter| counter.labels.clone()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline() -> MetricsPipeline {
+        MetricsPipeline::new(
+            None,
+            PipelineSettings {
+                max_received_series: Some(1),
+                time_labels: false,
+                timezone: chrono_tz::UTC,
+                label_filters: Default::default(),
+                raw_domain_labels: false,
+                language_labels: None,
+                system_labels: false,
+                label_renames: vec![],
+                streams: vec![],
+                subject_matchers: vec![],
+                body_matchers: vec![],
+                rules: vec![],
+                risky_attachments: None,
+                loop_detection: None,
+                quiet_hours: None,
+                snippets: None,
+                hash_addresses: None,
+            },
+        )
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn limit_received_series_folds_new_label_sets_past_the_cap() {
+        let pipeline = pipeline();
+        let first = labels(&[("from", "a@example.com"), ("label_Receipts", "true")]);
+        let second = labels(&[
+            ("account", "me"),
+            ("from", "b@example.com"),
+            ("label_Receipts", "true"),
+        ]);
+
+        assert_eq!(
+            pipeline.limit_received_series(first.clone(), Some(1)),
+            first
+        );
+        assert_eq!(
+            pipeline.limit_received_series(second, Some(1)),
+            labels(&[("account", "me"), ("from", OVERFLOW_VALUE)])
+        );
+        // A label set seen before the cap was hit keeps its labels
+        assert_eq!(
+            pipeline.limit_received_series(first.clone(), Some(1)),
+            first
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::{Notify, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::{http_trace, mail::WatchResponse};
+
+const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+/// Don't let pushes with made-up key IDs make us refetch Google's certs on every request
+const MIN_CERTS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Args)]
+#[command(group(
+    ArgGroup::new("pubsub_verification")
+        .args(["pubsub_push_token", "pubsub_push_audience"])
+        .multiple(true)
+))]
+pub struct PubSubOptions {
+    /// Register a Gmail watch on this Pub/Sub topic (`projects/<project>/topics/<topic>`)
+    /// and poll as soon as a push delivery arrives on /pubsub/push
+    #[arg(
+        long,
+        env = "PUBSUB_TOPIC",
+        requires = "pubsub_verification",
+        conflicts_with_all = ["once", "dry_run", "poll_on_scrape"]
+    )]
+    pub pubsub_topic: Option<String>,
+
+    /// Accept push deliveries whose `token` query parameter matches this
+    #[arg(long, env = "PUBSUB_PUSH_TOKEN")]
+    pub pubsub_push_token: Option<String>,
+
+    /// Accept push deliveries carrying a Google-signed OIDC token for this audience
+    #[arg(long, env = "PUBSUB_PUSH_AUDIENCE")]
+    pub pubsub_push_audience: Option<String>,
+
+    /// Also require the OIDC token to have been issued to this service account
+    #[arg(
+        long,
+        env = "PUBSUB_PUSH_SERVICE_ACCOUNT",
+        requires = "pubsub_push_audience"
+    )]
+    pub pubsub_push_service_account: Option<String>,
+
+    /// Renew the Gmail watch this many seconds before it expires. Gmail drops
+    /// a watch after 7 days, and Google recommends renewing daily, which the
+    /// default of 6 days does.
+    #[arg(long, env = "PUBSUB_RENEWAL_LEAD", default_value_t = 6 * 24 * 60 * 60)]
+    pub pubsub_renewal_lead: u64,
+}
+
+/// The watcher's side of push mode: keeps the Gmail watch registered and
+/// gets woken up early whenever a push delivery arrives.
+pub struct PubSubWatch {
+    pub topic: String,
+    pub wake: Arc<Notify>,
+    renewal_lead: Duration,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl PubSubWatch {
+    pub fn new(topic: String, wake: Arc<Notify>, renewal_lead: Duration) -> Self {
+        Self {
+            topic,
+            wake,
+            renewal_lead,
+            expires_at: None,
+        }
+    }
+
+    pub fn needs_renewal(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| Utc::now() + self.renewal_lead >= expires_at)
+    }
+
+    /// Returns when the watch now expires. An expiration Gmail didn't give in
+    /// milliseconds is taken as already passed, so the next poll renews again.
+    pub fn renewed(&mut self, watch: &WatchResponse) -> DateTime<Utc> {
+        let expires_at = watch
+            .expiration
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_else(Utc::now);
+        self.expires_at = Some(expires_at);
+        expires_at
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PushEnvelope {
+    message: PushMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushMessage {
+    data: String,
+    #[serde(rename = "messageId")]
+    message_id: String,
+}
+
+/// What Gmail publishes for every mailbox change
+#[derive(Debug, Deserialize)]
+struct GmailNotification {
+    #[serde(rename = "emailAddress")]
+    email_address: String,
+    #[serde(rename = "historyId")]
+    history_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushClaims {
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(Clone)]
+struct PushState {
+    options: Arc<PubSubOptions>,
+    wake: Arc<Notify>,
+    certs: Arc<GoogleCerts>,
+}
+
+/// Routes for receiving push deliveries. These do their own verification, so
+/// they're mounted outside the metrics basic auth.
+pub fn router(options: PubSubOptions, wake: Arc<Notify>) -> Router {
+    let state = PushState {
+        options: Arc::new(options),
+        wake,
+        certs: Arc::new(GoogleCerts::default()),
+    };
+
+    Router::new()
+        .route("/pubsub/push", post(receive_push))
+        .with_state(state)
+}
+
+async fn receive_push(
+    State(state): State<PushState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(envelope): Json<PushEnvelope>,
+) -> StatusCode {
+    if let Err((status, reason)) = verify_push(&state, &query, &headers).await {
+        warn!("Rejected Pub/Sub push: {}", reason);
+        return status;
+    }
+
+    match STANDARD
+        .decode(&envelope.message.data)
+        .map_err(|err| err.to_string())
+        .and_then(|data| {
+            serde_json::from_slice::<GmailNotification>(&data).map_err(|err| err.to_string())
+        }) {
+        Ok(notification) => debug!(
+            message_id = envelope.message.message_id,
+            email_address = notification.email_address,
+            history_id = notification.history_id,
+            "Gmail push notification"
+        ),
+        // Still worth polling; we only use the notification as a nudge
+        Err(err) => warn!("Couldn't decode Pub/Sub message data: {}", err),
+    }
+
+    state.wake.notify_one();
+    StatusCode::NO_CONTENT
+}
+
+/// Why a push was turned away: 401 for a bad token, or 503 when Google's
+/// signing keys couldn't be fetched, so Pub/Sub redelivers it later
+type Rejection = (StatusCode, String);
+
+fn unauthorized(reason: impl Into<String>) -> Rejection {
+    (StatusCode::UNAUTHORIZED, reason.into())
+}
+
+async fn verify_push(
+    state: &PushState,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<(), Rejection> {
+    if let Some(expected) = &state.options.pubsub_push_token {
+        let matches = query
+            .get("token")
+            .is_some_and(|token| token.as_bytes().ct_eq(expected.as_bytes()).into());
+        if !matches {
+            return Err(unauthorized("missing or wrong token query parameter"));
+        }
+    }
+
+    if let Some(audience) = &state.options.pubsub_push_audience {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("missing bearer token"))?;
+
+        let claims = state.certs.verify(token, audience).await?;
+
+        if let Some(service_account) = &state.options.pubsub_push_service_account {
+            if claims.email.as_ref() != Some(service_account) || claims.email_verified != Some(true)
+            {
+                return Err(unauthorized(format!(
+                    "token was issued to {:?}, not {}",
+                    claims.email, service_account
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Google's OIDC signing keys, fetched on demand and refetched when a token
+/// names a key we haven't seen (Google rotates them regularly).
+#[derive(Default)]
+struct GoogleCerts {
+    keys: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+impl GoogleCerts {
+    async fn verify(&self, token: &str, audience: &str) -> Result<PushClaims, Rejection> {
+        let kid = jsonwebtoken::decode_header(token)
+            .map_err(|err| unauthorized(format!("malformed token: {}", err)))?
+            .kid
+            .ok_or_else(|| unauthorized("token has no key id"))?;
+
+        let key = self.find_key(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[audience]);
+        validation.set_issuer(&GOOGLE_ISSUERS);
+
+        jsonwebtoken::decode::<PushClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| unauthorized(format!("invalid token: {}", err)))
+    }
+
+    async fn find_key(&self, kid: &str) -> Result<DecodingKey, Rejection> {
+        if let Some((certs, _)) = &*self.keys.read().await {
+            if let Some(jwk) = certs.find(kid) {
+                return DecodingKey::from_jwk(jwk).map_err(|err| unauthorized(err.to_string()));
+            }
+        }
+
+        let mut keys = self.keys.write().await;
+        let recently_fetched = keys
+            .as_ref()
+            .is_some_and(|(_, fetched_at)| fetched_at.elapsed() < MIN_CERTS_REFRESH_INTERVAL);
+        if !recently_fetched {
+            info!("Fetching Google OIDC signing keys");
+            let unavailable = |reason: String| (StatusCode::SERVICE_UNAVAILABLE, reason);
+            let json = http_trace::try_send_json(http_trace::client().get(GOOGLE_CERTS_URL))
+                .await
+                .map_err(|err| {
+                    unavailable(format!("couldn't fetch Google's signing keys: {}", err))
+                })?;
+            let certs = serde_json::from_value::<JwkSet>(json).map_err(|err| {
+                unavailable(format!("couldn't parse Google's signing keys: {}", err))
+            })?;
+            *keys = Some((certs, Instant::now()));
+        }
+
+        keys.as_ref()
+            .and_then(|(certs, _)| certs.find(kid))
+            .ok_or_else(|| unauthorized(format!("unknown signing key {}", kid)))
+            .and_then(|jwk| DecodingKey::from_jwk(jwk).map_err(|err| unauthorized(err.to_string())))
+    }
+}
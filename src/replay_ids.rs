@@ -0,0 +1,93 @@
+use std::{collections::HashSet, path::Path};
+
+use async_trait::async_trait;
+
+use crate::{
+    backend::MailBackend,
+    error::Result,
+    mail::{Attachment, MinimalMessage, UsableMessageDetails},
+};
+
+/// Reports a fixed list of messages as the mailbox's only change, for
+/// `replay-ids`. Their details and attachments still come from the real
+/// mailbox; nothing else about it is looked at, so the metrics depend on the
+/// messages alone.
+pub struct IdReplayBackend {
+    inner: Box<dyn MailBackend>,
+    /// Taken by the first poll
+    ids: Option<Vec<String>>,
+}
+
+impl IdReplayBackend {
+    pub fn new(inner: Box<dyn MailBackend>, ids: Vec<String>) -> Self {
+        Self {
+            inner,
+            ids: Some(ids),
+        }
+    }
+}
+
+/// One message ID per line; blank lines and `#` comments are skipped
+pub fn read_ids(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    Ok(contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|id| !id.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+#[async_trait]
+impl MailBackend for IdReplayBackend {
+    async fn current_cursor(&mut self) -> Result<String> {
+        Ok("replay".to_owned())
+    }
+
+    fn system_labels(&self) -> HashSet<String> {
+        self.inner.system_labels()
+    }
+
+    async fn changes_since(&mut self, _cursor: &str) -> Result<Option<Vec<MinimalMessage>>> {
+        Ok(Some(
+            self.ids
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| MinimalMessage {
+                    thread_id: id.clone(),
+                    id,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>> {
+        self.inner.fetch_details(messages).await
+    }
+
+    async fn inbox_unread(&mut self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Streams count whatever matches now, which isn't reproducible
+    async fn search_since(
+        &mut self,
+        _query: &str,
+        _since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
+
+    async fn fetch_attachment(
+        &mut self,
+        message_id: &str,
+        attachment: &Attachment,
+    ) -> Result<Vec<u8>> {
+        self.inner.fetch_attachment(message_id, attachment).await
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashSet, sync::RwLock, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::http_trace;
+
+/// Where a sender stands, as the `reputation` label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reputation {
+    Trusted,
+    Unknown,
+    Blocked,
+}
+
+impl Reputation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reputation::Trusted => "trusted",
+            Reputation::Unknown => "unknown",
+            Reputation::Blocked => "blocked",
+        }
+    }
+}
+
+/// Addresses and domains, one per line, read from files or URLs. A domain
+/// covers its subdomains too; blank lines and `#` comments are skipped.
+#[derive(Debug, Default)]
+struct SenderList {
+    sources: Vec<String>,
+    entries: RwLock<HashSet<String>>,
+}
+
+impl SenderList {
+    fn new(sources: Vec<String>) -> Self {
+        Self {
+            sources,
+            entries: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Re-reads every source. If any of them fails, the list is left as it
+    /// was rather than shrinking to the sources that worked.
+    async fn refresh(&self, name: &str) {
+        let mut entries = HashSet::new();
+        for source in &self.sources {
+            match read_source(source).await {
+                Ok(text) => entries.extend(parse_entries(&text)),
+                Err(err) => {
+                    warn!("Failed to load {} senders from {}: {}", name, source, err);
+                    return;
+                }
+            }
+        }
+
+        info!("Loaded {} {} senders", entries.len(), name);
+        *self.entries.write().unwrap() = entries;
+    }
+
+    fn contains(&self, address: &str) -> bool {
+        let entries = self.entries.read().unwrap();
+        if entries.contains(address) {
+            return true;
+        }
+
+        let Some((_, mut domain)) = address.rsplit_once('@') else {
+            return false;
+        };
+        loop {
+            if entries.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Allow and deny lists of senders, refreshed in the background
+#[derive(Debug)]
+pub struct SenderReputation {
+    trusted: SenderList,
+    blocked: SenderList,
+}
+
+impl SenderReputation {
+    pub fn new(trusted: Vec<String>, blocked: Vec<String>) -> Self {
+        Self {
+            trusted: SenderList::new(trusted),
+            blocked: SenderList::new(blocked),
+        }
+    }
+
+    pub async fn refresh(&self) {
+        self.trusted.refresh("trusted").await;
+        self.blocked.refresh("blocked").await;
+    }
+
+    /// Being blocked wins over being trusted
+    pub fn classify(&self, address: &str) -> Reputation {
+        let address = address.trim().to_lowercase();
+        if self.blocked.contains(&address) {
+            Reputation::Blocked
+        } else if self.trusted.contains(&address) {
+            Reputation::Trusted
+        } else {
+            Reputation::Unknown
+        }
+    }
+}
+
+/// Reloads the lists every `interval`; the first load is up to the caller.
+pub fn spawn_refreshing(reputation: std::sync::Arc<SenderReputation>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            reputation.refresh().await;
+        }
+    });
+}
+
+async fn read_source(source: &str) -> Result<String, String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let response = http_trace::send(reqwest::Client::new().get(source))
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        response.text().await.map_err(|err| err.to_string())
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn parse_entries(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.trim_start_matches('@').to_lowercase())
+}
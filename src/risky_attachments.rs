@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mail::{Attachment, UsableMessageDetails},
+    notify::{Notifier, NotifyConfig},
+};
+
+/// Executables, scripts, disk images and macro-enabled Office documents
+const DEFAULT_EXTENSIONS: [&str; 22] = [
+    "exe", "scr", "com", "bat", "cmd", "pif", "msi", "js", "jse", "vbs", "vbe", "wsf", "hta",
+    "ps1", "jar", "lnk", "iso", "img", "docm", "xlsm", "pptm", "dotm",
+];
+
+/// The `[risky_attachments]` section of the config. Attachments only show up
+/// when messages are fetched in full, which happens when the section is in the
+/// config at startup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskyAttachmentsConfig {
+    /// File extensions, without the dot, compared case-insensitively
+    pub extensions: Vec<String>,
+    /// MIME types, e.g. `application/x-msdownload`
+    pub mime_types: Vec<String>,
+    /// Webhook to tell about each message with a risky attachment
+    pub notify: Option<NotifyConfig>,
+}
+
+impl Default for RiskyAttachmentsConfig {
+    fn default() -> Self {
+        Self {
+            extensions: DEFAULT_EXTENSIONS.map(str::to_owned).to_vec(),
+            mime_types: vec![],
+            notify: None,
+        }
+    }
+}
+
+/// `[risky_attachments]`, ready to check messages against
+#[derive(Debug, Clone)]
+pub struct RiskyAttachments {
+    extensions: Vec<String>,
+    mime_types: Vec<String>,
+    pub notify: Option<Arc<Notifier>>,
+}
+
+impl RiskyAttachments {
+    pub fn new(config: &RiskyAttachmentsConfig) -> Self {
+        Self {
+            extensions: config
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect(),
+            mime_types: config
+                .mime_types
+                .iter()
+                .map(|mime_type| mime_type.to_lowercase())
+                .collect(),
+            notify: config
+                .notify
+                .clone()
+                .map(|notify| Arc::new(Notifier::new(notify))),
+        }
+    }
+
+    /// The extension or MIME type that makes `attachment` risky, if it is
+    fn risky_type(&self, attachment: &Attachment) -> Option<String> {
+        let filename = attachment.filename.to_lowercase();
+        if let Some((_, extension)) = filename.rsplit_once('.') {
+            if self.extensions.iter().any(|risky| risky == extension) {
+                return Some(extension.to_owned());
+            }
+        }
+
+        let mime_type = attachment.mime_type.to_lowercase();
+        self.mime_types.contains(&mime_type).then_some(mime_type)
+    }
+
+    /// The type of each of the message's risky attachments
+    pub fn check(&self, message: &UsableMessageDetails) -> Vec<String> {
+        message
+            .attachments
+            .iter()
+            .filter_map(|attachment| self.risky_type(attachment))
+            .collect()
+    }
+}
@@ -0,0 +1,167 @@
+use clap::Args;
+
+/// Knobs for the generated Prometheus alerting rules
+#[derive(Debug, Clone, Args)]
+pub struct RulesOptions {
+    /// Label selector matching this exporter's scrape job
+    #[arg(long, env = "RULES_SELECTOR", default_value = "job=\"gmail-exporter\"")]
+    pub selector: String,
+
+    /// Alert when no mail has arrived for this many hours
+    #[arg(long, env = "RULES_NO_MAIL_HOURS", default_value_t = 24)]
+    pub no_mail_hours: u64,
+
+    /// Alert when polls have stopped succeeding for this many minutes
+    #[arg(long, env = "RULES_STALE_POLL_MINUTES", default_value_t = 30)]
+    pub stale_poll_minutes: u64,
+
+    /// Alert when the vacation responder has been on for this many days
+    #[arg(long, env = "RULES_VACATION_DAYS", default_value_t = 14)]
+    pub vacation_days: u64,
+
+    /// Alert when the unread inbox count grows by more than this over 24h
+    #[arg(long, env = "RULES_UNREAD_GROWTH", default_value_t = 50)]
+    pub unread_growth: u64,
+}
+
+struct Alert {
+    name: &'static str,
+    expr: String,
+    for_duration: &'static str,
+    severity: &'static str,
+    summary: String,
+}
+
+/// Renders a Prometheus rules file covering the metrics this exporter emits.
+pub fn render(options: &RulesOptions) -> String {
+    let selector = &options.selector;
+
+    let alerts = [
+        Alert {
+            name: "GmailExporterDown",
+            expr: format!("up{{{}}} == 0", selector),
+            for_duration: "5m",
+            severity: "critical",
+            summary: "gmail-prom-exporter is not being scraped successfully".to_owned(),
+        },
+        Alert {
+            name: "GmailExporterAuthBroken",
+            expr: format!(
+                "increase(gmail_auth_refreshes_total{{{s}}}[15m]) > 5 and time() - gmail_last_poll_success_timestamp_seconds{{{s}}} > {stale}",
+                s = selector,
+                stale = options.stale_poll_minutes * 60
+            ),
+            for_duration: "5m",
+            severity: "critical",
+            summary: "Access token keeps being refreshed but polls aren't succeeding; re-authenticate".to_owned(),
+        },
+        Alert {
+            name: "GmailExporterPollsStale",
+            expr: format!(
+                "time() - gmail_last_poll_success_timestamp_seconds{{{}}} > {}",
+                selector,
+                options.stale_poll_minutes * 60
+            ),
+            for_duration: "5m",
+            severity: "warning",
+            summary: format!(
+                "No successful Gmail poll in the last {} minutes",
+                options.stale_poll_minutes
+            ),
+        },
+        Alert {
+            name: "GmailNoMailReceived",
+            expr: format!(
+                "sum by (instance) (increase(email_received{{{}}}[{}h])) == 0",
+                selector, options.no_mail_hours
+            ),
+            for_duration: "15m",
+            severity: "warning",
+            summary: format!("No mail received in the last {} hours", options.no_mail_hours),
+        },
+        Alert {
+            name: "GmailUnreadBacklogGrowing",
+            expr: format!(
+                "delta(gmail_inbox_unread_messages{{{}}}[24h]) > {}",
+                selector, options.unread_growth
+            ),
+            for_duration: "1h",
+            severity: "info",
+            summary: format!(
+                "Unread inbox count grew by more than {} in 24 hours",
+                options.unread_growth
+            ),
+        },
+        Alert {
+            name: "GmailHistoryReset",
+            expr: format!(
+                "increase(gmail_history_resets_total{{{}}}[1h]) > 0",
+                selector
+            ),
+            for_duration: "0m",
+            severity: "warning",
+            summary: "Saved history id expired; mail received while the exporter was behind was not counted".to_owned(),
+        },
+        Alert {
+            name: "GmailDraftsSitting",
+            expr: format!("gmail_stale_drafts{{{}}} > 0", selector),
+            for_duration: "0m",
+            severity: "info",
+            summary: "Drafts have sat unsent for longer than --stale-draft-age".to_owned(),
+        },
+        Alert {
+            name: "GmailVacationResponderLeftOn",
+            expr: format!(
+                "min_over_time(gmail_vacation_responder_enabled{{{}}}[{}d]) == 1",
+                selector, options.vacation_days
+            ),
+            for_duration: "0m",
+            severity: "info",
+            summary: format!(
+                "Vacation auto-responder has been on for {} days",
+                options.vacation_days
+            ),
+        },
+        Alert {
+            name: "GmailForwardingAddressChanged",
+            expr: format!(
+                "increase(gmail_forwarding_address_changes_total{{{}}}[1h]) > 0",
+                selector
+            ),
+            for_duration: "0m",
+            severity: "critical",
+            summary: "Gmail forwarding addresses changed; check that it was you".to_owned(),
+        },
+        Alert {
+            name: "GmailWatchExpiring",
+            expr: format!(
+                "gmail_watch_expiration_timestamp_seconds{{{}}} - time() < 24 * 3600",
+                selector
+            ),
+            for_duration: "15m",
+            severity: "warning",
+            summary: "Gmail watch expires within a day and hasn't been renewed; push mode will stop waking the exporter".to_owned(),
+        },
+    ];
+
+    let mut output = String::from("groups:\n  - name: gmail-prom-exporter\n    rules:\n");
+    for alert in alerts {
+        output.push_str(&format!("      - alert: {}\n", alert.name));
+        output.push_str(&format!("        expr: {}\n", yaml_string(&alert.expr)));
+        output.push_str(&format!("        for: {}\n", alert.for_duration));
+        output.push_str("        labels:\n");
+        output.push_str(&format!("          severity: {}\n", alert.severity));
+        output.push_str("        annotations:\n");
+        output.push_str(&format!(
+            "          summary: {}\n",
+            yaml_string(&alert.summary)
+        ));
+    }
+
+    output
+}
+
+/// Double-quoted YAML scalar, since PromQL is full of characters YAML cares about
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use tracing::{info, warn};
+
+use crate::auth::GoogleAuth;
+
+const FULL: &str = "https://mail.google.com/";
+const MODIFY: &str = "https://www.googleapis.com/auth/gmail.modify";
+pub const READONLY: &str = "https://www.googleapis.com/auth/gmail.readonly";
+const METADATA: &str = "https://www.googleapis.com/auth/gmail.metadata";
+const SETTINGS_BASIC: &str = "https://www.googleapis.com/auth/gmail.settings.basic";
+
+/// How much of the mailbox a feature reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Labels, history and message headers
+    Metadata,
+    /// Message bodies, attachments and search queries, which gmail.metadata
+    /// doesn't allow
+    Read,
+    /// Vacation, send-as, forwarding and filter settings
+    Settings,
+}
+
+impl Access {
+    /// The scopes that each allow it, narrowest last
+    fn scopes(self) -> &'static [&'static str] {
+        match self {
+            Access::Metadata => &[FULL, MODIFY, READONLY, METADATA],
+            Access::Read => &[FULL, MODIFY, READONLY],
+            Access::Settings => &[FULL, MODIFY, READONLY, SETTINGS_BASIC],
+        }
+    }
+}
+
+/// A feature that's turned on, and what it needs
+#[derive(Debug, Clone)]
+pub struct ScopeNeed {
+    /// As the user turned it on, e.g. `--scan-bodies`
+    pub feature: &'static str,
+    pub access: Access,
+}
+
+/// Features the granted scopes don't cover
+#[derive(Debug)]
+pub struct MissingScopes {
+    pub account: Option<String>,
+    pub granted: Vec<String>,
+    pub unmet: Vec<ScopeNeed>,
+}
+
+impl fmt::Display for MissingScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.account {
+            Some(account) => write!(f, "The token for account {}", account)?,
+            None => write!(f, "The token")?,
+        }
+        write!(f, " wasn't granted a scope these need:")?;
+        for need in &self.unmet {
+            let scopes = need.access.scopes();
+            write!(f, " {} needs {}", need.feature, scopes[scopes.len() - 1])?;
+            if scopes.len() > 1 {
+                write!(f, " (or {})", scopes[..scopes.len() - 1].join(", "))?;
+            }
+            write!(f, ";")?;
+        }
+        write!(
+            f,
+            " it has {}. Authenticate again, granting {}, or turn those off.",
+            match self.granted.is_empty() {
+                true => "none".to_owned(),
+                false => self.granted.join(", "),
+            },
+            READONLY
+        )
+    }
+}
+
+/// The needs that none of `granted` allows
+pub fn unmet(granted: &[String], needs: &[ScopeNeed]) -> Vec<ScopeNeed> {
+    needs
+        .iter()
+        .filter(|need| {
+            !need
+                .access
+                .scopes()
+                .iter()
+                .any(|scope| granted.iter().any(|granted| granted == scope))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Checks the token's scopes against what the turned on features need,
+/// rather than finding out from a 403 mid-poll. Only a definite answer fails
+/// the check; if the scopes can't be looked up, that's logged and the
+/// exporter carries on.
+pub async fn check(auth: &mut GoogleAuth, needs: &[ScopeNeed]) -> Result<(), MissingScopes> {
+    let granted = match auth.granted_scopes().await {
+        Ok(granted) => granted,
+        Err(err) => {
+            warn!(
+                "Couldn't look up the token's scopes, not checking them: {}",
+                err
+            );
+            return Ok(());
+        }
+    };
+    info!("Token scopes: {}", granted.join(", "));
+
+    let unmet = unmet(&granted, needs);
+    if unmet.is_empty() {
+        Ok(())
+    } else {
+        Err(MissingScopes {
+            account: auth.account.clone(),
+            granted,
+            unmet,
+        })
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Instant;
+
+use metrics::{describe_gauge, gauge};
+
+pub struct SelfMetrics {
+    started_at: Instant,
+}
+
+impl SelfMetrics {
+    pub fn install() -> Self {
+        describe_gauge!(
+            "gmail_exporter_build_info",
+            "Always 1, labeled with the version and commit of the running exporter."
+        );
+        describe_gauge!(
+            "gmail_exporter_uptime_seconds",
+            "Seconds since the exporter started."
+        );
+        describe_gauge!(
+            "gmail_exporter_resident_memory_bytes",
+            "Resident set size of the exporter process."
+        );
+        describe_gauge!(
+            "gmail_exporter_open_fds",
+            "Number of file descriptors the exporter process has open."
+        );
+        describe_gauge!(
+            "gmail_exporter_scrape_trimmed_series",
+            "Per-sender series left out of the previous scrape to stay under --max-scrape-bytes."
+        );
+        describe_gauge!(
+            "gmail_exporter_tokio_tasks",
+            "Number of tokio tasks currently alive."
+        );
+
+        gauge!(
+            "gmail_exporter_build_info",
+            1.0,
+            "version" => env!("CARGO_PKG_VERSION"),
+            "commit" => env!("GIT_COMMIT")
+        );
+
+        Self {
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn record_trimmed_series(&self, trimmed_series: usize) {
+        gauge!(
+            "gmail_exporter_scrape_trimmed_series",
+            trimmed_series as f64
+        );
+    }
+
+    /// Refresh the process gauges, called right before each scrape is rendered
+    pub fn update(&self) {
+        gauge!(
+            "gmail_exporter_uptime_seconds",
+            self.started_at.elapsed().as_secs_f64()
+        );
+
+        if let Some(rss) = resident_memory_bytes() {
+            gauge!("gmail_exporter_resident_memory_bytes", rss as f64);
+        }
+
+        if let Some(fds) = open_fds() {
+            gauge!("gmail_exporter_open_fds", fds as f64);
+        }
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            gauge!(
+                "gmail_exporter_tokio_tasks",
+                runtime.metrics().num_alive_tasks() as f64
+            );
+        }
+    }
+}
+
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+fn open_fds() -> Option<usize> {
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count())
+}
@@ -0,0 +1,53 @@
+use std::{collections::BTreeSet, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// Senders that have written before, as kept in the state file. Addresses
+/// and domains are stored hashed when --hash-addresses is on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KnownSenders {
+    #[serde(default)]
+    pub addresses: BTreeSet<String>,
+    #[serde(default)]
+    pub domains: BTreeSet<String>,
+}
+
+impl KnownSenders {
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.domains.is_empty()
+    }
+}
+
+/// Whether a message is the first from its sender
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewSender {
+    pub address: bool,
+    /// The first from anyone at the sender's registrable domain
+    pub domain: bool,
+}
+
+/// Remembers every sender seen, to tell when one writes for the first time
+#[derive(Default)]
+pub struct SenderHistory {
+    known: Mutex<KnownSenders>,
+}
+
+impl SenderHistory {
+    /// Picks up the senders saved in the state file
+    pub fn restore(&self, known: KnownSenders) {
+        *self.known.lock().unwrap() = known;
+    }
+
+    pub fn known(&self) -> KnownSenders {
+        self.known.lock().unwrap().clone()
+    }
+
+    /// `unknown`, for a sender that couldn't be parsed, is never new
+    pub fn observe(&self, address: &str, domain: &str) -> NewSender {
+        let mut known = self.known.lock().unwrap();
+        NewSender {
+            address: address != "unknown" && known.addresses.insert(address.to_owned()),
+            domain: domain != "unknown" && known.domains.insert(domain.to_owned()),
+        }
+    }
+}
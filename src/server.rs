@@ -0,0 +1,346 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::Args;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use metrics_exporter_prometheus::PrometheusHandle;
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+use crate::{
+    debug_status, exposition, openmetrics, self_metrics::SelfMetrics, status_page,
+    watch::ScrapeTrigger,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct MetricsServerOptions {
+    /// Address to serve /metrics on
+    #[arg(long, env = "METRICS_ADDR", default_value = "0.0.0.0:9090")]
+    pub metrics_addr: SocketAddr,
+
+    /// Serve /metrics on a unix domain socket instead of a TCP listener
+    #[arg(long, env = "METRICS_SOCKET", conflicts_with_all = ["metrics_tls_cert", "metrics_tls_key"])]
+    pub metrics_socket: Option<PathBuf>,
+
+    /// PEM certificate chain used to serve /metrics over https
+    #[arg(long, env = "METRICS_TLS_CERT", requires = "metrics_tls_key")]
+    pub metrics_tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --metrics-tls-cert
+    #[arg(long, env = "METRICS_TLS_KEY", requires = "metrics_tls_cert")]
+    pub metrics_tls_key: Option<PathBuf>,
+
+    /// Require HTTP basic auth on /metrics, given as user:pass
+    #[arg(long, env = "METRICS_BASIC_AUTH")]
+    pub metrics_basic_auth: Option<String>,
+
+    /// Keep /metrics responses under this many bytes by leaving out the
+    /// per-sender series with the lowest values; the number left out is
+    /// exposed as `gmail_exporter_scrape_trimmed_series`
+    #[arg(long, env = "MAX_SCRAPE_BYTES")]
+    pub max_scrape_bytes: Option<usize>,
+
+    /// Serve the REST API and event stream on this address instead of
+    /// alongside /metrics, with the same TLS and basic auth
+    #[arg(long, env = "API_ADDR")]
+    pub api_addr: Option<SocketAddr>,
+
+    /// Serve the Pub/Sub push receiver on this address instead of alongside
+    /// /metrics, e.g. to expose only it publicly; TLS applies as for /metrics
+    #[arg(long, env = "PUBSUB_PUSH_ADDR")]
+    pub pubsub_push_addr: Option<SocketAddr>,
+}
+
+#[derive(Clone)]
+struct ServerState {
+    handle: PrometheusHandle,
+    self_metrics: Arc<SelfMetrics>,
+    scrape_trigger: Option<Arc<ScrapeTrigger>>,
+    expected_authorization: Option<String>,
+    max_scrape_bytes: Option<usize>,
+}
+
+/// Every listener bound and TLS loaded, ready to serve. Done up front so a
+/// bad address or certificate stops startup instead of a background task.
+pub struct MetricsServer {
+    listeners: Vec<(Listener, Router, &'static str)>,
+    tls: Option<Arc<ServerConfig>>,
+}
+
+enum Listener {
+    Tcp(TcpListener, SocketAddr),
+    Unix(UnixListener, PathBuf),
+}
+
+pub async fn bind_metrics(
+    handle: PrometheusHandle,
+    options: MetricsServerOptions,
+    scrape_trigger: Option<Arc<ScrapeTrigger>>,
+    routes: Option<Router>,
+    unauthenticated_routes: Option<Router>,
+) -> Result<MetricsServer, String> {
+    let state = ServerState {
+        handle,
+        self_metrics: Arc::new(SelfMetrics::install()),
+        scrape_trigger,
+        expected_authorization: options
+            .metrics_basic_auth
+            .as_ref()
+            .map(|credentials| format!("Basic {}", STANDARD.encode(credentials))),
+        max_scrape_bytes: options.max_scrape_bytes,
+    };
+
+    let auth = middleware::from_fn_with_state(state.clone(), require_basic_auth);
+    let api = routes.unwrap_or_default().layer(auth.clone());
+    let push = unauthenticated_routes.unwrap_or_default();
+    let mut app = Router::new()
+        .route("/metrics", get(render_metrics))
+        .route("/", get(render_status_page))
+        .route("/debug/status", get(render_debug_status))
+        .with_state(state)
+        .layer(auth);
+
+    let tls = match (&options.metrics_tls_cert, &options.metrics_tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        (None, None) => None,
+        _ => {
+            return Err("--metrics-tls-cert and --metrics-tls-key must be provided together".into())
+        }
+    };
+
+    let mut listeners = vec![];
+    match options.api_addr {
+        Some(addr) => listeners.push((bind_tcp(addr, "the API").await?, api, "the API")),
+        None => app = app.merge(api),
+    }
+    match options.pubsub_push_addr {
+        Some(addr) => listeners.push((
+            bind_tcp(addr, "Pub/Sub pushes").await?,
+            push,
+            "Pub/Sub pushes",
+        )),
+        None => app = app.merge(push),
+    }
+
+    let metrics = match options.metrics_socket {
+        None => bind_tcp(options.metrics_addr, "metrics").await?,
+        Some(path) => {
+            // A stale socket from a previous run would make bind fail
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .map_err(|err| format!("Failed to remove stale metrics socket: {}", err))?;
+            }
+            let listener = UnixListener::bind(&path).map_err(|err| {
+                format!("Failed to bind metrics socket {}: {}", path.display(), err)
+            })?;
+            Listener::Unix(listener, path)
+        }
+    };
+    listeners.push((metrics, app, "metrics"));
+
+    Ok(MetricsServer { listeners, tls })
+}
+
+async fn bind_tcp(addr: SocketAddr, what: &str) -> Result<Listener, String> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| format!("Failed to bind {} listener on {}: {}", what, addr, err))?;
+    Ok(Listener::Tcp(listener, addr))
+}
+
+impl MetricsServer {
+    /// Returns if any of the servers stops
+    pub async fn serve(self) -> Result<(), String> {
+        let mut servers = tokio::task::JoinSet::new();
+        for (listener, app, what) in self.listeners {
+            servers.spawn(serve_listener(listener, app, self.tls.clone(), what));
+        }
+        servers
+            .join_next()
+            .await
+            .expect("Expected at least the metrics listener")
+            .map_err(|err| format!("Server task failed: {}", err))?
+    }
+}
+
+async fn serve_listener(
+    listener: Listener,
+    app: Router,
+    tls: Option<Arc<ServerConfig>>,
+    what: &str,
+) -> Result<(), String> {
+    let served = match (listener, tls) {
+        (Listener::Unix(listener, path), _) => {
+            info!("Serving {} on unix socket {}", what, path.display());
+            axum::serve(listener, app).await
+        }
+        (Listener::Tcp(listener, addr), Some(tls)) => {
+            info!("Serving {} over https on {}", what, addr);
+            serve_tls(listener, app, tls).await
+        }
+        (Listener::Tcp(listener, addr), None) => {
+            info!("Serving {} over http on {}", what, addr);
+            axum::serve(listener, app).await
+        }
+    };
+    served.map_err(|err| format!("Server for {} failed: {}", what, err))
+}
+
+async fn render_metrics(State(state): State<ServerState>, headers: HeaderMap) -> Response {
+    if let Some(scrape_trigger) = &state.scrape_trigger {
+        scrape_trigger.on_scrape().await;
+    }
+
+    state.self_metrics.update();
+    let mut rendered = state.handle.render() + &exposition::render_collectors();
+    if let Some(max_scrape_bytes) = state.max_scrape_bytes {
+        let (trimmed, trimmed_series) = exposition::trim_sender_series(&rendered, max_scrape_bytes);
+        if trimmed_series > 0 {
+            warn!(
+                "Left {} per-sender series out of the scrape to stay under {} bytes",
+                trimmed_series, max_scrape_bytes
+            );
+        }
+        state.self_metrics.record_trimmed_series(trimmed_series);
+        rendered = trimmed;
+    }
+
+    let wants_openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+
+    if wants_openmetrics {
+        (
+            [(header::CONTENT_TYPE, openmetrics::CONTENT_TYPE)],
+            openmetrics::render(&rendered),
+        )
+            .into_response()
+    } else {
+        (
+            [(
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )],
+            rendered,
+        )
+            .into_response()
+    }
+}
+
+async fn render_debug_status() -> Json<debug_status::DebugStatus> {
+    Json(debug_status::snapshot())
+}
+
+async fn render_status_page() -> Html<String> {
+    Html(status_page::render(&debug_status::snapshot()))
+}
+
+async fn require_basic_auth(
+    State(state): State<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = &state.expected_authorization else {
+        return next.run(request).await;
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    // Compared in constant time, so response times don't give the password away
+    let authorized =
+        provided.is_some_and(|provided| provided.as_bytes().ct_eq(expected.as_bytes()).into());
+    if authorized {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"metrics\"")],
+        )
+            .into_response()
+    }
+}
+
+fn load_tls_config(cert: &PathBuf, key: &PathBuf) -> Result<Arc<ServerConfig>, String> {
+    let cert_file = std::fs::File::open(cert)
+        .map_err(|err| format!("Failed to open --metrics-tls-cert: {}", err))?;
+    let key_file = std::fs::File::open(key)
+        .map_err(|err| format!("Failed to open --metrics-tls-key: {}", err))?;
+
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            format!(
+                "Failed to parse certificates from --metrics-tls-cert: {}",
+                err
+            )
+        })?;
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(key_file))
+        .map_err(|err| format!("Failed to parse --metrics-tls-key: {}", err))?
+        .ok_or("Expected --metrics-tls-key to contain a private key")?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|err| format!("Invalid metrics TLS certificate/key pair: {}", err))?;
+
+    Ok(Arc::new(config))
+}
+
+async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    config: Arc<ServerConfig>,
+) -> std::io::Result<()> {
+    let acceptor = TlsAcceptor::from(config);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("TLS handshake with {} failed: {}", peer, err);
+                    return;
+                }
+            };
+
+            let service = hyper::service::service_fn(move |request| app.clone().oneshot(request));
+
+            if let Err(err) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("Error serving metrics connection to {}: {}", peer, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use clap::Args;
+use mailparse::{MailAddr, MailAddrList, SingleInfo};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::info;
+
+use crate::{
+    backend::MailBackend,
+    error::Result,
+    mail::{MinimalMessage, UsableMessageDetails},
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct SimulateOptions {
+    /// Messages generated per second; 0 generates all of --simulate-count at
+    /// the first poll, e.g. to measure a scrape with --once
+    #[arg(long, env = "SIMULATE_RATE", default_value_t = 10.0)]
+    pub simulate_rate: f64,
+
+    /// Stop after generating this many messages
+    #[arg(long, env = "SIMULATE_COUNT")]
+    pub simulate_count: Option<u64>,
+
+    /// Distinct sender addresses to pick from
+    #[arg(long, env = "SIMULATE_SENDERS", default_value_t = 1000)]
+    pub simulate_senders: usize,
+
+    /// Distinct sender domains the addresses are spread over
+    #[arg(long, env = "SIMULATE_DOMAINS", default_value_t = 100)]
+    pub simulate_domains: usize,
+
+    /// Zipf exponent of the sender distribution: 0 picks senders uniformly,
+    /// higher values concentrate mail on the first few, like a real inbox
+    #[arg(long, env = "SIMULATE_SENDER_SKEW", default_value_t = 1.0)]
+    pub simulate_sender_skew: f64,
+
+    /// Labels to put on messages, each with the probability a message gets it
+    #[arg(
+        long,
+        env = "SIMULATE_LABELS",
+        value_delimiter = ',',
+        value_parser = parse_label_weight,
+        default_value = "INBOX=1,UNREAD=0.6,IMPORTANT=0.15,CATEGORY_UPDATES=0.3,\
+                         CATEGORY_PROMOTIONS=0.25,CATEGORY_SOCIAL=0.1"
+    )]
+    pub simulate_labels: Vec<(String, f64)>,
+
+    /// Seed for reproducible runs; random if unset
+    #[arg(long, env = "SIMULATE_SEED")]
+    pub simulate_seed: Option<u64>,
+}
+
+fn parse_label_weight(value: &str) -> Result<(String, f64), String> {
+    let (label, probability) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected LABEL=PROBABILITY, got {:?}", value))?;
+    let probability = probability
+        .parse::<f64>()
+        .ok()
+        .filter(|probability| (0.0..=1.0).contains(probability))
+        .ok_or_else(|| {
+            format!(
+                "expected a probability between 0 and 1, got {:?}",
+                probability
+            )
+        })?;
+
+    Ok((label.trim().to_owned(), probability))
+}
+
+/// Generates messages instead of fetching them. The cursor is the number of
+/// messages generated so far.
+pub struct SimulatedBackend {
+    options: SimulateOptions,
+    rng: StdRng,
+    /// Running totals of each sender's Zipf weight, to sample from
+    sender_weights: Vec<f64>,
+    started: Instant,
+    generated: u64,
+    unread: u64,
+    /// Messages returned by the last changes_since, waiting for fetch_details
+    pending: HashMap<String, UsableMessageDetails>,
+}
+
+impl SimulatedBackend {
+    /// `mailbox` tells apart simulated accounts sharing a seed
+    pub fn new(options: &SimulateOptions, mailbox: u64) -> Self {
+        assert!(
+            options.simulate_rate > 0.0 || options.simulate_count.is_some(),
+            "--simulate-rate 0 needs --simulate-count"
+        );
+        let rng = match options.simulate_seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(mailbox)),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        let sender_weights = (1..=options.simulate_senders.max(1))
+            .scan(0.0, |total, rank| {
+                *total += 1.0 / (rank as f64).powf(options.simulate_sender_skew);
+                Some(*total)
+            })
+            .collect();
+
+        info!(
+            "Simulating {} messages/s from {} senders",
+            options.simulate_rate, options.simulate_senders
+        );
+
+        Self {
+            options: options.clone(),
+            rng,
+            sender_weights,
+            started: Instant::now(),
+            generated: 0,
+            unread: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// How many messages should exist by now
+    fn due(&self) -> u64 {
+        let due = match self.options.simulate_rate {
+            rate if rate <= 0.0 => u64::MAX,
+            rate => (self.started.elapsed().as_secs_f64() * rate) as u64,
+        };
+        due.min(self.options.simulate_count.unwrap_or(u64::MAX))
+    }
+
+    fn generate(&mut self) -> UsableMessageDetails {
+        let total = *self.sender_weights.last().unwrap();
+        let point = self.rng.random_range(0.0..total);
+        let sender = self
+            .sender_weights
+            .partition_point(|&weight| weight <= point)
+            .min(self.sender_weights.len() - 1);
+        let domain = sender % self.options.simulate_domains.max(1);
+
+        let labels = self
+            .options
+            .simulate_labels
+            .iter()
+            .filter(|(_, probability)| self.rng.random_bool(*probability))
+            .map(|(label, _)| label.clone())
+            .collect::<Vec<_>>();
+        if labels.iter().any(|label| label == "UNREAD") {
+            self.unread += 1;
+        }
+
+        self.generated += 1;
+        let id = format!("sim{:016x}", self.generated);
+
+        UsableMessageDetails {
+            thread_id: id.clone(),
+            id,
+            history_id: self.generated.to_string(),
+            labels,
+            internal_date: chrono::Utc::now(),
+            from: address(format!("sender{}@domain{}.example", sender, domain)),
+            to: address("me@example.com".to_owned()),
+            subject: format!("Simulated message {}", self.generated),
+            snippet: format!("This is simulated message {}.", self.generated),
+            size_estimate: self.rng.random_range(1_000..200_000),
+            attachments: vec![],
+            list_unsubscribe: false,
+            body: None,
+            message_id: None,
+        }
+    }
+}
+
+fn address(addr: String) -> MailAddrList {
+    MailAddrList::from(vec![MailAddr::Single(SingleInfo {
+        display_name: None,
+        addr,
+    })])
+}
+
+#[async_trait]
+impl MailBackend for SimulatedBackend {
+    /// The simulated labels named in capitals, like Gmail's system labels
+    fn system_labels(&self) -> HashSet<String> {
+        self.options
+            .simulate_labels
+            .iter()
+            .map(|(label, _)| label)
+            .filter(|label| !label.chars().any(char::is_lowercase))
+            .cloned()
+            .collect()
+    }
+
+    async fn current_cursor(&mut self) -> Result<String> {
+        Ok(self.generated.to_string())
+    }
+
+    async fn changes_since(&mut self, _cursor: &str) -> Result<Option<Vec<MinimalMessage>>> {
+        self.pending.clear();
+
+        let mut added = vec![];
+        while self.generated < self.due() {
+            let message = self.generate();
+            added.push(MinimalMessage {
+                id: message.id.clone(),
+                thread_id: message.thread_id.clone(),
+            });
+            self.pending.insert(message.id.clone(), message);
+        }
+        Ok(Some(added))
+    }
+
+    async fn fetch_details(
+        &mut self,
+        messages: Vec<MinimalMessage>,
+    ) -> Result<Vec<UsableMessageDetails>> {
+        Ok(messages
+            .into_iter()
+            .filter_map(|message| self.pending.remove(&message.id))
+            .collect())
+    }
+
+    async fn inbox_unread(&mut self) -> Result<Option<u64>> {
+        Ok(Some(self.unread))
+    }
+
+    /// Simulated messages never match a stream
+    async fn search_since(
+        &mut self,
+        _query: &str,
+        _since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    mail::{MailClient, ParseForMetrics},
+    report::parse_since,
+    state::StateFile,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct SnapshotOptions {
+    /// Where the previous snapshot is read from and this one saved to
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: PathBuf,
+
+    /// Top senders are counted over the mail received this far back, e.g. `1d`
+    #[arg(long, env = "SNAPSHOT_SINCE", default_value = "1d", value_parser = parse_since)]
+    pub since: TimeDelta,
+
+    /// How many senders to keep
+    #[arg(long, env = "SNAPSHOT_TOP_SENDERS", default_value_t = 10)]
+    pub top_senders: usize,
+}
+
+/// Label counts and top senders at one point in time, kept in the state file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MailboxSnapshot {
+    pub taken_at: DateTime<Utc>,
+    /// By label name
+    pub labels: BTreeMap<String, LabelCounts>,
+    /// Messages per sender address over the window, for the top senders only
+    pub senders: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelCounts {
+    pub messages: u64,
+    pub unread: u64,
+}
+
+/// How a label's counts or a sender's messages moved between two snapshots
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Change {
+    Label {
+        name: String,
+        messages: i64,
+        unread: i64,
+    },
+    Sender {
+        address: String,
+        messages: i64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotDiff {
+    /// None on the first run, when there's nothing to compare against
+    pub since: Option<DateTime<Utc>>,
+    pub changes: Vec<Change>,
+}
+
+pub async fn take(
+    options: &SnapshotOptions,
+    mail: &mut MailClient,
+) -> Result<MailboxSnapshot, String> {
+    let mut labels = BTreeMap::new();
+    for label in mail.list_labels().await.map_err(|err| err.to_string())? {
+        let label = mail
+            .get_label(&label.id)
+            .await
+            .map_err(|err| err.to_string())?;
+        labels.insert(
+            label.name,
+            LabelCounts {
+                messages: label.messages_total.unwrap_or_default(),
+                unread: label.messages_unread.unwrap_or_default(),
+            },
+        );
+    }
+
+    // Epoch seconds rather than a date, which Gmail would read in the
+    // account's own timezone
+    let query = format!("after:{}", (Utc::now() - options.since).timestamp());
+    let listing = mail
+        .search_messages(&query)
+        .await
+        .map_err(|err| err.to_string())?;
+    info!("Counting senders of {} messages", listing.len());
+    let label_names = mail.load_labels().await.map_err(|err| err.to_string())?;
+    let mut senders = BTreeMap::<String, u64>::new();
+    for message in mail
+        .fetch_mail_details(listing, &label_names)
+        .await
+        .map_err(|err| err.to_string())?
+    {
+        if let Some(address) = message.from.first_address() {
+            *senders.entry(address).or_default() += 1;
+        }
+    }
+    let mut top = senders.into_iter().collect::<Vec<_>>();
+    top.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    top.truncate(options.top_senders);
+
+    Ok(MailboxSnapshot {
+        taken_at: Utc::now(),
+        labels,
+        senders: top.into_iter().collect(),
+    })
+}
+
+/// Only what moved is listed. A sender missing from one of the snapshots
+/// counts as 0 there.
+pub fn diff(previous: &MailboxSnapshot, current: &MailboxSnapshot) -> Vec<Change> {
+    let mut changes = vec![];
+    let names = previous
+        .labels
+        .keys()
+        .chain(current.labels.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let before = previous.labels.get(name).copied().unwrap_or_default();
+        let after = current.labels.get(name).copied().unwrap_or_default();
+        if before != after {
+            changes.push(Change::Label {
+                name: name.clone(),
+                messages: after.messages as i64 - before.messages as i64,
+                unread: after.unread as i64 - before.unread as i64,
+            });
+        }
+    }
+
+    let addresses = previous
+        .senders
+        .keys()
+        .chain(current.senders.keys())
+        .collect::<BTreeSet<_>>();
+    for address in addresses {
+        let before = previous.senders.get(address).copied().unwrap_or_default();
+        let after = current.senders.get(address).copied().unwrap_or_default();
+        if before != after {
+            changes.push(Change::Sender {
+                address: address.clone(),
+                messages: after as i64 - before as i64,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Takes a snapshot, saves it in place of the previous one and returns how
+/// the mailbox changed since that
+pub async fn run(options: &SnapshotOptions, mail: &mut MailClient) -> Result<SnapshotDiff, String> {
+    let state_file = StateFile::new(options.state_file.clone(), None);
+    let previous = state_file.load().snapshot;
+    let current = take(options, mail).await?;
+    state_file.save_snapshot(&current);
+
+    Ok(match previous {
+        Some(previous) => SnapshotDiff {
+            since: Some(previous.taken_at),
+            changes: diff(&previous, &current),
+        },
+        None => SnapshotDiff {
+            since: None,
+            changes: vec![],
+        },
+    })
+}
@@ -0,0 +1,65 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Stands in for text matched by a `redact` pattern
+const REDACTED: &str = "[redacted]";
+
+/// The `[snippets]` section of the config: include a preview of each message
+/// in event sink records and webhook notifications, so they can be shown
+/// without fetching the message again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnippetsConfig {
+    /// Previews are cut to this many characters
+    pub max_chars: usize,
+    /// Regexes for text to hide, e.g. `\b\d{6}\b` for one-time codes
+    pub redact: Vec<String>,
+}
+
+impl Default for SnippetsConfig {
+    fn default() -> Self {
+        Self {
+            max_chars: 100,
+            redact: vec![],
+        }
+    }
+}
+
+/// `[snippets]`, with the patterns compiled
+#[derive(Debug, Clone)]
+pub struct Snippets {
+    max_chars: usize,
+    redact: Vec<Regex>,
+}
+
+impl Snippets {
+    pub fn compile(config: &SnippetsConfig) -> Result<Self, String> {
+        let redact = config
+            .redact
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|err| format!("Invalid snippet redaction {:?}: {}", pattern, err))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            max_chars: config.max_chars,
+            redact,
+        })
+    }
+
+    /// Redaction happens before truncating, so a match cut in half by the
+    /// limit is still hidden
+    pub fn preview(&self, snippet: &str) -> String {
+        let mut preview = snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+        for pattern in &self.redact {
+            preview = pattern.replace_all(&preview, REDACTED).into_owned();
+        }
+
+        match preview.char_indices().nth(self.max_chars) {
+            Some((end, _)) => format!("{}…", preview[..end].trim_end()),
+            None => preview,
+        }
+    }
+}
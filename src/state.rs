@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use metrics::register_counter;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    anomaly::{ArrivalBaseline, HourBaseline},
+    backfill::BackfillProgress,
+    senders::{KnownSenders, SenderHistory},
+    snapshot::MailboxSnapshot,
+    threads::{ThreadState, ThreadTracker},
+};
+
+/// Running totals of every counter the pipeline has incremented, so they can
+/// be written out and restored without scraping our own recorder.
+static COUNTERS: OnceLock<Mutex<HashMap<CounterKey, u64>>> = OnceLock::new();
+
+type CounterKey = (String, Vec<(String, String)>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CounterSnapshot {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExporterState {
+    #[serde(default)]
+    pub counters: Vec<CounterSnapshot>,
+    /// History ID the watcher should resume from
+    #[serde(default)]
+    pub history_id: Option<String>,
+    /// Weeks of arrivals per hour of the week, for the anomaly score
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arrival_baseline: Vec<HourBaseline>,
+    /// Threads with recent messages, by thread ID, for the thread metrics
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub threads: HashMap<String, ThreadState>,
+    /// Every sender seen, for --new-senders
+    #[serde(default, skip_serializing_if = "KnownSenders::is_empty")]
+    pub known_senders: KnownSenders,
+    /// The last `snapshot`, to compare the next one with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<MailboxSnapshot>,
+    /// How far an unfinished `export` or `sync` got
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backfill: Option<BackfillProgress>,
+}
+
+fn counters() -> &'static Mutex<HashMap<CounterKey, u64>> {
+    COUNTERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Increments a counter and records it, so it's saved in the state file and
+/// restored on restart like the pipeline's own counters
+pub fn increment_counter(name: &str, labels: &[(String, String)], value: u64) {
+    metrics::counter!(name.to_owned(), value, labels);
+    record_counter(name, labels, value);
+}
+
+pub fn record_counter(name: &str, labels: &[(String, String)], value: u64) {
+    *counters()
+        .lock()
+        .unwrap()
+        .entry((name.to_owned(), labels.to_vec()))
+        .or_default() += value;
+}
+
+pub struct StateFile {
+    pub path: PathBuf,
+    /// Only counters labelled with this account are saved here, so accounts
+    /// sharing a process each keep their own state
+    account: Option<String>,
+    /// Serializes read-modify-write cycles between the snapshot task and the watcher
+    write_lock: Mutex<()>,
+    /// Saved alongside the counters, once tracked
+    arrival_baseline: OnceLock<Arc<ArrivalBaseline>>,
+    threads: OnceLock<Arc<ThreadTracker>>,
+    sender_history: OnceLock<Arc<SenderHistory>>,
+    /// Everything as of the last `save_checkpoint`, which is all `save` ever
+    /// writes, so a save between checkpoints can't pair counters from part
+    /// of a batch with the position before it
+    checkpoint: Mutex<Option<Checkpoint>>,
+}
+
+/// The counters and what's tracked alongside them, copied at a batch boundary
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    counters: Vec<CounterSnapshot>,
+    arrival_baseline: Option<Vec<HourBaseline>>,
+    threads: Option<HashMap<String, ThreadState>>,
+    known_senders: Option<KnownSenders>,
+    history_id: String,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf, account: Option<String>) -> Self {
+        Self {
+            path,
+            account,
+            write_lock: Mutex::new(()),
+            arrival_baseline: OnceLock::new(),
+            threads: OnceLock::new(),
+            sender_history: OnceLock::new(),
+            checkpoint: Mutex::new(None),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ExporterState)) {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut state = self.load();
+        f(&mut state);
+
+        write_atomically(&self.path, &serde_json::to_string_pretty(&state).unwrap());
+    }
+
+    pub fn load(&self) -> ExporterState {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to parse state file {}: {}",
+                    self.path.display(),
+                    err
+                )
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => ExporterState::default(),
+            Err(err) => panic!("Failed to read state file {}: {}", self.path.display(), err),
+        }
+    }
+
+    /// Re-seeds the recorder (and our running totals) from the last snapshot.
+    pub fn restore_counters(&self) {
+        let state = self.load();
+
+        for snapshot in &state.counters {
+            register_counter!(snapshot.name.clone(), &snapshot.labels).absolute(snapshot.value);
+            record_counter(&snapshot.name, &snapshot.labels, snapshot.value);
+        }
+
+        if !state.counters.is_empty() {
+            info!(
+                "Restored {} counter series from {}",
+                state.counters.len(),
+                self.path.display()
+            );
+        }
+    }
+
+    /// Restores the baseline from the last snapshot and saves it with every
+    /// snapshot from now on
+    pub fn track_arrival_baseline(&self, arrival_baseline: Arc<ArrivalBaseline>) {
+        arrival_baseline.restore(self.load().arrival_baseline);
+        let _ = self.arrival_baseline.set(arrival_baseline);
+    }
+
+    /// Likewise for the threads being tracked
+    pub fn track_threads(&self, threads: Arc<ThreadTracker>) {
+        threads.restore(self.load().threads);
+        let _ = self.threads.set(threads);
+    }
+
+    /// Likewise for the senders seen so far
+    pub fn track_senders(&self, sender_history: Arc<SenderHistory>) {
+        sender_history.restore(self.load().known_senders);
+        let _ = self.sender_history.set(sender_history);
+    }
+
+    /// Writes the last checkpoint again, e.g. on shutdown; nothing before
+    /// the first one
+    pub fn save(&self) {
+        let checkpoint = self.checkpoint.lock().unwrap();
+        if let Some(checkpoint) = &*checkpoint {
+            self.write_checkpoint(checkpoint);
+        }
+    }
+
+    /// Saves everything along with the history ID it covers, in one write, so
+    /// the counters and the position never disagree after a crash. Only call
+    /// this between batches, once everything before `history_id` is recorded.
+    pub fn save_checkpoint(&self, history_id: &str) {
+        let counters = counters()
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((_, labels), _)| match &self.account {
+                Some(account) => labels
+                    .iter()
+                    .any(|(key, value)| key == "account" && value == account),
+                None => true,
+            })
+            .map(|((name, labels), value)| CounterSnapshot {
+                name: name.clone(),
+                labels: labels.clone(),
+                value: *value,
+            })
+            .collect();
+        let checkpoint = Checkpoint {
+            counters,
+            arrival_baseline: self
+                .arrival_baseline
+                .get()
+                .map(|arrival_baseline| arrival_baseline.baselines()),
+            threads: self.threads.get().map(|threads| threads.threads()),
+            known_senders: self
+                .sender_history
+                .get()
+                .map(|sender_history| sender_history.known()),
+            history_id: history_id.to_owned(),
+        };
+
+        let mut saved = self.checkpoint.lock().unwrap();
+        self.write_checkpoint(&checkpoint);
+        *saved = Some(checkpoint);
+    }
+
+    fn write_checkpoint(&self, checkpoint: &Checkpoint) {
+        self.update(|state| {
+            state.counters = checkpoint.counters.clone();
+            if let Some(arrival_baseline) = &checkpoint.arrival_baseline {
+                state.arrival_baseline = arrival_baseline.clone();
+            }
+            if let Some(threads) = &checkpoint.threads {
+                state.threads = threads.clone();
+            }
+            if let Some(known_senders) = &checkpoint.known_senders {
+                state.known_senders = known_senders.clone();
+            }
+            state.history_id = Some(checkpoint.history_id.clone());
+        });
+    }
+
+    pub fn save_snapshot(&self, snapshot: &MailboxSnapshot) {
+        self.update(|state| state.snapshot = Some(snapshot.clone()));
+    }
+
+    /// Saves how far a backfill got, or forgets it once it's done
+    pub fn save_backfill(&self, progress: Option<&BackfillProgress>) {
+        self.update(|state| state.backfill = progress.cloned());
+    }
+
+    /// Moves the position on without new counters, past mail that won't be
+    /// counted: a history reset, or a batch saved before it's recorded
+    pub fn save_history_id(&self, history_id: &str) {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        if let Some(checkpoint) = &mut *checkpoint {
+            checkpoint.history_id = history_id.to_owned();
+        }
+        self.update(|state| state.history_id = Some(history_id.to_owned()));
+    }
+}
+
+/// Write to a sibling temp file and rename over the target, so a crash
+/// mid-write never leaves a truncated state file behind.
+fn write_atomically(path: &Path, contents: &str) {
+    let tmp_path = path.with_extension("tmp");
+
+    if let Err(err) =
+        std::fs::write(&tmp_path, contents).and_then(|_| std::fs::rename(&tmp_path, path))
+    {
+        error!("Failed to write state file {}: {}", path.display(), err);
+    }
+}
+
+/// Periodically write the last checkpoint; the final save on shutdown is up
+/// to the caller.
+pub fn spawn_snapshotting(state_file: std::sync::Arc<StateFile>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            state_file.save();
+        }
+    });
+}
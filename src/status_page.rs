@@ -0,0 +1,197 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+
+use crate::debug_status::{AccountStatus, DebugStatus, PollOutcome};
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+h2 { border-bottom: 1px solid #ccc; padding-bottom: .2em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { text-align: left; padding: .2em 1em .2em 0; vertical-align: top; }
+.ok { color: #080; } .warn { color: #b60; } .muted { color: #888; }
+";
+
+/// The human-readable counterpart of `/debug/status`, served at `/`
+pub fn render(status: &DebugStatus) -> String {
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"30\">\
+         <title>Gmail exporter status</title><style>{}</style></head><body>\
+         <h1>Gmail exporter</h1>\
+         <p class=\"muted\">{} {} &middot; <a href=\"metrics\">metrics</a> &middot; \
+         <a href=\"debug/status\">status as JSON</a></p>\
+         <p class=\"muted\">User-Agent: {}</p>",
+        STYLE,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        escape(&status.user_agent),
+    )
+    .unwrap();
+
+    if status.accounts.is_empty() {
+        html.push_str("<p>No mailbox has been polled yet.</p>");
+    }
+    for (account, account_status) in &status.accounts {
+        render_account(&mut html, account, account_status);
+    }
+
+    html.push_str("</body></html>");
+    html
+}
+
+fn render_account(html: &mut String, account: &str, status: &AccountStatus) {
+    let now = Utc::now();
+    write!(html, "<h2>{}</h2><table>", escape(account)).unwrap();
+
+    let auth = match status.access_token_expires_at {
+        Some(expires_at) if expires_at > now => format!(
+            "<span class=\"ok\">Access token valid</span> until {}",
+            timestamp(expires_at, now)
+        ),
+        Some(expires_at) => format!(
+            "<span class=\"warn\">Access token expired</span> {}; it's refreshed on the next request",
+            timestamp(expires_at, now)
+        ),
+        None => "<span class=\"muted\">Not refreshed yet</span>".to_owned(),
+    };
+    row(html, "Auth", &auth);
+
+    let last_poll = match &status.last_poll {
+        Some(poll) => {
+            let outcome = match poll.outcome {
+                PollOutcome::Ok { messages } => {
+                    format!("<span class=\"ok\">{} new messages</span>", messages)
+                }
+                PollOutcome::HistoryReset => {
+                    "<span class=\"warn\">history ID expired and was reset</span>".to_owned()
+                }
+                PollOutcome::Failed => "<span class=\"warn\">failed</span>".to_owned(),
+            };
+            format!("{}: {}", timestamp(poll.at, now), outcome)
+        }
+        None => "<span class=\"muted\">Not polled yet</span>".to_owned(),
+    };
+    row(html, "Last poll", &last_poll);
+    row(
+        html,
+        "Cursor",
+        &escape(status.history_id.as_deref().unwrap_or("none")),
+    );
+    row(
+        html,
+        "Messages (24h)",
+        &status.messages_last_24h.to_string(),
+    );
+    if status.backlog_messages > 0 {
+        row(
+            html,
+            "Backlog",
+            &format!(
+                "<span class=\"warn\">{} messages waiting</span>",
+                status.backlog_messages
+            ),
+        );
+    }
+    html.push_str("</table>");
+
+    if !status.top_senders.is_empty() {
+        html.push_str("<h3>Top senders</h3><table><tr><th>Sender</th><th>Messages</th></tr>");
+        for sender in &status.top_senders {
+            write!(
+                html,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(&sender.sender),
+                sender.messages
+            )
+            .unwrap();
+        }
+        html.push_str("</table>");
+    }
+
+    let Some(settings) = &status.settings else {
+        return;
+    };
+    html.push_str("<h3>Configuration</h3><table>");
+    row(
+        html,
+        "Labels included",
+        &list_or(&settings.label_filters.include, "all"),
+    );
+    row(
+        html,
+        "Labels excluded",
+        &list_or(&settings.label_filters.exclude, "none"),
+    );
+    row(
+        html,
+        "Max received series",
+        &settings
+            .max_received_series
+            .map(|max| max.to_string())
+            .unwrap_or_else(|| "unlimited".to_owned()),
+    );
+    row(
+        html,
+        "Time labels",
+        &match settings.time_labels {
+            true => format!("on, in {}", escape(&settings.timezone)),
+            false => "off".to_owned(),
+        },
+    );
+    row(
+        html,
+        "Streams",
+        &list_or(
+            &settings
+                .streams
+                .iter()
+                .map(|stream| format!("{}: {}", stream.name, stream.query))
+                .collect::<Vec<_>>(),
+            "none",
+        ),
+    );
+    row(html, "Rules", &list_or(&settings.rules, "none"));
+    html.push_str("</table>");
+}
+
+fn row(html: &mut String, name: &str, value: &str) {
+    write!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value).unwrap();
+}
+
+fn list_or(items: &[String], empty: &str) -> String {
+    match items.is_empty() {
+        true => format!("<span class=\"muted\">{}</span>", empty),
+        false => items
+            .iter()
+            .map(|item| escape(item))
+            .collect::<Vec<_>>()
+            .join("<br>"),
+    }
+}
+
+fn timestamp(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - at).num_seconds();
+    let relative = match seconds.abs() {
+        0..=59 => format!("{}s", seconds.abs()),
+        60..=3599 => format!("{}m", seconds.abs() / 60),
+        _ => format!("{}h", seconds.abs() / 3600),
+    };
+    format!(
+        "{} <span class=\"muted\">({})</span>",
+        at.format("%Y-%m-%d %H:%M:%S UTC"),
+        match seconds >= 0 {
+            true => format!("{} ago", relative),
+            false => format!("in {}", relative),
+        }
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
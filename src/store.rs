@@ -0,0 +1,177 @@
+use std::{path::Path, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row};
+use tracing::warn;
+
+use crate::{
+    events::MessageEvent,
+    mail::{category_of, ParseForMetrics, UsableMessageDetails},
+};
+
+/// SQLite database with a row for every message we've counted, so messages
+/// Gmail hands us again after a restart aren't counted twice, and so the
+/// history can be queried with plain SQL.
+pub struct MessageStore {
+    connection: Mutex<Connection>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS messages (
+    account TEXT NOT NULL,
+    id TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    from_address TEXT,
+    from_domain TEXT,
+    to_address TEXT,
+    to_domain TEXT,
+    subject TEXT NOT NULL,
+    -- JSON array of label names
+    labels TEXT NOT NULL,
+    -- Unix milliseconds
+    internal_date INTEGER NOT NULL,
+    size_estimate INTEGER NOT NULL,
+    observed_at INTEGER NOT NULL,
+    PRIMARY KEY (account, id)
+);
+CREATE INDEX IF NOT EXISTS messages_thread ON messages (account, thread_id);
+CREATE INDEX IF NOT EXISTS messages_internal_date ON messages (internal_date);
+CREATE INDEX IF NOT EXISTS messages_observed_at ON messages (observed_at);
+";
+
+impl MessageStore {
+    pub fn open(path: &Path) -> Self {
+        let connection = Connection::open(path)
+            .unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err));
+        connection
+            .execute_batch(SCHEMA)
+            .unwrap_or_else(|err| panic!("Failed to create tables in {}: {}", path.display(), err));
+
+        Self {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    /// Whether `record` has stored the message before
+    pub fn contains(&self, account: Option<&str>, id: &str) -> bool {
+        let result = self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM messages WHERE account = ?1 AND id = ?2)",
+            params![account.unwrap_or_default(), id],
+            |row| row.get(0),
+        );
+
+        result.unwrap_or_else(|err| {
+            // Counting matters more than the record, so count it anyway
+            warn!(message_id = id, "Failed to look up stored message: {}", err);
+            false
+        })
+    }
+
+    /// Returns false if the message was already recorded. The single mailbox
+    /// without a configured account name is stored under `''`.
+    pub fn record(&self, account: Option<&str>, message: &UsableMessageDetails) -> bool {
+        let result = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO messages (
+                account, id, thread_id, from_address, from_domain, to_address, to_domain,
+                subject, labels, internal_date, size_estimate, observed_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                account.unwrap_or_default(),
+                message.id,
+                message.thread_id,
+                message.from.first_address(),
+                message.from.first_domain(),
+                message.to.first_address(),
+                message.to.first_domain(),
+                message.subject,
+                serde_json::to_string(&message.labels).unwrap(),
+                message.internal_date.timestamp_millis(),
+                message.size_estimate as i64,
+                chrono::Utc::now().timestamp_millis(),
+            ],
+        );
+
+        match result {
+            Ok(inserted) => inserted > 0,
+            Err(err) => {
+                // Counting matters more than the record, so count it anyway
+                warn!(message_id = message.id, "Failed to store message: {}", err);
+                true
+            }
+        }
+    }
+
+    /// Messages first observed after `since`, oldest first, optionally only
+    /// from one account
+    pub fn observed_since(
+        &self,
+        since: DateTime<Utc>,
+        account: Option<&str>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<MessageEvent>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT account, id, thread_id, from_address, from_domain, to_address, to_domain,
+                subject, labels, internal_date, observed_at
+            FROM messages
+            WHERE observed_at > ?1 AND (?2 IS NULL OR account = ?2)
+            ORDER BY observed_at
+            LIMIT ?3",
+        )?;
+
+        let rows = statement.query_map(
+            params![since.timestamp_millis(), account, limit as i64],
+            message_event,
+        )?;
+
+        rows.collect()
+    }
+
+    /// Messages received (by their internal date) after `since`, oldest
+    /// first, optionally only from one account
+    pub fn received_since(
+        &self,
+        since: DateTime<Utc>,
+        account: Option<&str>,
+    ) -> rusqlite::Result<Vec<MessageEvent>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT account, id, thread_id, from_address, from_domain, to_address, to_domain,
+                subject, labels, internal_date, observed_at
+            FROM messages
+            WHERE internal_date > ?1 AND (?2 IS NULL OR account = ?2)
+            ORDER BY internal_date",
+        )?;
+
+        let rows =
+            statement.query_map(params![since.timestamp_millis(), account], message_event)?;
+        rows.collect()
+    }
+}
+
+/// A row selected with the columns of `observed_since`, in that order
+fn message_event(row: &Row) -> rusqlite::Result<MessageEvent> {
+    let account: String = row.get(0)?;
+    let labels: Vec<String> = serde_json::from_str(&row.get::<_, String>(8)?).unwrap_or_default();
+    let millis = |index| {
+        row.get::<_, i64>(index)
+            .map(|millis| DateTime::from_timestamp_millis(millis).unwrap_or_default())
+    };
+
+    Ok(MessageEvent {
+        event_type: "message",
+        account: (!account.is_empty()).then_some(account),
+        id: row.get(1)?,
+        thread_id: row.get(2)?,
+        from: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        from_domain: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        to: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+        to_domain: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+        subject: row.get(7)?,
+        category: category_of(&labels),
+        snippet: None,
+        labels,
+        date: millis(9)?,
+        observed_at: millis(10)?,
+    })
+}
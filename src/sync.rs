@@ -0,0 +1,190 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::{TimeDelta, Utc};
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::{
+    backfill::BackfillProgress,
+    events::{EventSink, MessageEvent},
+    mail::MailClient,
+    report::parse_since,
+    state::StateFile,
+    store::MessageStore,
+};
+
+/// Messages fetched and stored per batch
+const BATCH_SIZE: usize = 500;
+
+/// How long to wait for the sinks to deliver what was published before
+/// exiting anyway
+const SINK_CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Args)]
+pub struct SyncOptions {
+    /// SQLite database to fill, as later given to `watch-inbox --sqlite-path`
+    /// and `report`
+    #[arg(long, env = "SQLITE_PATH")]
+    pub sqlite_path: PathBuf,
+
+    /// How far back to sync, e.g. `90d`
+    #[arg(long, env = "SYNC_SINCE", default_value = "90d", value_parser = parse_since)]
+    pub since: TimeDelta,
+
+    /// Additional Gmail search query to narrow the sync, e.g. `in:inbox`
+    #[arg(long, env = "SYNC_QUERY")]
+    pub query: Option<String>,
+
+    /// Store the messages under this `[[accounts]]` entry's name
+    #[arg(long, env = "SYNC_ACCOUNT")]
+    pub account: Option<String>,
+
+    /// Save the history ID from the start of the sync here, so `watch-inbox`
+    /// with the same --state-file carries on from where the sync began
+    /// rather than from whenever it's started. Progress is saved here too,
+    /// so an interrupted sync run again carries on where it stopped.
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub listed: usize,
+    /// Listed messages that weren't in the database yet
+    pub stored: usize,
+}
+
+/// Record every message in the window in the database, oldest first, and
+/// publish an event for each one that's new to it. With a state file, mail
+/// that arrived after the saved history ID is left for the watcher carrying
+/// on from it, so it's counted there rather than skipped as already stored.
+pub async fn run(
+    options: &SyncOptions,
+    mail: &mut MailClient,
+    event_sinks: &[Arc<dyn EventSink>],
+) -> Result<SyncSummary, String> {
+    let labels = mail.load_labels().await.map_err(|err| err.to_string())?;
+
+    let state_file = options
+        .state_file
+        .as_ref()
+        .map(|path| StateFile::new(path.clone(), options.account.clone()));
+    let name = format!(
+        "sync {} {}",
+        options.sqlite_path.display(),
+        options.query.as_deref().unwrap_or_default()
+    );
+    let resumed = state_file
+        .as_ref()
+        .and_then(|state_file| state_file.load().backfill)
+        .filter(|progress| progress.name == name && progress.history_id.is_some());
+    let mut progress = match resumed {
+        // The window and history ID of the run being resumed, so nothing
+        // falls between the two runs
+        Some(progress) => {
+            info!(
+                "Resuming the sync after the {} messages already synced",
+                progress.written
+            );
+            progress
+        }
+        None => {
+            // Epoch seconds rather than a date, which Gmail would read in
+            // the account's own timezone
+            let mut query = format!("after:{}", (Utc::now() - options.since).timestamp());
+            if let Some(extra) = &options.query {
+                query.push_str(&format!(" ({})", extra));
+            }
+            let mut progress = BackfillProgress::new(name, query);
+            progress.history_id = Some(
+                mail.fetch_profile()
+                    .await
+                    .map_err(|err| err.to_string())?
+                    .history_id,
+            );
+            progress
+        }
+    };
+    let history_id = progress.history_id.clone().unwrap_or_default();
+
+    let query = progress.remaining_query();
+    info!("Listing messages matching {}", query);
+    // A backfill lists everything, whatever --max-pages says
+    let (mut listing, _) = mail
+        .search_message_pages(&query, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    listing.reverse();
+    progress.skip_written(&mut listing);
+
+    if options.state_file.is_some() {
+        let arrived_since = mail
+            .fetch_history_pages(&history_id, None)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or("Gmail expired the history ID from the start of the sync")?
+            .messages_added
+            .into_iter()
+            .map(|message| message.id)
+            .collect::<HashSet<_>>();
+        listing.retain(|message| !arrived_since.contains(&message.id));
+    }
+    info!("Syncing {} messages", listing.len());
+
+    let store = MessageStore::open(&options.sqlite_path);
+    let mut summary = SyncSummary {
+        listed: listing.len(),
+        ..Default::default()
+    };
+    let mut fetched = 0;
+    for batch in listing.chunks(BATCH_SIZE) {
+        let messages = mail
+            .fetch_mail_details(batch.to_vec(), &labels)
+            .await
+            .map_err(|err| err.to_string())?;
+        fetched += messages.len();
+        for message in &messages {
+            if !store.record(options.account.as_deref(), message) {
+                continue;
+            }
+            summary.stored += 1;
+            let event = MessageEvent::new(options.account.as_deref(), message, None);
+            for sink in event_sinks {
+                sink.publish_waiting(&event).await;
+            }
+        }
+        info!("Synced {}/{} messages", fetched, listing.len());
+
+        progress.advance(&messages);
+        if let Some(state_file) = &state_file {
+            state_file.save_backfill(Some(&progress));
+        }
+    }
+
+    let closing = async {
+        for sink in event_sinks {
+            sink.close().await;
+        }
+    };
+    if tokio::time::timeout(SINK_CLOSE_TIMEOUT, closing)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Sinks didn't deliver everything within {:?}, exiting anyway",
+            SINK_CLOSE_TIMEOUT
+        );
+    }
+
+    if let Some(state_file) = &state_file {
+        state_file.save_backfill(None);
+        state_file.save_history_id(&history_id);
+        info!(
+            "Saved history id {} to {}",
+            history_id,
+            state_file.path.display()
+        );
+    }
+
+    Ok(summary)
+}
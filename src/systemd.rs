@@ -0,0 +1,23 @@
+use sd_notify::NotifyState;
+use tracing::{debug, warn};
+
+fn notify(state: NotifyState) {
+    // NOTIFY_SOCKET is left set so later notifications keep working
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", err);
+    }
+}
+
+/// Tells systemd we're authenticated and have completed a poll.
+pub fn notify_ready() {
+    debug!("Notifying systemd that we're ready");
+    notify(NotifyState::Ready);
+}
+
+pub fn ping_watchdog() {
+    notify(NotifyState::Watchdog);
+}
+
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds of the `thread_bucket` label values, by how many messages
+/// the thread had with the new one
+const BUCKETS: [(u64, &str); 5] = [(1, "1"), (2, "2"), (5, "3-5"), (10, "6-10"), (20, "11-20")];
+const LAST_BUCKET: &str = "21+";
+
+/// How often quiet threads are swept out of memory; a quiet thread that
+/// hasn't been swept yet still starts afresh when it's next observed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What's remembered about a thread between its messages
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ThreadState {
+    pub messages: u64,
+    /// When a message in the thread was last observed, rather than its
+    /// date, so a backlog of old mail doesn't count as quiet threads
+    #[serde(alias = "last_message")]
+    pub last_observed: DateTime<Utc>,
+}
+
+/// A message's place in its thread
+pub struct ThreadMessage {
+    /// The first message seen in the thread
+    pub started: bool,
+    /// How long the thread is now, as a `thread_bucket` label value
+    pub bucket: &'static str,
+}
+
+/// Counts messages per thread, by thread ID, forgetting threads nothing has
+/// been observed in for longer than `idle`. A reply to a thread that was
+/// already forgotten, or from before tracking began, starts it afresh.
+pub struct ThreadTracker {
+    idle: Duration,
+    threads: Mutex<Threads>,
+}
+
+struct Threads {
+    by_id: HashMap<String, ThreadState>,
+    last_pruned: DateTime<Utc>,
+}
+
+impl ThreadTracker {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            threads: Mutex::new(Threads {
+                by_id: HashMap::new(),
+                last_pruned: Utc::now(),
+            }),
+        }
+    }
+
+    /// Picks up the threads saved in the state file
+    pub fn restore(&self, threads: HashMap<String, ThreadState>) {
+        self.threads.lock().unwrap().by_id = threads;
+    }
+
+    pub fn threads(&self) -> HashMap<String, ThreadState> {
+        self.threads.lock().unwrap().by_id.clone()
+    }
+
+    pub fn observe(&self, thread_id: &str) -> ThreadMessage {
+        let idle = chrono::TimeDelta::from_std(self.idle).unwrap_or(chrono::TimeDelta::MAX);
+        let now = Utc::now();
+        let mut threads = self.threads.lock().unwrap();
+        if now - threads.last_pruned >= chrono::TimeDelta::from_std(PRUNE_INTERVAL).unwrap() {
+            threads
+                .by_id
+                .retain(|_, thread| now - thread.last_observed < idle);
+            threads.last_pruned = now;
+        }
+
+        let thread = threads
+            .by_id
+            .entry(thread_id.to_owned())
+            .or_insert(ThreadState {
+                messages: 0,
+                last_observed: now,
+            });
+        if now - thread.last_observed >= idle {
+            thread.messages = 0;
+        }
+        thread.messages += 1;
+        thread.last_observed = now;
+
+        ThreadMessage {
+            started: thread.messages == 1,
+            bucket: bucket(thread.messages),
+        }
+    }
+}
+
+fn bucket(messages: u64) -> &'static str {
+    BUCKETS
+        .iter()
+        .find(|(max, _)| messages <= *max)
+        .map_or(LAST_BUCKET, |(_, bucket)| bucket)
+}
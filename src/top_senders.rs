@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::exposition::{self, Collector};
+
+/// Number of sub-windows the rolling window is split into; the oldest one is
+/// dropped as time moves on.
+const BUCKETS: u64 = 24;
+
+/// Space-saving sketch: tracks at most `capacity` senders, evicting the
+/// smallest count (and inheriting it) when a new sender shows up.
+#[derive(Debug, Default)]
+struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::new(),
+        }
+    }
+
+    fn observe(&mut self, sender: &str) {
+        if let Some(count) = self.counts.get_mut(sender) {
+            *count += 1;
+            return;
+        }
+
+        if self.counts.len() < self.capacity {
+            self.counts.insert(sender.to_owned(), 1);
+            return;
+        }
+
+        let (smallest_sender, smallest_count) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, count)| **count)
+            .map(|(sender, count)| (sender.clone(), *count))
+            .unwrap();
+
+        self.counts.remove(&smallest_sender);
+        self.counts.insert(sender.to_owned(), smallest_count + 1);
+    }
+}
+
+/// Approximate heaviest senders over a rolling window, exposed as a bounded
+/// set of `gmail_top_sender_messages` gauges.
+pub struct TopSenders {
+    metric: &'static str,
+    help: &'static str,
+    top_n: usize,
+    bucket_length: Duration,
+    base_labels: Vec<(String, String)>,
+    buckets: Mutex<Vec<(u64, SpaceSaving)>>,
+}
+
+impl TopSenders {
+    pub fn new(top_n: usize, window: Duration, base_labels: Vec<(String, String)>) -> Self {
+        Self {
+            metric: "gmail_top_sender_messages",
+            help: "Approximate messages from each of the heaviest senders over the rolling window.",
+            top_n,
+            bucket_length: (window / BUCKETS as u32).max(Duration::from_secs(1)),
+            base_labels,
+            buckets: Mutex::new(vec![]),
+        }
+    }
+
+    /// Expose the gauges under another name, to track a subset of mail separately
+    pub fn with_metric(mut self, metric: &'static str, help: &'static str) -> Self {
+        self.metric = metric;
+        self.help = help;
+        self
+    }
+
+    fn current_bucket(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / self.bucket_length.as_secs()
+    }
+
+    pub fn observe(&self, sender: &str) {
+        let current = self.current_bucket();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        buckets.retain(|(bucket, _)| bucket + BUCKETS > current);
+
+        if buckets.last().map(|(bucket, _)| *bucket) != Some(current) {
+            // Track extra candidates so the top N stays accurate after merging buckets
+            buckets.push((current, SpaceSaving::new((self.top_n * 10).max(100))));
+        }
+
+        buckets.last_mut().unwrap().1.observe(sender);
+    }
+
+    pub fn top(&self) -> Vec<(String, u64)> {
+        let current = self.current_bucket();
+        let buckets = self.buckets.lock().unwrap();
+
+        let mut merged: HashMap<&str, u64> = HashMap::new();
+        for (_, sketch) in buckets
+            .iter()
+            .filter(|(bucket, _)| bucket + BUCKETS > current)
+        {
+            for (sender, count) in &sketch.counts {
+                *merged.entry(sender).or_default() += count;
+            }
+        }
+
+        let mut top = merged
+            .into_iter()
+            .map(|(sender, count)| (sender.to_owned(), count))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(self.top_n);
+        top
+    }
+}
+
+impl Collector for TopSenders {
+    fn header(&self) -> String {
+        format!(
+            "# HELP {metric} {help}\n# TYPE {metric} gauge\n",
+            metric = self.metric,
+            help = self.help
+        )
+    }
+
+    fn render_series(&self) -> String {
+        let mut output = String::new();
+
+        for (sender, count) in self.top() {
+            let mut labels = self.base_labels.clone();
+            labels.push(("from".to_owned(), sender));
+            output.push_str(&format!(
+                "{} {}\n",
+                exposition::format_series(self.metric, &labels),
+                count
+            ));
+        }
+
+        output
+    }
+}
@@ -0,0 +1,710 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use rand::Rng;
+use tracing::{debug, error, info, instrument, warn, Span};
+
+use crate::{
+    backend::MailBackend,
+    debug_status::{self, PollOutcome},
+    dmarc,
+    error::Result,
+    http_trace,
+    mail::{MinimalMessage, UsableMessageDetails},
+    pipeline::MetricsPipeline,
+    pubsub::PubSubWatch,
+    state::StateFile,
+    store::MessageStore,
+    systemd,
+};
+
+/// Messages whose details are fetched, recorded and moved past at a time, so
+/// a long catch-up never holds more than this many in memory and a crash
+/// midway loses at most one batch of progress
+const DETAILS_BATCH_SIZE: usize = 100;
+
+/// A dead-man-switch that doesn't answer in time mustn't hold up polling
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `gmail_inbox_age_bucket` series, by their `older_than` label and age in
+/// days. Each counts everything older, so they nest like histogram buckets.
+const INBOX_AGE_BUCKETS: [(&str, i64); 3] = [("1d", 1), ("7d", 7), ("30d", 30)];
+
+/// Flipped once on shutdown, for every watcher in the process
+static STOP: OnceLock<tokio::sync::watch::Sender<bool>> = OnceLock::new();
+
+fn stop_sender() -> &'static tokio::sync::watch::Sender<bool> {
+    STOP.get_or_init(|| tokio::sync::watch::Sender::new(false))
+}
+
+/// For `Watcher::stopping`
+pub fn stopping() -> tokio::sync::watch::Receiver<bool> {
+    stop_sender().subscribe()
+}
+
+/// Asks every watcher to stop at its next batch boundary, so the state file
+/// can be saved with nothing half recorded
+pub fn stop() {
+    stop_sender().send_replace(true);
+}
+
+/// A fraction of at least 0 and below 1, so a jittered interval stays positive
+pub fn parse_jitter(value: &str) -> Result<f64, String> {
+    let jitter = value
+        .parse::<f64>()
+        .map_err(|err| format!("expected a fraction, e.g. 0.1: {}", err))?;
+    if (0.0..1.0).contains(&jitter) {
+        Ok(jitter)
+    } else {
+        Err(format!("must be at least 0 and below 1, got {}", jitter))
+    }
+}
+
+/// Decides how long to wait between polls. In adaptive mode the interval
+/// halves after a poll that found mail and doubles after an idle one, within
+/// `[min, max]`; jitter then spreads it by up to ±`jitter` of itself.
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    pub current: Duration,
+    pub adaptive: Option<(Duration, Duration)>,
+    pub jitter: f64,
+}
+
+impl PollSchedule {
+    pub fn next_delay(&mut self, found_mail: bool) -> Duration {
+        if let Some((min, max)) = self.adaptive {
+            self.current = if found_mail {
+                (self.current / 2).max(min)
+            } else {
+                (self.current * 2).min(max)
+            };
+        }
+
+        if self.jitter > 0.0 {
+            let factor = rand::rng().random_range(-self.jitter..=self.jitter);
+            self.current.mul_f64(1.0 + factor)
+        } else {
+            self.current
+        }
+    }
+}
+
+/// What the watcher does once polls have kept failing for the failure budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FailurePolicy {
+    /// Keep retrying every interval, however long it takes
+    Retry,
+    /// Exit with an error, for an orchestrator to restart
+    #[default]
+    Exit,
+    /// Stop polling but keep serving /metrics, with `gmail_poll_failing` at 1
+    Serve,
+}
+
+/// What a crash in the middle of a poll can do to a batch of messages. Only
+/// matters with a state file, which is what survives the crash. Either way
+/// the state file only ever holds counters as of a batch boundary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Delivery {
+    /// Save the counters and the position past a batch together once it has
+    /// been recorded. A crash before that lists the batch again: the saved
+    /// counters still count it once, but sinks and notifications may see it
+    /// twice.
+    #[default]
+    AtLeastOnce,
+    /// Save the position past a batch before recording it. A crash midway
+    /// loses the rest of the batch, but nothing is ever sent twice.
+    AtMostOnce,
+}
+
+/// Polls Gmail history for new messages and feeds them through the pipeline.
+pub struct Watcher {
+    pub mail: Box<dyn MailBackend>,
+    pub pipeline: Arc<MetricsPipeline>,
+    pub starting_from: String,
+    pub schedule: PollSchedule,
+    pub state_file: Option<Arc<StateFile>>,
+    /// Send sd_notify readiness after the first poll and a watchdog ping every poll
+    pub systemd: bool,
+    /// Process at most this many messages per poll, leaving the rest for later polls
+    pub max_messages_per_poll: Option<usize>,
+    /// Messages found by an earlier poll that are still waiting to be processed
+    pub backlog: VecDeque<MinimalMessage>,
+    /// Keep a Gmail watch on a Pub/Sub topic and poll early when pushes arrive
+    pub pubsub: Option<PubSubWatch>,
+    /// Record every message, and skip ones recorded before
+    pub store: Option<Arc<MessageStore>>,
+    /// Download and count DMARC aggregate reports found in new mail
+    pub dmarc_reports: bool,
+    /// Report the number of drafts every poll
+    pub drafts: bool,
+    /// With `drafts`, also report how many are older than this
+    pub stale_draft_age: Option<Duration>,
+    /// Report the number of starred messages every poll
+    pub starred: bool,
+    /// Report the size of the spam folder every poll, and count mail arriving in it
+    pub spam: bool,
+    /// Report the size of the trash every poll, and count mail moved to it or deleted
+    pub trash: bool,
+    /// How often to check the vacation responder and send-as aliases
+    pub settings_interval: Option<Duration>,
+    pub settings_checked_at: Option<Instant>,
+    /// How often to count inbox messages by age, for `INBOX_AGE_BUCKETS`
+    pub inbox_age_interval: Option<Duration>,
+    pub inbox_age_checked_at: Option<Instant>,
+    pub delivery: Delivery,
+    /// Pinged after every poll that got through, e.g. a healthchecks.io check
+    pub heartbeat_url: Option<String>,
+    pub on_failure: FailurePolicy,
+    /// How long polls may keep failing before `on_failure` applies
+    pub failure_budget: Duration,
+    /// When the current run of failed polls began
+    pub failing_since: Option<Instant>,
+    /// Set on shutdown; the watcher stops at the next batch boundary
+    pub stopping: tokio::sync::watch::Receiver<bool>,
+}
+
+impl Watcher {
+    /// Returns an error once polls have failed for longer than the failure
+    /// budget, under the exit policy, and Ok once stopped for shutdown
+    pub async fn run(mut self) -> Result<(), String> {
+        info!("Beginning silent watch for new mail...");
+
+        let mut ready = false;
+
+        loop {
+            if *self.stopping.borrow() {
+                return Ok(());
+            }
+
+            let found = self.poll().await?;
+
+            if self.systemd {
+                if !ready {
+                    systemd::notify_ready();
+                    ready = true;
+                }
+                systemd::ping_watchdog();
+            }
+            let Some(found) = found else {
+                return self.keep_serving().await;
+            };
+
+            let delay = self
+                .pipeline
+                .poll_delay(self.schedule.next_delay(found > 0));
+            debug!("Next poll in {:?}", delay);
+            let wake = match &self.pubsub {
+                Some(pubsub) => Some(pubsub.wake.clone()),
+                None => self.mail.wake(),
+            };
+            let stopping = self.stopping.wait_for(|stopping| *stopping);
+            match wake {
+                Some(wake) => tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = wake.notified() => debug!("Woken early by a change notification"),
+                    _ = stopping => {}
+                },
+                None => tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = stopping => {}
+                },
+            }
+        }
+    }
+
+    /// Under the serve policy: no more polls, but the systemd watchdog keeps
+    /// getting pinged so /metrics stays up
+    async fn keep_serving(mut self) -> Result<(), String> {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.schedule.next_delay(false)) => {}
+                _ = self.stopping.wait_for(|stopping| *stopping) => return Ok(()),
+            }
+            if self.systemd {
+                systemd::ping_watchdog();
+            }
+        }
+    }
+
+    /// Polls once, applying the failure policy if that fails. Returns the
+    /// number of new messages found, None once the serve policy has stopped
+    /// polling, and an error once the exit policy gives up.
+    async fn poll(&mut self) -> Result<Option<usize>, String> {
+        let poll = async {
+            self.renew_pubsub().await?;
+            self.poll_once().await
+        };
+        match poll.await {
+            Ok(found) => {
+                if self.failing_since.take().is_some() {
+                    info!("Polling has recovered");
+                    self.pipeline.record_poll_failing(false);
+                }
+                Ok(Some(found))
+            }
+            Err(err) => {
+                warn!("Poll failed: {}", err);
+                Ok(self.poll_failed()?.then_some(0))
+            }
+        }
+    }
+
+    async fn renew_pubsub(&mut self) -> Result<()> {
+        if let Some(pubsub) = &mut self.pubsub {
+            if pubsub.needs_renewal() {
+                let watch = self.mail.watch_pubsub(&pubsub.topic).await?;
+                let expires_at = pubsub.renewed(&watch);
+                info!(
+                    "Registered Gmail watch on {} (expires at {})",
+                    pubsub.topic, expires_at
+                );
+                self.pipeline.record_watch_renewal(expires_at);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the failure policy once the budget is used up, returning
+    /// whether to keep polling. Whatever was left of the backlog is listed
+    /// again from the saved position next poll.
+    fn poll_failed(&mut self) -> Result<bool, String> {
+        self.backlog.clear();
+        self.pipeline.record_poll_failing(true);
+        debug_status::record_poll(
+            self.pipeline.account.as_deref(),
+            &self.starting_from,
+            PollOutcome::Failed,
+            0,
+        );
+
+        let failing_for = self
+            .failing_since
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+        if failing_for < self.failure_budget {
+            warn!("Poll failed, retrying next interval");
+            return Ok(true);
+        }
+
+        match self.on_failure {
+            FailurePolicy::Retry => {
+                warn!("Polls have been failing for {:?}, retrying", failing_for);
+                Ok(true)
+            }
+            FailurePolicy::Exit => Err(format!(
+                "Polls have been failing for {:?}, giving up",
+                failing_for
+            )),
+            FailurePolicy::Serve => {
+                error!(
+                    "Polls have been failing for {:?}, no longer polling; still serving /metrics",
+                    failing_for
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns the number of new messages found
+    #[instrument(skip_all, fields(account = self.pipeline.account.as_deref(), messages))]
+    pub async fn poll_once(&mut self) -> Result<usize> {
+        if *self.stopping.borrow() {
+            return Ok(0);
+        }
+
+        // Work through mail carried over from a previous poll before asking for more
+        if self.backlog.is_empty() && !self.list_changes().await? {
+            return Ok(0);
+        }
+
+        let chunk_size = self
+            .max_messages_per_poll
+            .unwrap_or(usize::MAX)
+            .min(self.backlog.len());
+        if chunk_size < self.backlog.len() {
+            info!(
+                "Processing {} messages this poll, {} carried over to later polls",
+                chunk_size,
+                self.backlog.len() - chunk_size
+            );
+        }
+
+        self.pipeline.record_poll();
+
+        if let Some(unread) = self.mail.inbox_unread().await? {
+            self.pipeline.record_inbox_unread(unread);
+        }
+        if self.starred {
+            if let Some(starred) = self.mail.starred_total().await? {
+                self.pipeline.record_starred_total(starred);
+            }
+        }
+        if self.drafts {
+            self.record_drafts().await?;
+        }
+        if self.spam {
+            if let Some(spam) = self.mail.spam_total().await? {
+                self.pipeline.record_spam_total(spam);
+            }
+        }
+        if self.trash {
+            if let Some(trash) = self.mail.trash_total().await? {
+                self.pipeline.record_trash_total(trash);
+            }
+        }
+        if let Some(settings_interval) = self.settings_interval {
+            if self
+                .settings_checked_at
+                .is_none_or(|checked_at| checked_at.elapsed() >= settings_interval)
+            {
+                if let Some(settings) = self.mail.mailbox_settings().await? {
+                    self.pipeline.record_mailbox_settings(settings);
+                }
+                self.settings_checked_at = Some(Instant::now());
+            }
+        }
+        if let Some(inbox_age_interval) = self.inbox_age_interval {
+            if self
+                .inbox_age_checked_at
+                .is_none_or(|checked_at| checked_at.elapsed() >= inbox_age_interval)
+            {
+                self.record_inbox_age().await?;
+                self.inbox_age_checked_at = Some(Instant::now());
+            }
+        }
+
+        let mut found = 0;
+        let mut remaining = self.max_messages_per_poll.unwrap_or(usize::MAX);
+        loop {
+            let batch_size = remaining.min(self.backlog.len()).min(DETAILS_BATCH_SIZE);
+            let batch = self.backlog.drain(..batch_size).collect::<Vec<_>>();
+            remaining -= batch_size;
+            found += self.process_batch(batch).await?;
+            // The rest is listed again from the saved position after a restart
+            if remaining == 0 || *self.stopping.borrow() {
+                break;
+            }
+            // A listing that stopped at a page boundary goes on from the
+            // position just saved, once everything it listed is recorded
+            if self.backlog.is_empty() {
+                if !self.mail.more_changes() {
+                    break;
+                }
+                if !self.list_changes().await? {
+                    return Ok(found);
+                }
+            }
+        }
+
+        if let Some(lag) = self.mail.cursor_lag(&self.starting_from).await? {
+            self.pipeline.record_history_lag(lag);
+        }
+
+        Span::current().record("messages", found);
+        debug_status::record_poll(
+            self.pipeline.account.as_deref(),
+            &self.starting_from,
+            PollOutcome::Ok { messages: found },
+            self.backlog.len(),
+        );
+
+        self.heartbeat().await;
+        Ok(found)
+    }
+
+    /// Lists the changes since `starting_from` into the backlog and records
+    /// the ones that aren't new mail. Returns false if the history was reset
+    /// instead, which ends the poll.
+    async fn list_changes(&mut self) -> Result<bool> {
+        let Some(history) = self.mail.changes_since(&self.starting_from).await? else {
+            let history_id = self.mail.current_cursor().await?;
+            warn!(
+                "History id {} is no longer valid, resetting to {}; \
+                 mail in between is not counted",
+                self.starting_from, history_id
+            );
+            self.pipeline.record_history_reset();
+            self.starting_from = history_id;
+
+            if let Some(state_file) = &self.state_file {
+                state_file.save_history_id(&self.starting_from);
+            }
+            debug_status::record_poll(
+                self.pipeline.account.as_deref(),
+                &self.starting_from,
+                PollOutcome::HistoryReset,
+                0,
+            );
+            self.heartbeat().await;
+            return Ok(false);
+        };
+        self.backlog.extend(history);
+
+        let deleted = self.mail.take_messages_deleted();
+        if self.trash {
+            self.pipeline.record_messages_deleted(deleted);
+        }
+
+        for label in self.mail.take_labels_added() {
+            if self.spam && label == "SPAM" {
+                self.pipeline.record_spam_received(None);
+            }
+            if self.trash && label == "TRASH" {
+                self.pipeline.record_trashed(None);
+            }
+            self.pipeline.record_label_added(&label);
+        }
+        Ok(true)
+    }
+
+    async fn heartbeat(&mut self) {
+        self.pipeline.record_heartbeat();
+
+        let Some(url) = &self.heartbeat_url else {
+            return;
+        };
+        if self.pipeline.dry_run {
+            info!("[dry run] would ping {}", url);
+            return;
+        }
+        let ping = http_trace::client()
+            .get(url)
+            .timeout(HEARTBEAT_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = ping {
+            warn!("Heartbeat ping to {} failed: {}", url, err);
+        }
+    }
+
+    /// Fetches and records a batch of the backlog, then moves the history
+    /// position past it. Returns the number of messages recorded.
+    async fn process_batch(&mut self, batch: Vec<MinimalMessage>) -> Result<usize> {
+        let mail_details = self.mail.fetch_details(batch).await?;
+
+        let mut latest_history_id = mail_details
+            .last()
+            .map(|message| message.history_id.clone());
+        // Once everything listed has been processed, move past the changes
+        // that weren't new mail as well, so they aren't listed again
+        if self.backlog.is_empty() {
+            if let Some(cursor) = self.mail.take_latest_cursor() {
+                latest_history_id = Some(cursor);
+            }
+        }
+        if self.delivery == Delivery::AtMostOnce {
+            if let Some(history_id) = &latest_history_id {
+                self.starting_from = history_id.clone();
+                if let Some(state_file) = &self.state_file {
+                    state_file.save_history_id(&self.starting_from);
+                }
+            }
+        }
+        let mail_details = self.skip_already_recorded(mail_details);
+        let found = mail_details.len();
+
+        if !mail_details.is_empty() {
+            info!("Found more mail: {} messages", mail_details.len());
+            debug!("{:#?}", mail_details);
+
+            self.record_streams(&mail_details).await?;
+            if self.dmarc_reports {
+                self.record_dmarc_reports(&mail_details).await?;
+            }
+            for message in &mail_details {
+                if self.spam && message.labels.iter().any(|label| label == "SPAM") {
+                    self.pipeline.record_spam_received(Some(&message.id));
+                }
+                if self.trash && message.labels.iter().any(|label| label == "TRASH") {
+                    self.pipeline.record_trashed(Some(&message.id));
+                }
+                self.pipeline.record_message(message);
+            }
+        }
+
+        if let Some(history_id) = latest_history_id {
+            self.starting_from = history_id;
+        }
+        if let Some(state_file) = &self.state_file {
+            state_file.save_checkpoint(&self.starting_from);
+        }
+        // Only once the counts are saved, or a crash in between would skip
+        // the batch for good when it's listed again
+        if let Some(store) = &self.store {
+            for message in &mail_details {
+                store.record(self.pipeline.account.as_deref(), message);
+            }
+        }
+
+        Ok(found)
+    }
+
+    fn skip_already_recorded(
+        &self,
+        messages: Vec<UsableMessageDetails>,
+    ) -> Vec<UsableMessageDetails> {
+        let Some(store) = &self.store else {
+            return messages;
+        };
+
+        messages
+            .into_iter()
+            .filter(|message| {
+                let recorded = store.contains(self.pipeline.account.as_deref(), &message.id);
+                if recorded {
+                    debug!(message_id = message.id, "Skipping already recorded message");
+                }
+                !recorded
+            })
+            .collect()
+    }
+
+    async fn record_drafts(&mut self) -> Result<()> {
+        if let Some(drafts) = self.mail.drafts_total().await? {
+            self.pipeline.record_drafts(drafts);
+        }
+
+        if let Some(stale_draft_age) = self.stale_draft_age {
+            let before = chrono::Utc::now()
+                - chrono::TimeDelta::from_std(stale_draft_age).unwrap_or(chrono::TimeDelta::MAX);
+            if let Some(stale) = self.mail.drafts_before(before).await? {
+                self.pipeline.record_stale_drafts(stale);
+            }
+        }
+        Ok(())
+    }
+
+    async fn record_inbox_age(&mut self) -> Result<()> {
+        let now = chrono::Utc::now();
+        let cutoffs = INBOX_AGE_BUCKETS.map(|(_, days)| now - chrono::TimeDelta::days(days));
+        if let Some(counts) = self.mail.inbox_older_than(&cutoffs).await? {
+            for ((older_than, _), messages) in INBOX_AGE_BUCKETS.into_iter().zip(counts) {
+                self.pipeline.record_inbox_age(older_than, messages);
+            }
+        }
+        Ok(())
+    }
+
+    /// A report that can't be parsed is skipped rather than failing the poll,
+    /// since it would fail again on every retry
+    async fn record_dmarc_reports(&mut self, messages: &[UsableMessageDetails]) -> Result<()> {
+        for message in messages {
+            for attachment in dmarc::report_attachments(message) {
+                let data = self.mail.fetch_attachment(&message.id, attachment).await?;
+                match dmarc::parse_report(&data) {
+                    Ok(report) => self.pipeline.record_dmarc_report(&report, &message.id),
+                    Err(err) => warn!(
+                        message_id = message.id,
+                        "Skipping DMARC report {}: {}", attachment.filename, err
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stream queries are in the backend's search syntax, which can't be
+    /// evaluated locally, so ask the server which of the new messages each
+    /// stream matches, searching back only as far as the oldest of them.
+    async fn record_streams(&mut self, messages: &[UsableMessageDetails]) -> Result<()> {
+        let streams = self.pipeline.streams();
+        let Some(oldest) = messages.iter().map(|message| message.internal_date).min() else {
+            return Ok(());
+        };
+
+        for stream in streams {
+            let matching = self.mail.search_since(&stream.query, oldest).await?;
+
+            for message in messages
+                .iter()
+                .filter(|message| matching.contains(&message.id))
+            {
+                self.pipeline.record_stream_message(&stream.name, message);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drives a watcher from /metrics scrapes rather than a timer, so polling
+/// follows scrape frequency and stops entirely when nobody is scraping.
+pub struct ScrapeTrigger {
+    watcher: Arc<tokio::sync::Mutex<Watcher>>,
+    min_interval: Duration,
+    last_poll: Mutex<Option<Instant>>,
+    /// Cleared once the serve policy stops polling
+    polling: Arc<AtomicBool>,
+    /// Why polling was given up on, under the exit policy
+    gave_up: tokio::sync::watch::Sender<Option<String>>,
+}
+
+/// How long a scrape waits for the poll it triggered before rendering what
+/// we have; the poll itself keeps going in the background.
+const SCRAPE_POLL_WAIT: Duration = Duration::from_secs(8);
+
+impl ScrapeTrigger {
+    pub fn new(watcher: Watcher, min_interval: Duration) -> Self {
+        Self {
+            watcher: Arc::new(tokio::sync::Mutex::new(watcher)),
+            min_interval,
+            last_poll: Mutex::new(None),
+            polling: Arc::new(AtomicBool::new(true)),
+            gave_up: tokio::sync::watch::channel(None).0,
+        }
+    }
+
+    /// Resolves once polls have failed for longer than the failure budget,
+    /// under the exit policy
+    pub async fn gave_up(&self) -> String {
+        let mut gave_up = self.gave_up.subscribe();
+        let reason = gave_up.wait_for(Option::is_some).await.unwrap();
+        reason.clone().unwrap_or_default()
+    }
+
+    /// Waits for a poll in progress to stop at a batch boundary; later
+    /// scrapes don't poll
+    pub async fn stop(&self) {
+        stop();
+        drop(self.watcher.lock().await);
+    }
+
+    pub async fn on_scrape(&self) {
+        if !self.polling.load(Ordering::Relaxed) {
+            return;
+        }
+        {
+            let mut last_poll = self.last_poll.lock().unwrap();
+            if last_poll.is_some_and(|last_poll| last_poll.elapsed() < self.min_interval) {
+                return;
+            }
+            *last_poll = Some(Instant::now());
+        }
+
+        let watcher = self.watcher.clone();
+        let polling = self.polling.clone();
+        let gave_up = self.gave_up.clone();
+        let poll = tokio::spawn(async move {
+            match watcher.lock().await.poll().await {
+                Ok(Some(_)) => {}
+                Ok(None) => polling.store(false, Ordering::Relaxed),
+                Err(err) => {
+                    polling.store(false, Ordering::Relaxed);
+                    gave_up.send_replace(Some(err));
+                }
+            }
+        });
+
+        if tokio::time::timeout(SCRAPE_POLL_WAIT, poll).await.is_err() {
+            warn!("Poll is taking a while, serving the metrics we already have");
+        }
+    }
+}